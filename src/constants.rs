@@ -1,14 +1,75 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use lazy_static::lazy_static;
+use regex::Regex;
 use serde::Deserialize;
 
-use crate::expr::{evaluate, EvalError};
+use crate::expr::{
+    evaluate, explain, symbols, EvalError, EvalErrorMessage, Expr, ExprValue, Location,
+};
+use crate::format_value::Format;
 use crate::functions::Functions;
 use crate::value::{Context, Primitive};
 
 #[derive(Debug, Deserialize, Default)]
 #[serde(default, deny_unknown_fields)]
 pub struct Constants {
+    /// Constants file whose constants and macros this one can use, relative to this file.
+    /// Its constants are not generated, and constants defined here replace them by name.
+    pub extends: Option<PathBuf>,
+
     #[serde(rename = "constant")]
     pub constants: Vec<Constant>,
+
+    #[serde(rename = "macro")]
+    pub macros: Vec<Macro>,
+}
+impl Constants {
+    /// Append constants and macros from another file
+    pub fn extend(&mut self, other: Self) {
+        self.constants.extend(other.constants);
+        self.macros.extend(other.macros);
+    }
+}
+
+/// User-defined function, evaluated as an expression of its parameters
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Macro {
+    pub name: String,
+
+    /// Parameter names, usable as symbols in `expr`
+    #[serde(default)]
+    pub params: Vec<String>,
+
+    pub expr: String,
+}
+impl Macro {
+    /// The macro can call functions already in `fns`, but not itself
+    /// or macros registered after it, so recursion is impossible.
+    pub fn register(&self, fns: &mut Functions) {
+        let available = fns.clone();
+        let params = self.params.clone();
+        let expr = self.expr.clone();
+        fns.insert_closure(&self.name, move |location, args| {
+            if args.len() != params.len() {
+                return Err(location.error_here(EvalErrorMessage::ArgumentCount));
+            }
+            let mut ctx = Context::new();
+            for (param, arg) in params.iter().zip(args) {
+                if let ExprValue::Primitive(value) = arg.value {
+                    ctx.insert(param.clone(), value);
+                } else {
+                    unreachable!("Calls and symbols should not exist anymore")
+                }
+            }
+            Ok(Expr {
+                value: ExprValue::Primitive(evaluate(&expr, &ctx, &available)?),
+                location,
+            })
+        });
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
@@ -19,8 +80,36 @@ pub struct Constant {
     #[serde(default, rename = "type")]
     pub type_: Option<String>,
 
-    #[serde(rename = "value")]
-    value_string: String,
+    /// Emit as a member of this enum in languages that support enums
+    #[serde(default, rename = "enum")]
+    pub enum_: Option<String>,
+
+    /// Expression for the value
+    #[serde(default, rename = "value")]
+    value_string: Option<String>,
+
+    /// String with `${NAME}` replaced by values of other constants,
+    /// used instead of `value`
+    #[serde(default)]
+    format: Option<String>,
+
+    /// Name of an earlier constant whose value is reused,
+    /// used instead of `value` and `format`
+    #[serde(default)]
+    alias_of: Option<String>,
+
+    /// Literal formatting for this constant in all languages, overriding
+    /// the formats of its type and language for the kinds of values it sets
+    #[serde(default)]
+    pub literal_format: Option<Format>,
+
+    /// Short comment emitted on the same line as the constant
+    #[serde(default)]
+    pub note: Option<String>,
+
+    /// Raw values used verbatim in the output of the named languages
+    #[serde(default)]
+    pub overrides: HashMap<String, String>,
 
     #[serde(skip)]
     resolved_value: Option<Primitive>,
@@ -30,8 +119,416 @@ impl Constant {
         self.resolved_value.clone().expect("Value not resolved")
     }
 
-    pub fn resolve_value(&mut self, ctx: &Context) -> Result<(), EvalError> {
-        self.resolved_value = Some(evaluate(&self.value_string, ctx, &Functions::default())?);
+    /// Expression given as `value`, if any
+    pub fn expression(&self) -> Option<&str> {
+        self.value_string.as_deref()
+    }
+
+    /// Use a value resolved elsewhere instead of the expression
+    pub fn set_value(&mut self, value: Primitive) {
+        self.resolved_value = Some(value);
+    }
+
+    /// Names of other constants this one refers to.
+    /// Unparseable expressions are reported when resolving, not here.
+    pub fn references(&self) -> Vec<String> {
+        match (&self.value_string, &self.format, &self.alias_of) {
+            (Some(value), None, None) => symbols(value).unwrap_or_default(),
+            (None, Some(format), None) => RE_INTERPOLATE
+                .captures_iter(format)
+                .filter_map(|cap| cap.get(2))
+                .map(|name| name.as_str().to_owned())
+                .collect(),
+            (None, None, Some(target)) => vec![target.clone()],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn resolve_value(&mut self, ctx: &Context, fns: &Functions) -> Result<(), EvalError> {
+        self.resolved_value = Some(match (&self.value_string, &self.format, &self.alias_of) {
+            (Some(value), None, None) if value.trim().is_empty() => {
+                let location = Location::new(value, 0, value.len());
+                return Err(location.error_here(EvalErrorMessage::EmptyValue));
+            },
+            (Some(value), None, None) => evaluate(value, ctx, fns)?,
+            (None, Some(format), None) => Primitive::String(interpolate(format, ctx)?),
+            (None, None, Some(target)) => ctx.get(target).cloned().ok_or_else(|| {
+                Location::new(target, 0, target.len())
+                    .error_here(EvalErrorMessage::UnknownSymbol(target.clone()))
+            })?,
+            _ => {
+                let location = Location::new(&self.name, 0, self.name.len());
+                return Err(location.error_here(EvalErrorMessage::ValueOrFormat));
+            },
+        });
         Ok(())
     }
 }
+
+/// Resolves constants in order, each seeing the values of the ones before it.
+/// The function table is built once and shared by all constants.
+pub struct Evaluator {
+    fns: Functions,
+    context: Context,
+}
+impl Evaluator {
+    /// Evaluator starting from the values in `context`
+    pub fn new(fns: Functions, context: Context) -> Self {
+        Self { fns, context }
+    }
+
+    /// Resolve the value of `constant` and make it available to later constants
+    pub fn resolve(&mut self, constant: &mut Constant) -> Result<(), EvalError> {
+        constant.resolve_value(&self.context, &self.fns)?;
+        self.context.insert(constant.name.clone(), constant.value());
+        Ok(())
+    }
+
+    /// Steps of evaluating `text` with the constants resolved so far, see `expr::explain`
+    pub fn explain(&self, text: &str) -> Result<Vec<String>, EvalError> {
+        explain(text, &self.context, &self.fns)
+    }
+
+    /// Values of all resolved constants
+    pub fn into_context(self) -> Context {
+        self.context
+    }
+}
+
+/// CRC-32 of resolved constant names and values, sorted by name, as hex digits.
+/// Depends on nothing else, so identical constants always give the same checksum.
+pub fn checksum(constants: &[Constant]) -> String {
+    let mut lines: Vec<String> = constants
+        .iter()
+        .map(|c| format!("{}={}\n", c.name, c.value()))
+        .collect();
+    lines.sort();
+    format!("{:08x}", crc32(lines.concat().as_bytes()))
+}
+
+/// CRC-32 with the IEEE polynomial, as used by zlib
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+lazy_static! {
+    static ref RE_INTERPOLATE: Regex = Regex::new(r"\$(\$|\{([^}]*)\})").unwrap();
+}
+
+/// Replace `${NAME}` with the value of constant `NAME`, and `$$` with `$`.
+/// Strings are inserted without quotes.
+fn interpolate(text: &str, ctx: &Context) -> Result<String, EvalError> {
+    let mut result = String::with_capacity(text.len());
+    let mut last_end = 0;
+    for cap in RE_INTERPOLATE.captures_iter(text) {
+        let m = cap.get(0).unwrap();
+        result.push_str(&text[last_end..m.start()]);
+        last_end = m.end();
+
+        if let Some(name) = cap.get(2) {
+            match ctx.get(name.as_str()) {
+                Some(Primitive::String(s)) => result.push_str(s),
+                Some(value) => result.push_str(&value.to_string()),
+                None => {
+                    return Err(Location::new(text, m.start(), m.as_str().len()).error_here(
+                        EvalErrorMessage::UnknownSymbol(name.as_str().to_owned()),
+                    ));
+                },
+            }
+        } else {
+            result.push('$');
+        }
+    }
+    result.push_str(&text[last_end..]);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test_constants {
+    use super::{crc32, Constants, Evaluator};
+    use crate::expr::{EvalErrorMessage, Location};
+    use crate::functions::Functions;
+    use crate::value::{Context, Primitive};
+
+    fn resolve(text: &str) -> Result<Context, (String, EvalErrorMessage, Location)> {
+        let constants = toml::from_str::<Constants>(text).unwrap();
+        let mut fns = Functions::default();
+        for macro_ in &constants.macros {
+            macro_.register(&mut fns);
+        }
+        let mut evaluator = Evaluator::new(fns, Context::new());
+        for mut constant in constants.constants {
+            evaluator
+                .resolve(&mut constant)
+                .map_err(|e| (constant.name.clone(), e.message, e.location))?;
+        }
+        Ok(evaluator.into_context())
+    }
+
+    #[test]
+    fn test_format_interpolation() {
+        let context = resolve(
+            r#"
+            [[constant]]
+            name = "major"
+            value = "1"
+
+            [[constant]]
+            name = "minor"
+            value = "(add major 1)"
+
+            [[constant]]
+            name = "channel"
+            value = '"beta"'
+
+            [[constant]]
+            name = "VERSION"
+            format = "build-${major}.${minor}-${channel} ($$5)"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            context["VERSION"],
+            Primitive::String("build-1.2-beta ($5)".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_format_errors() {
+        let text = r#"
+            [[constant]]
+            name = "VERSION"
+            format = "v${major}"
+        "#;
+        let (name, message, location) = resolve(text).unwrap_err();
+        assert_eq!(name, "VERSION");
+        assert_eq!(message, EvalErrorMessage::UnknownSymbol("major".to_owned()));
+        assert_eq!(location, Location::new("v${major}", 1, 8));
+
+        for text in &[
+            "[[constant]]\nname = \"A\"",
+            "[[constant]]\nname = \"A\"\nvalue = \"1\"\nformat = \"1\"",
+        ] {
+            let (_, message, location) = resolve(text).unwrap_err();
+            assert_eq!(message, EvalErrorMessage::ValueOrFormat);
+            assert_eq!(location, Location::new("A", 0, 1));
+        }
+
+        for value in &["", "   "] {
+            let text = format!("[[constant]]\nname = \"A\"\nvalue = \"{}\"", value);
+            let (name, message, location) = resolve(&text).unwrap_err();
+            assert_eq!(name, "A");
+            assert_eq!(message, EvalErrorMessage::EmptyValue);
+            assert_eq!(location, Location::new(value, 0, value.len()));
+        }
+    }
+
+    #[test]
+    fn test_unicode_names() {
+        let context = resolve(
+            r#"
+            [[constant]]
+            name = "größe"
+            value = "4"
+
+            [[constant]]
+            name = "Δ"
+            value = "(mul größe 2)"
+
+            [[constant]]
+            name = "true_größe"
+            value = "(add Δ größe)"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(context["Δ"], Primitive::Integer(8));
+        assert_eq!(context["true_größe"], Primitive::Integer(12));
+    }
+
+    #[test]
+    fn test_alias() {
+        let context = resolve(
+            r#"
+            [[constant]]
+            name = "NEW_NAME"
+            value = "(add 1 2)"
+
+            [[constant]]
+            name = "OLD_NAME"
+            alias_of = "NEW_NAME"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(context["OLD_NAME"], Primitive::Integer(3));
+        assert_eq!(context["NEW_NAME"], context["OLD_NAME"]);
+
+        let (name, message, _) =
+            resolve("[[constant]]\nname = \"OLD\"\nalias_of = \"MISSING\"").unwrap_err();
+        assert_eq!(name, "OLD");
+        assert_eq!(message, EvalErrorMessage::UnknownSymbol("MISSING".to_owned()));
+
+        let text = "[[constant]]\nname = \"A\"\nvalue = \"1\"\nalias_of = \"A\"";
+        let (_, message, _) = resolve(text).unwrap_err();
+        assert_eq!(message, EvalErrorMessage::ValueOrFormat);
+    }
+
+    #[test]
+    fn test_bits() {
+        let context = resolve(
+            r#"
+            [[constant]]
+            name = "FLAG_A"
+            value = "0b001"
+
+            [[constant]]
+            name = "FLAG_B"
+            value = "0b100"
+
+            [[constant]]
+            name = "MASK"
+            value = "(bits FLAG_A FLAG_B)"
+
+            [[constant]]
+            name = "OVERLAPPING"
+            value = "(bits FLAG_A MASK 0b10)"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(context["MASK"], Primitive::Integer(0b101));
+        assert_eq!(context["OVERLAPPING"], Primitive::Integer(0b111));
+
+        for invalid in &["(bits)", "(bits 1 true)", "(bits 1.0)"] {
+            let text = format!("[[constant]]\nname = \"A\"\nvalue = \"{}\"", invalid);
+            assert!(resolve(&text).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_list_aggregates() {
+        let context = resolve(
+            r#"
+            [[constant]]
+            name = "SIZES"
+            value = "(list 4 16 8)"
+
+            [[constant]]
+            name = "TOTAL"
+            value = "(sum SIZES)"
+
+            [[constant]]
+            name = "SMALLEST"
+            value = "(min SIZES)"
+
+            [[constant]]
+            name = "LARGEST"
+            value = "(max SIZES)"
+
+            [[constant]]
+            name = "MIXED"
+            value = "(max (list 1 2.5 2))"
+
+            [[constant]]
+            name = "EMPTY_SUM"
+            value = "(sum (list))"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(context["TOTAL"], Primitive::Integer(28));
+        assert_eq!(context["SMALLEST"], Primitive::Integer(4));
+        assert_eq!(context["LARGEST"], Primitive::Integer(16));
+        assert_eq!(context["MIXED"], Primitive::Float(2.5));
+        assert_eq!(context["EMPTY_SUM"], Primitive::Integer(0));
+
+        for invalid in &["(min (list))", "(max (list 1 true))", "(sum 5)"] {
+            let text = format!("[[constant]]\nname = \"A\"\nvalue = \"{}\"", invalid);
+            let (_, message, _) = resolve(&text).unwrap_err();
+            assert!(matches!(message, EvalErrorMessage::InvalidArgument(_)), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_macros() {
+        let context = resolve(
+            r#"
+            [[macro]]
+            name = "kib"
+            params = ["x"]
+            expr = "(mul x 1024)"
+
+            [[macro]]
+            name = "mib"
+            params = ["x"]
+            expr = "(kib (kib x))"
+
+            [[macro]]
+            name = "three"
+            expr = "3"
+
+            [[constant]]
+            name = "A"
+            value = "(add (mib 2) (kib (three)))"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(context["A"], Primitive::Integer(2 * 1024 * 1024 + 3 * 1024));
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let macros = r#"
+            [[macro]]
+            name = "recursive"
+            params = ["x"]
+            expr = "(recursive x)"
+
+            [[macro]]
+            name = "double"
+            params = ["x"]
+            expr = "(add x x)"
+        "#;
+
+        let (_, message, location) =
+            resolve(&format!("{}[[constant]]\nname = \"A\"\nvalue = \"(double 1 2)\"", macros))
+                .unwrap_err();
+        assert_eq!(message, EvalErrorMessage::ArgumentCount);
+        assert_eq!(location, Location::new("(double 1 2)", 1, 6));
+
+        let (_, message, _) =
+            resolve(&format!("{}[[constant]]\nname = \"A\"\nvalue = \"(recursive 1)\"", macros))
+                .unwrap_err();
+        assert_eq!(message, EvalErrorMessage::UnknownFunction("recursive".to_owned()));
+    }
+
+    #[test]
+    fn test_evaluator() {
+        let mut constants = toml::from_str::<Constants>(
+            "[[constant]]\nname = \"B\"\nvalue = \"(add A 1)\"\n\
+             [[constant]]\nname = \"C\"\nvalue = \"(mul B 2)\"",
+        )
+        .unwrap()
+        .constants;
+        let seeds: Context = vec![("A".to_owned(), Primitive::Integer(1))].into_iter().collect();
+        let mut evaluator = Evaluator::new(Functions::default(), seeds);
+        for constant in &mut constants {
+            evaluator.resolve(constant).unwrap();
+        }
+        assert_eq!(evaluator.explain("C").unwrap().last().unwrap(), "Result: 4");
+        let context = evaluator.into_context();
+        assert_eq!(context.len(), 3);
+        assert_eq!(context["C"], Primitive::Integer(4));
+        assert_eq!(constants[1].value(), Primitive::Integer(4));
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
@@ -26,15 +26,52 @@ impl Location {
             message,
         }
     }
+
+    /// One-based line and column of the start, with columns counted in characters
+    pub fn line_column(&self) -> (usize, usize) {
+        let before = &self.string[..self.start];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        (line, column)
+    }
 }
+
+/// Lines longer than this many characters are cut to a window around the underline
+const DISPLAY_WIDTH: usize = 100;
+
+/// Characters shown on each side of the underline, which is also at most this long
+const DISPLAY_CONTEXT: usize = 40;
+
 impl fmt::Display for Location {
+    /// The line containing the start, underlined up to the end of the line.
+    /// Long lines are cut to a window around the underline, marked with `...`.
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let (line, column) = self.line_column();
+        let text: Vec<char> = self.string.lines().nth(line - 1).unwrap_or("").chars().collect();
+        let end = (self.start + self.len).min(self.string.len());
+        let mut underline = self.string[self.start..end]
+            .chars()
+            .take_while(|c| *c != '\n')
+            .count()
+            .max(1);
+
+        let start = column - 1;
+        let (mut from, mut to) = (0, text.len());
+        if text.len() > DISPLAY_WIDTH {
+            underline = underline.min(DISPLAY_CONTEXT);
+            from = start.saturating_sub(DISPLAY_CONTEXT);
+            to = (start + underline + DISPLAY_CONTEXT).min(text.len());
+        }
+        let prefix = if from > 0 { "..." } else { "" };
+        let suffix = if to < text.len() { "..." } else { "" };
         write!(
             f,
-            "  {}\n  {}{}",
-            self.string,
-            " ".repeat(self.start),
-            "^".repeat(self.len)
+            "  {}{}{}\n  {}{}",
+            prefix,
+            text[from..to].iter().collect::<String>(),
+            suffix,
+            " ".repeat(prefix.len() + start - from),
+            "^".repeat(underline)
         )
     }
 }
@@ -50,9 +87,22 @@ impl fmt::Display for EvalError {
         use EvalErrorMessage::*;
         write!(
             f,
-            "{}\n{}",
+            "{}",
             match &self.message {
                 InvalidChar(c) => format!("Invalid character {:?} for this position", c),
+                InvalidEscape(c) => format!("Invalid escape sequence \"\\{}\"", c),
+                InvalidSeparator => {
+                    "Digit separator '_' is only allowed between two digits".to_owned()
+                },
+                InvalidDigitForRadix(radix, digit) => format!(
+                    "Invalid digit {:?} for {} literal",
+                    digit,
+                    match radix {
+                        2 => "binary",
+                        8 => "octal",
+                        _ => "hexadecimal",
+                    }
+                ),
                 EmptyExpression => "Empty expressions are not allowed".to_owned(),
                 UnmatchedOpen => "Unmatched opening '('".to_owned(),
                 UnmatchedClose => "Unmatched closing ')'".to_owned(),
@@ -63,8 +113,21 @@ impl fmt::Display for EvalError {
                 ArgumentCount => "Function argument count incorrect".to_owned(),
                 InvalidArgument(msg) => format!("Argument invalid: {}", msg),
                 Overflow => "Overflow or underflow occurred".to_owned(),
-            },
-            self.location
+                DivisionByZero => "Division by zero".to_owned(),
+                ValueOrFormat => {
+                    "Exactly one of `value`, `format` and `alias_of` must be given".to_owned()
+                },
+                EmptyValue => "Value is empty; use '\"\"' for an empty string".to_owned(),
+            }
+        )?;
+        if self.location.string.is_empty() {
+            return Ok(());
+        }
+        let (line, column) = self.location.line_column();
+        write!(
+            f,
+            " (line {}, column {})\n\n{}",
+            line, column, self.location
         )
     }
 }
@@ -72,6 +135,9 @@ impl fmt::Display for EvalError {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum EvalErrorMessage {
     InvalidChar(char),
+    InvalidEscape(char),
+    InvalidSeparator,
+    InvalidDigitForRadix(u32, char),
     EmptyExpression,
     UnmatchedOpen,
     UnmatchedClose,
@@ -82,6 +148,9 @@ pub enum EvalErrorMessage {
     ArgumentCount,
     InvalidArgument(String),
     Overflow,
+    DivisionByZero,
+    ValueOrFormat,
+    EmptyValue,
 }
 
 #[derive(Debug, Clone)]
@@ -141,15 +210,30 @@ impl Expr {
         }
     }
 
-    fn call_functions(self, fns: &Functions) -> Result<Self, EvalError> {
+    /// Evaluate calls innermost first, appending `(call args) = result` to `trace` if given
+    fn call_functions(
+        self, fns: &Functions, mut trace: Option<&mut Vec<String>>,
+    ) -> Result<Self, EvalError> {
         if let ExprValue::Call(sym, args) = self.value {
             let args = args
                 .into_iter()
-                .map(|a| a.call_functions(fns))
+                .map(|a| a.call_functions(fns, trace.as_deref_mut()))
                 .collect::<Result<Vec<Self>, EvalError>>()?;
 
             if let Some(fn_) = fns.get(&sym) {
-                fn_(self.location, args)
+                let location = self.location;
+                let call = trace.as_ref().map(|_| {
+                    Self {
+                        location: location.clone(),
+                        value: ExprValue::Call(sym.clone(), args.clone()),
+                    }
+                    .to_string()
+                });
+                let result = fn_(location, args)?;
+                if let (Some(trace), Some(call)) = (trace, call) {
+                    trace.push(format!("{} = {}", call, result));
+                }
+                Ok(result)
             } else {
                 Err(EvalError {
                     location: self.location,
@@ -162,6 +246,22 @@ impl Expr {
     }
 }
 
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match &self.value {
+            ExprValue::Primitive(p) => write!(f, "{}", p),
+            ExprValue::Symbol(sym) => write!(f, "{}", sym),
+            ExprValue::Call(sym, args) => {
+                write!(f, "({}", sym)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                write!(f, ")")
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ExprValue {
     Primitive(Primitive),
@@ -171,53 +271,70 @@ pub enum ExprValue {
 
 fn scan(text: &str) -> Result<Vec<Token>, EvalError> {
     lazy_static! {
-        static ref RE_FLT: Regex = Regex::new(r"^[-+]?[0-9]+\.[0-9]+([eE][-+]?[0-9]+)?").unwrap();
-        static ref RE_INT: Regex = Regex::new(r"^[-+]?[0-9_]*[0-9]").unwrap();
-        static ref RE_RDX: Regex = Regex::new(r"^0(b|o|x)([0-9a-f_]*[0-9a-f])").unwrap();
-        static ref RE_BLN: Regex = Regex::new(r"^(true|false)").unwrap();
-        static ref RE_SYM: Regex = Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*").unwrap();
+        static ref RE_STR: Regex = Regex::new(r#"^"([^"\\]|\\.)*""#).unwrap();
+        static ref RE_FLT: Regex =
+            Regex::new(r"^[-+]?[0-9]+(\.[0-9]+([eE][-+]?[0-9]+)?|[eE][-+]?[0-9]+)").unwrap();
+        static ref RE_INT: Regex = Regex::new(r"^[-+]?[0-9][0-9_]*").unwrap();
+        static ref RE_RDX: Regex = Regex::new(r"^0(b|o|x)([0-9a-zA-Z_]+)").unwrap();
+        static ref RE_BLN: Regex = Regex::new(r"^(true|false)\b").unwrap();
+        static ref RE_SYM: Regex = Regex::new(r"^[\p{XID_Start}_]\p{XID_Continue}*").unwrap();
     }
 
     let mut result = Vec::new();
     let mut offset: usize = 0;
     while offset < text.len() {
-        if let Some(m) = RE_FLT.find(&text[offset..]) {
+        if let Some(m) = RE_STR.find(&text[offset..]) {
+            let location = Location::new(text, offset, m.as_str().len());
+            let value = unescape(&m.as_str()[1..m.as_str().len() - 1])
+                .map_err(|c| location.error_here(EvalErrorMessage::InvalidEscape(c)))?;
+            result.push(Token {
+                location,
+                type_: TokenValue::Literal(Primitive::String(value)),
+            });
+            offset += m.as_str().len();
+        } else if let Some(m) = RE_FLT.find(&text[offset..]) {
             result.push(Token {
                 location: Location::new(text, offset, m.as_str().len()),
                 type_: TokenValue::Literal(Primitive::Float(m.as_str().parse().unwrap())),
             });
             offset += m.as_str().len();
         } else if let Some(cap) = RE_RDX.captures(&text[offset..]) {
-            let radix = cap.get(1);
-            let number = cap.get(2).unwrap().as_str().replace("_", "");
-            println!("{:?} => {:?}", cap.get(0), (&number, &radix));
+            let location = Location::new(text, offset, cap.get(0).unwrap().as_str().len());
+            let radix = match cap.get(1).unwrap().as_str() {
+                "b" => 2,
+                "o" => 8,
+                "x" => 16,
+                _ => unreachable!("Invalid radix"),
+            };
+            let digits = cap.get(2).unwrap();
+            check_separators(text, offset + digits.start(), digits.as_str())?;
+            let number = digits.as_str().replace("_", "");
+            if let Some(digit) = number.chars().find(|c| !c.is_digit(radix)) {
+                let message = EvalErrorMessage::InvalidDigitForRadix(radix, digit);
+                return Err(location.error_here(message));
+            }
             result.push(Token {
-                location: Location::new(text, offset, cap.get(0).unwrap().as_str().len()),
                 type_: TokenValue::Literal(Primitive::Integer(
-                    i128::from_str_radix(
-                        &number,
-                        match radix.map(|m| m.as_str()) {
-                            Some("b") => 2,
-                            Some("o") => 8,
-                            Some("x") => 16,
-                            _ => panic!("Invalid radix"), // TODO: better error message
-                        },
-                    )
-                    .expect("Integer parsing failed"), // TODO: better error message
+                    i128::from_str_radix(&number, radix)
+                        .map_err(|_| location.error_here(EvalErrorMessage::Overflow))?,
                 )),
+                location,
             });
             offset += cap.get(0).unwrap().as_str().len();
         } else if let Some(cap) = RE_INT.captures(&text[offset..]) {
+            let literal = cap.get(0).unwrap().as_str();
+            check_separators(text, offset, literal)?;
+            let location = Location::new(text, offset, literal.len());
             result.push(Token {
-                location: Location::new(text, offset, cap.get(0).unwrap().as_str().len()),
                 type_: TokenValue::Literal(Primitive::Integer(
-                    i128::from_str_radix(
-                        cap.get(0).unwrap().as_str(), 10
-                    )
-                    .expect("Integer parsing failed"), // TODO: better error message
+                    literal
+                        .replace("_", "")
+                        .parse::<i128>()
+                        .map_err(|_| location.error_here(EvalErrorMessage::Overflow))?,
                 )),
+                location,
             });
-            offset += cap.get(0).unwrap().as_str().len();
+            offset += literal.len();
         } else if let Some(cap) = RE_BLN.captures(&text[offset..]) {
             let value_str = cap.get(0).unwrap().as_str();
             result.push(Token {
@@ -232,9 +349,9 @@ fn scan(text: &str) -> Result<Vec<Token>, EvalError> {
             });
             offset += m.as_str().len();
         } else {
-            match text[offset..].chars().nth(0).unwrap() {
+            match text[offset..].chars().next().unwrap() {
                 c if c.is_whitespace() => {
-                    offset += 1;
+                    offset += c.len_utf8();
                 },
                 '(' => {
                     result.push(Token {
@@ -252,7 +369,7 @@ fn scan(text: &str) -> Result<Vec<Token>, EvalError> {
                 },
                 other => {
                     return Err(EvalError {
-                        location: Location::new(text, offset, 1),
+                        location: Location::new(text, offset, other.len_utf8()),
                         message: EvalErrorMessage::InvalidChar(other),
                     });
                 },
@@ -263,11 +380,52 @@ fn scan(text: &str) -> Result<Vec<Token>, EvalError> {
     Ok(result)
 }
 
-/// Parse S-expression
-fn parse_expr(tokens: Vec<Token>) -> Result<Expr, EvalError> {
+/// Digit separators are only allowed between two digits, like in Rust.
+/// `start` is the offset of `literal` in `text`.
+fn check_separators(text: &str, start: usize, literal: &str) -> Result<(), EvalError> {
+    let bytes = literal.as_bytes();
+    for (i, b) in bytes.iter().enumerate() {
+        if *b == b'_' {
+            let after_digit = i > 0 && bytes[i - 1].is_ascii_alphanumeric();
+            let before_digit = i + 1 < bytes.len() && bytes[i + 1].is_ascii_alphanumeric();
+            if !(after_digit && before_digit) {
+                return Err(Location::new(text, start + i, 1)
+                    .error_here(EvalErrorMessage::InvalidSeparator));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolve backslash escapes in a string literal, returning the invalid escape on error
+fn unescape(text: &str) -> Result<String, char> {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            result.push(match chars.next().expect("Regex guarantees escape target") {
+                '\\' => '\\',
+                '"' => '"',
+                'n' => '\n',
+                't' => '\t',
+                other => return Err(other),
+            });
+        } else {
+            result.push(c);
+        }
+    }
+    Ok(result)
+}
+
+/// Parse S-expression, starting with an opening token, from the tokens of `text`
+fn parse_expr(text: &str, tokens: Vec<Token>) -> Result<Expr, EvalError> {
     type Level = u32;
 
-    assert!(tokens.len() > 1 && tokens[0].type_ == TokenValue::ExprOpen);
+    match tokens.first() {
+        Some(token) if token.type_ == TokenValue::ExprOpen => {},
+        Some(token) => return Err(token.error_here(EvalErrorMessage::UnexpectedToken)),
+        None => return Err(whole(text).error_here(EvalErrorMessage::EmptyExpression)),
+    }
     let mut level: Level = 1;
     let mut index: usize = 1;
     let mut buffer: Vec<(Level, Expr, usize)> = Vec::new();
@@ -307,7 +465,11 @@ fn parse_expr(tokens: Vec<Token>) -> Result<Expr, EvalError> {
                     }
                 }
 
-                let last_tok_index = buffer[buf_index].2;
+                // Nothing at this level, i.e. `()`
+                if buf_index == buffer.len() {
+                    return Err(tokens[index].error_here(EvalErrorMessage::EmptyExpression));
+                }
+
                 let mut expr_iter = buffer.drain(buf_index..).map(|(_, e, i)| (e, i));
                 if let Some((function, fn_tok_index)) = expr_iter.next() {
                     if let ExprValue::Symbol(fn_sym) = function.value {
@@ -325,9 +487,6 @@ fn parse_expr(tokens: Vec<Token>) -> Result<Expr, EvalError> {
                             tokens[fn_tok_index].error_here(EvalErrorMessage::CallNonSymbol)
                         );
                     }
-                } else {
-                    let token = &tokens[last_tok_index];
-                    return Err(token.error_here(EvalErrorMessage::EmptyExpression));
                 }
             },
         }
@@ -335,21 +494,22 @@ fn parse_expr(tokens: Vec<Token>) -> Result<Expr, EvalError> {
         index += 1;
     }
 
-    if level > 0 {
+    if level > 0 || buffer.len() != 1 {
         Err(tokens[0].error_here(EvalErrorMessage::UnexpectedToken))
     } else {
-        assert_eq!(buffer.len(), 1);
         Ok(buffer.remove(0).1)
     }
 }
 
-/// Parse tokens to either S-expression or atom
-fn parse(tokens: Vec<Token>) -> Result<Expr, EvalError> {
+/// Location of all of `text`, for errors about the expression as a whole
+fn whole(text: &str) -> Location {
+    Location::new(text, 0, text.len())
+}
+
+/// Parse the tokens of `text` to either S-expression or atom
+fn parse(text: &str, tokens: Vec<Token>) -> Result<Expr, EvalError> {
     if tokens.is_empty() {
-        Err(EvalError {
-            location: Location::new("", 0, 0),
-            message: EvalErrorMessage::EmptyExpression,
-        })
+        Err(whole(text).error_here(EvalErrorMessage::EmptyExpression))
     } else if tokens.len() == 1 {
         match tokens[0].type_.clone() {
             TokenValue::Literal(val) => Ok(Expr {
@@ -369,13 +529,73 @@ fn parse(tokens: Vec<Token>) -> Result<Expr, EvalError> {
                 Err(tokens[1].error_here(EvalErrorMessage::UnexpectedToken))
             },
             TokenValue::ExprClose => Err(tokens[0].error_here(EvalErrorMessage::UnmatchedClose)),
-            TokenValue::ExprOpen => parse_expr(tokens),
+            TokenValue::ExprOpen => parse_expr(text, tokens),
         }
     }
 }
 
+/// Names of constants referred to by an expression, in order of appearance.
+/// Names of called functions are not included.
+pub fn symbols(text: &str) -> Result<Vec<String>, EvalError> {
+    let tokens = scan(text)?;
+    Ok(tokens
+        .iter()
+        .enumerate()
+        .filter_map(|(i, token)| match &token.type_ {
+            TokenValue::Symbol(sym) if i == 0 || tokens[i - 1].type_ != TokenValue::ExprOpen => {
+                Some(sym.clone())
+            },
+            _ => None,
+        })
+        .collect())
+}
+
+/// Deepest nesting of calls in an expression, zero for a single value.
+/// Only parentheses and strings are recognized, so that this is cheap
+/// even for expressions too large to parse.
+pub fn nesting_depth(text: &str) -> usize {
+    let mut depth: usize = 0;
+    let mut max = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in text.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {},
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '(' => {
+                depth += 1;
+                max = max.max(depth);
+            },
+            ')' => depth = depth.saturating_sub(1),
+            _ => {},
+        }
+    }
+    max
+}
+
+/// Value of a single literal, without evaluating symbols or function calls
+pub fn parse_literal(text: &str) -> Result<Primitive, EvalError> {
+    let tokens = scan(text)?;
+    match tokens.first() {
+        None => Err(whole(text).error_here(EvalErrorMessage::EmptyExpression)),
+        Some(Token {
+            type_: TokenValue::Literal(value),
+            ..
+        }) if tokens.len() == 1 => Ok(value.clone()),
+        Some(token) => Err(token.error_here(EvalErrorMessage::UnexpectedToken)),
+    }
+}
+
 pub fn evaluate(text: &str, ctx: &Context, fns: &Functions) -> Result<Primitive, EvalError> {
-    let expr = parse(scan(text)?)?.resolve_all(ctx)?.call_functions(fns)?;
+    let expr = parse(text, scan(text)?)?.resolve_all(ctx)?.call_functions(fns, None)?;
 
     if let ExprValue::Primitive(p) = expr.value {
         Ok(p)
@@ -384,12 +604,26 @@ pub fn evaluate(text: &str, ctx: &Context, fns: &Functions) -> Result<Primitive,
     }
 }
 
+/// Steps of evaluating an expression, one per line: the parsed expression,
+/// the expression with symbols substituted, each function call, and the result
+pub fn explain(text: &str, ctx: &Context, fns: &Functions) -> Result<Vec<String>, EvalError> {
+    let expr = parse(text, scan(text)?)?;
+    let mut steps = vec![format!("Parsed: {}", expr)];
+    let expr = expr.resolve_all(ctx)?;
+    steps.push(format!("Substituted: {}", expr));
+    let mut calls = Vec::new();
+    let expr = expr.call_functions(fns, Some(&mut calls))?;
+    steps.extend(calls.into_iter().map(|call| format!("Called: {}", call)));
+    steps.push(format!("Result: {}", expr));
+    Ok(steps)
+}
+
 #[cfg(test)]
 mod test_expr {
     use crate::functions::Functions;
     use crate::value::{Context, Primitive};
 
-    use super::evaluate;
+    use super::{evaluate, nesting_depth, parse_literal, symbols, EvalErrorMessage, Location};
 
     macro_rules! approx_eq {
         ($v1:expr, $v2:expr) => {{ $v1.approx_eq(&$v2, 0.01) }};
@@ -451,4 +685,418 @@ mod test_expr {
             Primitive::Float(4.6)
         ));
     }
+
+    #[test]
+    fn test_eval_pow2() {
+        assert_eq!(evaluate!("(next_pow2 17)"), Ok(Primitive::Integer(32)));
+        assert_eq!(evaluate!("(next_pow2 16)"), Ok(Primitive::Integer(16)));
+        assert_eq!(evaluate!("(next_pow2 1)"), Ok(Primitive::Integer(1)));
+        assert_eq!(evaluate!("(is_pow2 16)"), Ok(Primitive::Boolean(true)));
+        assert_eq!(evaluate!("(is_pow2 17)"), Ok(Primitive::Boolean(false)));
+        assert_eq!(evaluate!("(is_pow2 0)"), Ok(Primitive::Boolean(false)));
+
+        assert_eq!(
+            evaluate!("(next_pow2 0x4000_0000_0000_0000_0000_0000_0000_0001)")
+                .unwrap_err()
+                .message,
+            EvalErrorMessage::Overflow
+        );
+        assert!(matches!(
+            evaluate!("(next_pow2 0)").unwrap_err().message,
+            EvalErrorMessage::InvalidArgument(_)
+        ));
+        assert!(matches!(
+            evaluate!("(next_pow2 -4)").unwrap_err().message,
+            EvalErrorMessage::InvalidArgument(_)
+        ));
+    }
+
+    #[test]
+    fn test_eval_zero_bits() {
+        assert_eq!(evaluate!("(trailing_zeros 0b1000)"), Ok(Primitive::Integer(3)));
+        assert_eq!(evaluate!("(trailing_zeros 0x1000)"), Ok(Primitive::Integer(12)));
+        assert_eq!(evaluate!("(trailing_zeros -8)"), Ok(Primitive::Integer(3)));
+        assert_eq!(evaluate!("(trailing_zeros 0)"), Ok(Primitive::Integer(128)));
+        assert_eq!(evaluate!("(leading_zeros 1)"), Ok(Primitive::Integer(127)));
+        assert_eq!(evaluate!("(leading_zeros 0xff)"), Ok(Primitive::Integer(120)));
+        assert_eq!(evaluate!("(leading_zeros -1)"), Ok(Primitive::Integer(0)));
+        assert_eq!(evaluate!("(leading_zeros 0)"), Ok(Primitive::Integer(128)));
+        for invalid in &["(trailing_zeros 1.0)", "(leading_zeros true)", "(trailing_zeros 1 2)"] {
+            assert!(evaluate!(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_eval_align() {
+        assert_eq!(evaluate!("(align_up 13 8)"), Ok(Primitive::Integer(16)));
+        assert_eq!(evaluate!("(align_up 16 8)"), Ok(Primitive::Integer(16)));
+        assert_eq!(evaluate!("(align_up -13 8)"), Ok(Primitive::Integer(-8)));
+        assert_eq!(evaluate!("(align_down 13 8)"), Ok(Primitive::Integer(8)));
+        assert_eq!(evaluate!("(align_down 16 8)"), Ok(Primitive::Integer(16)));
+        assert_eq!(evaluate!("(align_down -13 8)"), Ok(Primitive::Integer(-16)));
+
+        assert_eq!(
+            evaluate!("(align_up 0x7fff_ffff_ffff_ffff_ffff_ffff_ffff_ffff 16)")
+                .unwrap_err()
+                .message,
+            EvalErrorMessage::Overflow
+        );
+        assert!(matches!(
+            evaluate!("(align_up 13 0)").unwrap_err().message,
+            EvalErrorMessage::InvalidArgument(_)
+        ));
+        assert!(matches!(
+            evaluate!("(align_down 13 -8)").unwrap_err().message,
+            EvalErrorMessage::InvalidArgument(_)
+        ));
+    }
+
+    #[test]
+    fn test_eval_string() {
+        assert_eq!(evaluate!(r#""""#), Ok(Primitive::String(String::new())));
+        assert_eq!(
+            evaluate!(r#""a (b) \"c\" \\""#),
+            Ok(Primitive::String(r#"a (b) "c" \"#.to_owned()))
+        );
+        assert_eq!(
+            evaluate!(r#""\q""#).unwrap_err().message,
+            EvalErrorMessage::InvalidEscape('q')
+        );
+    }
+
+    #[test]
+    fn test_eval_typeof() {
+        let string = |s: &str| Ok(Primitive::String(s.to_owned()));
+        assert_eq!(evaluate!("(typeof true)"), string("boolean"));
+        assert_eq!(evaluate!("(typeof 1)"), string("integer"));
+        assert_eq!(evaluate!("(typeof 1.0)"), string("float"));
+        assert_eq!(evaluate!(r#"(typeof "x")"#), string("string"));
+        assert_eq!(evaluate!("(typeof (typeof 1))"), string("string"));
+    }
+
+    #[test]
+    fn test_eval_list() {
+        use Primitive::*;
+        assert_eq!(evaluate!("(list)"), Ok(List(vec![])));
+        assert_eq!(
+            evaluate!("(list 1 (add 1 1) true)"),
+            Ok(List(vec![Integer(1), Integer(2), Boolean(true)]))
+        );
+        assert_eq!(evaluate!("(count (list 1 2 3))"), Ok(Integer(3)));
+        assert_eq!(evaluate!("(count (list))"), Ok(Integer(0)));
+        assert!(evaluate!("(count 1)").is_err());
+    }
+
+    #[test]
+    fn test_eval_separators() {
+        assert_eq!(evaluate!("1_000"), Ok(Primitive::Integer(1000)));
+        assert_eq!(evaluate!("-1_000_000"), Ok(Primitive::Integer(-1_000_000)));
+        assert_eq!(evaluate!("0b1010_0101"), Ok(Primitive::Integer(0b1010_0101)));
+
+        let cases = [("1__2", 1), ("1_", 1), ("0x_1", 2), ("0x1_", 3), ("(add 1 2_)", 8)];
+        for (text, start) in &cases {
+            let error = evaluate!(text).unwrap_err();
+            assert_eq!(error.message, EvalErrorMessage::InvalidSeparator, "{}", text);
+            assert_eq!(error.location, Location::new(text, *start, 1), "{}", text);
+            assert!(error.to_string().contains("only allowed between two digits"));
+        }
+    }
+
+    #[test]
+    fn test_eval_repeat() {
+        assert_eq!(evaluate!("(repeat 0xab 4)"), Ok(Primitive::Integer(0xabab_abab)));
+        assert_eq!(evaluate!("(repeat 0xab 1)"), Ok(Primitive::Integer(0xab)));
+        assert_eq!(evaluate!("(repeat 0xab 0)"), Ok(Primitive::Integer(0)));
+        assert_eq!(evaluate!("(repeat 0 1_000_000_000_000)"), Ok(Primitive::Integer(0)));
+        assert_eq!(
+            evaluate!("(repeat 0x7f 16)"),
+            Ok(Primitive::Integer(0x7f7f_7f7f_7f7f_7f7f_7f7f_7f7f_7f7f_7f7f))
+        );
+
+        assert_eq!(
+            evaluate!("(repeat 0xab 16)").unwrap_err().message,
+            EvalErrorMessage::Overflow
+        );
+        assert!(matches!(
+            evaluate!("(repeat 0x100 2)").unwrap_err().message,
+            EvalErrorMessage::InvalidArgument(_)
+        ));
+        assert!(matches!(
+            evaluate!("(repeat 0xab -1)").unwrap_err().message,
+            EvalErrorMessage::InvalidArgument(_)
+        ));
+    }
+
+    #[test]
+    fn test_eval_hash() {
+        assert_eq!(
+            evaluate!(r#"(hash "")"#),
+            Ok(Primitive::Integer(0xcbf2_9ce4_8422_2325))
+        );
+        assert_eq!(
+            evaluate!(r#"(hash "a")"#),
+            Ok(Primitive::Integer(0xaf63_dc4c_8601_ec8c))
+        );
+        assert_eq!(
+            evaluate!(r#"(hash "foobar")"#),
+            Ok(Primitive::Integer(0x8594_4171_f739_67e8))
+        );
+        assert!(matches!(
+            evaluate!("(hash 1)").unwrap_err().message,
+            EvalErrorMessage::InvalidArgument(_)
+        ));
+    }
+
+    #[test]
+    fn test_eval_size() {
+        assert_eq!(evaluate!(r#"(size "4 KiB")"#), Ok(Primitive::Integer(4096)));
+        assert_eq!(evaluate!(r#"(size "1 MB")"#), Ok(Primitive::Integer(1_000_000)));
+        assert_eq!(evaluate!(r#"(size "2GiB")"#), Ok(Primitive::Integer(2 << 30)));
+        assert_eq!(evaluate!(r#"(size "512")"#), Ok(Primitive::Integer(512)));
+        assert_eq!(evaluate!(r#"(size "8 B")"#), Ok(Primitive::Integer(8)));
+        for invalid in &[
+            r#"(size "4 kib")"#,
+            r#"(size "KiB")"#,
+            r#"(size "1.5 MiB")"#,
+            "(size 4)",
+        ] {
+            assert!(
+                matches!(
+                    evaluate!(invalid).unwrap_err().message,
+                    EvalErrorMessage::InvalidArgument(_)
+                ),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_eval_radix_digits() {
+        assert_eq!(evaluate!("0xAB_cd"), Ok(Primitive::Integer(0xabcd)));
+        assert_eq!(evaluate!("0o17"), Ok(Primitive::Integer(0o17)));
+
+        let error = evaluate!("0b102").unwrap_err();
+        assert_eq!(error.message, EvalErrorMessage::InvalidDigitForRadix(2, '2'));
+        assert_eq!(error.location, Location::new("0b102", 0, 5));
+        assert!(error.to_string().starts_with("Invalid digit '2' for binary literal"));
+
+        let error = evaluate!("(add 1 0o78)").unwrap_err();
+        assert_eq!(error.message, EvalErrorMessage::InvalidDigitForRadix(8, '8'));
+        assert_eq!(error.location, Location::new("(add 1 0o78)", 7, 4));
+        assert!(error.to_string().starts_with("Invalid digit '8' for octal literal"));
+
+        let error = evaluate!("0xfg").unwrap_err();
+        assert_eq!(error.message, EvalErrorMessage::InvalidDigitForRadix(16, 'g'));
+
+        let error = evaluate!("0x1_0000_0000_0000_0000_0000_0000_0000_0000").unwrap_err();
+        assert_eq!(error.message, EvalErrorMessage::Overflow);
+    }
+
+    #[test]
+    fn test_eval_bool() {
+        assert_eq!(evaluate!("(bool 0)"), Ok(Primitive::Boolean(false)));
+        assert_eq!(evaluate!("(bool 5)"), Ok(Primitive::Boolean(true)));
+        assert_eq!(evaluate!("(bool -1)"), Ok(Primitive::Boolean(true)));
+        assert_eq!(evaluate!("(bool 0.0)"), Ok(Primitive::Boolean(false)));
+        assert_eq!(evaluate!("(bool 0.5)"), Ok(Primitive::Boolean(true)));
+        assert_eq!(evaluate!("(bool false)"), Ok(Primitive::Boolean(false)));
+        assert!(matches!(
+            evaluate!(r#"(bool "")"#).unwrap_err().message,
+            EvalErrorMessage::InvalidArgument(_)
+        ));
+    }
+
+    #[test]
+    fn test_eval_parse_number() {
+        assert_eq!(evaluate!(r#"(parse_int "0xff")"#), Ok(Primitive::Integer(255)));
+        assert_eq!(evaluate!(r#"(parse_int " -1_000 ")"#), Ok(Primitive::Integer(-1000)));
+        assert_eq!(evaluate!(r#"(parse_float "2.5e3")"#), Ok(Primitive::Float(2500.0)));
+        assert_eq!(evaluate!(r#"(parse_float "2")"#), Ok(Primitive::Float(2.0)));
+        for invalid in &[
+            r#"(parse_int "0xfg")"#,
+            r#"(parse_int "12 apples")"#,
+            r#"(parse_int "1.5")"#,
+            r#"(parse_int "")"#,
+            r#"(parse_int 5)"#,
+            r#"(parse_float "true")"#,
+        ] {
+            let error = evaluate!(invalid).unwrap_err();
+            assert!(matches!(error.message, EvalErrorMessage::InvalidArgument(_)), "{}", invalid);
+        }
+        assert_eq!(
+            evaluate!(r#"(parse_int "0x1_0000_0000_0000_0000_0000_0000_0000_0000")"#)
+                .unwrap_err()
+                .message,
+            EvalErrorMessage::Overflow
+        );
+    }
+
+    #[test]
+    fn test_eval_wrapping() {
+        assert_eq!(evaluate!("(wadd 0xFFFFFFFF 1 32)"), Ok(Primitive::Integer(0)));
+        assert_eq!(evaluate!("(wadd 0xFFFFFFFF 1)"), Ok(Primitive::Integer(0x1_0000_0000)));
+        assert_eq!(evaluate!("(wsub 0 1 8)"), Ok(Primitive::Integer(0xff)));
+        assert_eq!(evaluate!("(wsub 0 1)"), Ok(Primitive::Integer(0xffff_ffff_ffff_ffff)));
+        assert_eq!(evaluate!("(wmul 0x80 2 8)"), Ok(Primitive::Integer(0)));
+        assert_eq!(evaluate!("(wmul 0x10 0x10 12)"), Ok(Primitive::Integer(0x100)));
+        assert_eq!(evaluate!("(wsub 0 1 128)"), Ok(Primitive::Integer(-1)));
+        assert_eq!(evaluate!("(wadd 5 -1 4)"), Ok(Primitive::Integer(4)));
+        for invalid in &["(wadd 1 1 0)", "(wadd 1 1 129)", "(wadd 1 1.0 8)", "(wadd 1 2 3 4)"] {
+            assert!(evaluate!(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_eval_division() {
+        assert_eq!(evaluate!("(div 7 2)"), Ok(Primitive::Integer(3)));
+        assert_eq!(evaluate!("(div -7 2)"), Ok(Primitive::Integer(-3)));
+        assert_eq!(evaluate!("(floor_div -7 2)"), Ok(Primitive::Integer(-4)));
+        assert_eq!(evaluate!("(floor_div 7 -2)"), Ok(Primitive::Integer(-4)));
+        assert_eq!(evaluate!("(floor_div -7 -2)"), Ok(Primitive::Integer(3)));
+        assert_eq!(evaluate!("(floor_div -8 2)"), Ok(Primitive::Integer(-4)));
+        assert_eq!(evaluate!("(mod -7 2)"), Ok(Primitive::Integer(-1)));
+        assert_eq!(evaluate!("(euclid_mod -7 2)"), Ok(Primitive::Integer(1)));
+        assert_eq!(evaluate!("(euclid_mod -7 -2)"), Ok(Primitive::Integer(1)));
+        assert_eq!(evaluate!("(euclid_mod 7 2)"), Ok(Primitive::Integer(1)));
+
+        for zero in &["(div 1 0)", "(mod 1 0)", "(floor_div 1 0)", "(euclid_mod 1 0)"] {
+            assert_eq!(evaluate!(zero).unwrap_err().message, EvalErrorMessage::DivisionByZero);
+        }
+        assert_eq!(
+            evaluate!("(floor_div (type_min \"i128\") -1)").unwrap_err().message,
+            EvalErrorMessage::Overflow
+        );
+        for invalid in &["(div 1.0 2)", "(mod 1 \"a\")", "(floor_div 1)", "(euclid_mod 1 2 3)"] {
+            assert!(evaluate!(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_error_location() {
+        let error = evaluate!("(add 1 true)").unwrap_err();
+        assert_eq!(error.location.line_column(), (1, 8));
+        assert_eq!(
+            error.to_string(),
+            concat!(
+                "Argument invalid: Cannot (add Integer(1) Boolean(true)) (line 1, column 8)\n",
+                "\n",
+                "  (add 1 true)\n",
+                "         ^^^^",
+            )
+        );
+
+        let error = evaluate!("(add\n  \"ä\" 1\n  unknown)").unwrap_err();
+        assert_eq!(error.location.line_column(), (3, 3));
+        assert!(error.to_string().ends_with("\n\n    unknown)\n    ^^^^^^^"));
+
+        let error = evaluate!("(add \"ä\" true)").unwrap_err();
+        assert_eq!(error.location.line_column(), (1, 10));
+
+        let text = format!("(add {}unknown {})", "1 ".repeat(1000), "2 ".repeat(1000));
+        let error = evaluate!(&text).unwrap_err();
+        assert_eq!(error.location.line_column(), (1, 2006));
+        assert_eq!(
+            error.location.to_string(),
+            format!(
+                "  ...{}unknown{}...\n  {}^^^^^^^",
+                "1 ".repeat(20),
+                " 2".repeat(20),
+                " ".repeat(43)
+            )
+        );
+
+        let text = format!("{} €", "1 ".repeat(100));
+        let error = evaluate!(&text).unwrap_err();
+        assert_eq!(
+            error.location.to_string(),
+            format!("  ...{}  €\n  {}^", " 1".repeat(19), " ".repeat(43))
+        );
+    }
+
+    #[test]
+    fn test_eval_type_limits() {
+        assert_eq!(evaluate!(r#"(type_max "u32")"#), Ok(Primitive::Integer(4_294_967_295)));
+        assert_eq!(evaluate!(r#"(type_min "u32")"#), Ok(Primitive::Integer(0)));
+        assert_eq!(evaluate!(r#"(type_max "u8")"#), Ok(Primitive::Integer(255)));
+        assert_eq!(evaluate!(r#"(type_min "i8")"#), Ok(Primitive::Integer(-128)));
+        assert_eq!(evaluate!(r#"(type_max "i16")"#), Ok(Primitive::Integer(32767)));
+        assert_eq!(evaluate!(r#"(type_max "u64")"#), Ok(Primitive::Integer(u64::MAX as i128)));
+        assert_eq!(evaluate!(r#"(type_min "i128")"#), Ok(Primitive::Integer(i128::MIN)));
+        assert_eq!(evaluate!(r#"(type_max "i128")"#), Ok(Primitive::Integer(i128::MAX)));
+        assert_eq!(evaluate!(r#"(type_min "u128")"#), Ok(Primitive::Integer(0)));
+        for invalid in &[r#"(type_max "u128")"#, r#"(type_max "u7")"#, r#"(type_max "ä8")"#] {
+            assert!(matches!(
+                evaluate!(invalid).unwrap_err().message,
+                EvalErrorMessage::InvalidArgument(_)
+            ));
+        }
+        assert!(evaluate!(r#"(type_max "")"#).is_err());
+        assert!(evaluate!("(type_max 32)").is_err());
+    }
+
+    #[test]
+    fn test_symbols() {
+        assert_eq!(symbols("A").unwrap(), vec!["A"]);
+        assert_eq!(symbols("(add größe _Ω1 true_x)").unwrap(), vec!["größe", "_Ω1", "true_x"]);
+        assert!(symbols("(add\u{3000}1)").unwrap().is_empty());
+        let error = symbols("(add 1 €)").unwrap_err();
+        assert_eq!(error.message, EvalErrorMessage::InvalidChar('€'));
+        assert!(error.to_string().ends_with("(add 1 €)\n         ^"), "{}", error);
+        assert_eq!(symbols("(add A (mul B 2) A)").unwrap(), vec!["A", "B", "A"]);
+        assert!(symbols("(list 1 \"A\")").unwrap().is_empty());
+        assert!(symbols("\"unterminated").is_err());
+    }
+
+    #[test]
+    fn test_nesting_depth() {
+        assert_eq!(nesting_depth("1"), 0);
+        assert_eq!(nesting_depth("(add 1 2)"), 1);
+        assert_eq!(nesting_depth("(add (mul 1 2) (add (mul 3 4) 5))"), 3);
+        assert_eq!(nesting_depth(r#"(list "((\"((" 1)"#), 1);
+        assert_eq!(nesting_depth(&"(list ".repeat(1000)), 1000);
+    }
+
+    #[test]
+    fn test_parse_literal() {
+        assert_eq!(parse_literal("0x10"), Ok(Primitive::Integer(16)));
+        assert_eq!(parse_literal(" -1.5 "), Ok(Primitive::Float(-1.5)));
+        assert_eq!(parse_literal("\"a b\""), Ok(Primitive::String("a b".to_owned())));
+        for text in &["A", "(add 1 2)", "1 2"] {
+            let error = parse_literal(text).unwrap_err();
+            assert_eq!(error.message, EvalErrorMessage::UnexpectedToken, "{}", text);
+        }
+        assert_eq!(parse_literal("").unwrap_err().message, EvalErrorMessage::EmptyExpression);
+        assert_eq!(parse_literal("  ").unwrap_err().location, Location::new("  ", 0, 2));
+    }
+
+    #[test]
+    fn test_eval_empty_calls() {
+        for text in &["()", "(add ())", "(add 1 (()))"] {
+            let error = evaluate!(text).unwrap_err();
+            assert_eq!(error.message, EvalErrorMessage::EmptyExpression, "{}", text);
+        }
+        let error = evaluate!(" ").unwrap_err();
+        assert_eq!(error.message, EvalErrorMessage::EmptyExpression);
+        assert_eq!(error.location, Location::new(" ", 0, 1));
+    }
+
+    #[test]
+    fn test_eval_random_tokens_never_panic() {
+        const TOKENS: &[&str] = &["(", ")", "1", "2.5", "true", "\"s\"", "add", "list", "x", " "];
+
+        // Deterministic linear congruential generator
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..5000 {
+            let mut text = String::new();
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            for _ in 0..(state >> 60) + 1 {
+                state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+                text.push_str(TOKENS[(state >> 33) as usize % TOKENS.len()]);
+                text.push(' ');
+            }
+            let result = std::panic::catch_unwind(|| evaluate!(&text));
+            assert!(result.is_ok(), "Panicked on {:?}", text);
+        }
+    }
 }
@@ -1,31 +1,202 @@
-use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
 
 use crate::value::Primitive;
 
-#[derive(Debug, Clone, Deserialize, Default)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatError {
+    /// Scaled fixed-point value does not fit in an integer
+    FixedPointOverflow { value: f64, scale_bits: u8 },
+    /// Scaled value does not fit in an integer
+    ScaleOverflow { value: f64, scale: f64 },
+    /// Integer does not fit in the range of its type
+    OutOfRange { value: i128, bits: u8, signed: bool },
+    /// NaN or infinity without a token configured for it
+    NonFinite(f64),
+}
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::FixedPointOverflow { value, scale_bits } => write!(
+                f,
+                "Value {} scaled by 2^{} does not fit in a fixed-point integer",
+                value, scale_bits
+            ),
+            Self::ScaleOverflow { value, scale } => write!(
+                f,
+                "Value {} scaled by {} does not fit in an integer",
+                value, scale
+            ),
+            Self::OutOfRange {
+                value,
+                bits,
+                signed,
+            } => write!(
+                f,
+                "Value {} does not fit in a {}-bit {} integer",
+                value,
+                bits,
+                if *signed { "signed" } else { "unsigned" }
+            ),
+            Self::NonFinite(value) => write!(
+                f,
+                "Value {} has no literal; set `nan`, `infinity` and `neg_infinity` of float format",
+                value
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(default, deny_unknown_fields)]
 pub struct Format {
     pub boolean: Option<BooleanFormat>,
     pub integer: Option<IntegerFormat>,
+    pub float: Option<FloatFormat>,
+    pub list: Option<ListFormat>,
+    /// Multiply numbers by this and round half away from zero,
+    /// formatting the result as an integer. Overrides `float.fixed_point`.
+    pub scale: Option<f64>,
 }
 impl Format {
-    pub fn format(&self, value: &Primitive) -> String {
-        (match value {
+    /// This format with the options set in `overrides` replacing its own
+    pub fn merged(&self, overrides: &Format) -> Format {
+        Format {
+            boolean: overrides.boolean.clone().or_else(|| self.boolean.clone()),
+            integer: overrides.integer.clone().or_else(|| self.integer.clone()),
+            float: overrides.float.clone().or_else(|| self.float.clone()),
+            list: overrides.list.clone().or_else(|| self.list.clone()),
+            scale: overrides.scale.or(self.scale),
+        }
+    }
+
+    /// This format without the two's complement width of integers,
+    /// for values whose type gives the width instead
+    pub fn without_bits(&self) -> Format {
+        Format {
+            integer: self.integer.as_ref().map(IntegerFormat::without_bits),
+            ..self.clone()
+        }
+    }
+
+    /// This format with negative numbers never in parentheses of their own,
+    /// for values already in parentheses
+    pub fn without_parentheses(&self) -> Format {
+        Format {
+            integer: self.integer.as_ref().map(|integer| IntegerFormat {
+                negative_style: integer.negative_style.without_parentheses(),
+                ..integer.clone()
+            }),
+            float: self.float.as_ref().map(|float| FloatFormat {
+                negative_style: float.negative_style.without_parentheses(),
+                ..float.clone()
+            }),
+            ..self.clone()
+        }
+    }
+
+    /// Whether negative integers are rendered as two's complement
+    pub fn is_twos_complement(&self) -> bool {
+        self.integer
+            .as_ref()
+            .is_some_and(|integer| integer.negative_style == NegativeStyle::TwosComplement)
+    }
+
+    pub fn format(&self, value: &Primitive) -> Result<String, FormatError> {
+        if let Some(scale) = self.scale {
+            let number = match value {
+                Primitive::Integer(v) => Some(*v as f64),
+                Primitive::Float(v) => Some(*v),
+                _ => None,
+            };
+            if let Some(number) = number {
+                let scaled = round_to_integer(number * scale)
+                    .ok_or(FormatError::ScaleOverflow { value: number, scale })?;
+                return self.integer.clone().unwrap_or_default().format_fitted(scaled);
+            }
+        }
+
+        // Fixed-point floats are formatted as integers
+        if let Primitive::Float(v) = value {
+            if let Some(fixed) = self.float.as_ref().and_then(|f| f.fixed_point) {
+                return self.format(&Primitive::Integer(fixed.scale(*v)?));
+            }
+        }
+
+        Ok((match value {
             Primitive::Boolean(v) => self.boolean.clone().map(|b| b.format(*v)),
-            Primitive::Integer(v) => self.integer.clone().map(|b| b.format(*v)),
+            Primitive::Integer(v) => match &self.integer {
+                Some(integer) => Some(integer.format_fitted(*v)?),
+                None => None,
+            },
+            Primitive::Float(v) if !v.is_finite() => {
+                Some(self.float.clone().unwrap_or_default().non_finite(*v)?)
+            },
+            Primitive::Float(v) => self.float.clone().map(|b| b.format(*v)),
+            Primitive::List(v) => Some(self.list.clone().unwrap_or_default().format(self, v)?),
             _ => None,
         })
-        .unwrap_or_else(|| value.to_string())
+        .unwrap_or_else(|| value.to_string()))
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-#[serde(deny_unknown_fields)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListFormat {
+    open: String,
+    separator: String,
+    close: String,
+}
+impl ListFormat {
+    /// Elements are formatted using the enclosing format
+    pub fn format(&self, format: &Format, items: &[Primitive]) -> Result<String, FormatError> {
+        let items: Vec<String> =
+            items.iter().map(|item| format.format(item)).collect::<Result<_, _>>()?;
+        Ok(format!("{}{}{}", self.open, items.join(&self.separator), self.close))
+    }
+}
+impl Default for ListFormat {
+    fn default() -> Self {
+        Self {
+            open: "[".to_owned(),
+            separator: ", ".to_owned(),
+            close: "]".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawBooleanFormat")]
 pub struct BooleanFormat {
     #[serde(rename = "true")]
     true_: String,
     #[serde(rename = "false")]
     false_: String,
+    /// Imports required by the true and false tokens, e.g. `<stdbool.h>` in C
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub import: Vec<String>,
+}
+impl TryFrom<RawBooleanFormat> for BooleanFormat {
+    type Error = String;
+
+    fn try_from(raw: RawBooleanFormat) -> Result<Self, Self::Error> {
+        let (true_, false_) = match (raw.true_, raw.false_, raw.as_int) {
+            (None, None, true) => ("1".to_owned(), "0".to_owned()),
+            (Some(_), _, true) | (_, Some(_), true) => {
+                return Err("`as_int` cannot be combined with `true` and `false`".to_owned())
+            },
+            (Some(true_), Some(false_), false) => (true_, false_),
+            (None, _, false) => return Err("missing field `true`".to_owned()),
+            (_, None, false) => return Err("missing field `false`".to_owned()),
+        };
+        Ok(Self {
+            true_,
+            false_,
+            import: raw.import,
+        })
+    }
 }
 impl BooleanFormat {
     pub fn format(&self, boolean: bool) -> String {
@@ -37,28 +208,88 @@ impl BooleanFormat {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
+/// Fields of `BooleanFormat`, where `as_int = true` is shorthand for `1` and `0`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBooleanFormat {
+    #[serde(default, rename = "true")]
+    true_: Option<String>,
+    #[serde(default, rename = "false")]
+    false_: Option<String>,
+    #[serde(default)]
+    import: Vec<String>,
+    #[serde(default)]
+    as_int: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(default, deny_unknown_fields)]
 pub struct IntegerFormat {
     radix: Radix,
     /// Underscore between every n digits, zero to disable
     underscores: u8,
+    /// Inserted by `underscores` instead of `_`, e.g. `,` for documentation
+    separator: Option<char>,
     /// Zero pad to n digits, zero to disable
     zero_pad: u8,
     /// Omit `0x` prefix on non-base 10 numbers
     omit_prefix: bool,
+    /// Prefix of octal numbers
+    octal_style: OctalStyle,
+    /// Appended to the literal, e.g. `u32` in Rust
+    literal_suffix: String,
+    /// Representation of negative integers
+    negative_style: NegativeStyle,
+    /// Width of two's complement with `negative_style = "twos_complement"`,
+    /// used for values whose type has no `bits`, e.g. `-1` as `0xff` at 8 bits.
+    /// Values must fit in the width, either signed or unsigned. Like the width of
+    /// types, this applies to every radix, decimal included.
+    #[serde(deserialize_with = "deserialize_bits", skip_serializing_if = "Option::is_none")]
+    bits: Option<u8>,
 }
 impl IntegerFormat {
-    pub fn format(&self, mut integer: i128) -> String {
-        let negative: bool = integer < 0;
-        let radix = self.radix.value();
+    /// Like `format`, but negative integers are written in two's complement
+    /// if `negative_style` asks for it and `bits` gives the width.
+    /// Values must then fit in the width, either signed or unsigned.
+    pub fn format_fitted(&self, integer: i128) -> Result<String, FormatError> {
+        let bits = match self.bits {
+            Some(bits) if self.negative_style == NegativeStyle::TwosComplement => bits,
+            _ => return Ok(self.format(integer)),
+        };
+        // Every i128 is in the unsigned range of 127 bits, and in either range of 128
+        let too_small = bits < 128 && integer < -(1 << (bits - 1));
+        let too_large = bits < 127 && integer >= 1 << bits;
+        if too_small || too_large {
+            return Err(FormatError::OutOfRange {
+                value: integer,
+                bits,
+                signed: integer < 0,
+            });
+        }
+        let mask = u128::MAX >> (128 - bits as u32);
+        Ok(self.format_magnitude(false, integer as u128 & mask))
+    }
+
+    /// This format without `bits`, for values whose type gives the width instead
+    pub fn without_bits(&self) -> Self {
+        Self {
+            bits: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn format(&self, integer: i128) -> String {
+        // Magnitude as unsigned, as the absolute value of i128::MIN overflows
+        self.format_magnitude(integer < 0, integer.unsigned_abs())
+    }
 
-        integer = integer.abs();
+    fn format_magnitude(&self, negative: bool, mut magnitude: u128) -> String {
+        let radix = self.radix.value();
         let mut digits: Vec<char> = Vec::new();
-        while integer > 0 {
-            let digit = (integer % (radix as i128)) as u32;
+        while magnitude > 0 {
+            let digit = (magnitude % (radix as u128)) as u32;
             digits.push(std::char::from_digit(digit, radix).unwrap());
-            integer /= radix as i128;
+            magnitude /= radix as u128;
         }
 
         while digits.len() < (self.zero_pad.max(1) as usize) {
@@ -70,29 +301,183 @@ impl IntegerFormat {
             let mut i = result.len();
             while i > self.underscores as usize {
                 i -= self.underscores as usize;
-                result.insert(i, '_');
+                result.insert(i, self.separator.unwrap_or('_'));
             }
         }
 
         if !self.omit_prefix {
-            result = format!("{}{}", self.radix.prefix(), result);
+            let prefix = match (self.radix, self.octal_style) {
+                // Zero padding or the value zero already reads as octal in C
+                (Radix::Octal, OctalStyle::C) if result.starts_with('0') => "",
+                (Radix::Octal, OctalStyle::C) => "0",
+                (Radix::Octal, OctalStyle::None) => "",
+                (radix, _) => radix.prefix(),
+            };
+            result = format!("{}{}", prefix, result);
         }
 
-        if negative {
-            format!("-{}", result)
-        } else {
-            result
+        result.push_str(&self.literal_suffix);
+
+        match self.negative_style {
+            _ if !negative => result,
+            NegativeStyle::Parenthesized => format!("(-{})", result),
+            NegativeStyle::SignMagnitude | NegativeStyle::TwosComplement => {
+                format!("-{}", result)
+            },
+        }
+    }
+}
+
+/// Width in bits between 1 and 128
+pub(crate) fn deserialize_bits<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u8>, D::Error> {
+    use serde::de::Error;
+
+    match u8::deserialize(deserializer)? {
+        bits @ 1..=128 => Ok(Some(bits)),
+        bits => Err(D::Error::custom(format!("Width of {} bits is not between 1 and 128", bits))),
+    }
+}
+
+/// How negative numbers are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum NegativeStyle {
+    /// Sign followed by the magnitude, e.g. `-0x5`
+    #[serde(alias = "sign_magnitude")]
+    #[default]
+    SignMagnitude,
+    /// Two's complement at the width given by `bits` of the type, or else by `bits`
+    /// of the integer format, e.g. `0xffff_fffb` for a 32-bit type.
+    /// Without a width, sign and magnitude are used instead.
+    #[serde(alias = "twos_complement")]
+    TwosComplement,
+    /// Sign and magnitude in parentheses, e.g. `(-0x5)`
+    #[serde(alias = "parenthesized")]
+    Parenthesized,
+}
+impl NegativeStyle {
+    /// Sign and magnitude instead of `Parenthesized`
+    fn without_parentheses(self) -> Self {
+        match self {
+            Self::Parenthesized => Self::SignMagnitude,
+            other => other,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FloatFormat {
+    /// Appended to the literal, e.g. `f32` in Rust
+    literal_suffix: String,
+    /// Representation of negative floats. Two's complement is sign and magnitude here,
+    /// and fixed-point floats use the style of the integer format instead.
+    negative_style: NegativeStyle,
+    /// Output as a scaled integer using the integer format
+    fixed_point: Option<FixedPoint>,
+    /// Token for NaN, e.g. `f64::NAN` in Rust. NaN is an error if this is None.
+    nan: Option<String>,
+    /// Token for positive infinity, e.g. `INFINITY` in C.
+    /// Infinities are an error if this is None.
+    infinity: Option<String>,
+    /// Token for negative infinity, `infinity` prefixed by `-` if this is None
+    neg_infinity: Option<String>,
+}
+impl FloatFormat {
+    pub fn format(&self, float: f64) -> String {
+        let result = format!("{}{}", float_literal(float), self.literal_suffix);
+        match self.negative_style {
+            NegativeStyle::Parenthesized if result.starts_with('-') => format!("({})", result),
+            _ => result,
+        }
+    }
+
+    /// Configured token for NaN or an infinity
+    pub fn non_finite(&self, float: f64) -> Result<String, FormatError> {
+        let token = if float.is_nan() {
+            self.nan.clone()
+        } else if float > 0.0 {
+            self.infinity.clone()
+        } else {
+            self.neg_infinity
+                .clone()
+                .or_else(|| self.infinity.as_ref().map(|inf| format!("-{}", inf)))
+        };
+        token.ok_or(FormatError::NonFinite(float))
+    }
+}
+
+/// Shortest decimal representation that round-trips, always containing
+/// a fractional part or an exponent so that it reads back as a float.
+/// Exponents are used below `1e-5` and from `1e17` up.
+pub fn float_literal(float: f64) -> String {
+    if !float.is_finite() {
+        return float.to_string();
+    }
+
+    // Shortest round-trip digits, e.g. `-1.25e2`
+    let scientific = format!("{:e}", float);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+    if !(-5..17).contains(&exponent) {
+        return scientific;
+    }
+
+    let (sign, mantissa) = match mantissa.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", mantissa),
+    };
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    // Number of digits before the decimal point
+    let point = exponent + 1;
+    if point <= 0 {
+        format!("{}0.{}{}", sign, "0".repeat(-point as usize), digits)
+    } else if point as usize >= digits.len() {
+        format!("{}{}{}.0", sign, digits, "0".repeat(point as usize - digits.len()))
+    } else {
+        let (integer, fraction) = digits.split_at(point as usize);
+        format!("{}{}.{}", sign, integer, fraction)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FixedPoint {
+    /// Number of fractional bits, e.g. 16 for Q16.16
+    scale_bits: u8,
+}
+impl FixedPoint {
+    /// Multiply by `2^scale_bits` and round to the nearest integer
+    pub fn scale(self, float: f64) -> Result<i128, FormatError> {
+        round_to_integer(float * 2f64.powi(self.scale_bits as i32)).ok_or(
+            FormatError::FixedPointOverflow {
+                value: float,
+                scale_bits: self.scale_bits,
+            },
+        )
+    }
+}
+
+/// Round half away from zero, None if the result doesn't fit
+fn round_to_integer(value: f64) -> Option<i128> {
+    let rounded = value.round();
+    // Both bounds are exactly representable powers of two
+    if rounded.is_finite() && rounded >= i128::MIN as f64 && rounded < i128::MAX as f64 {
+        Some(rounded as i128)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 pub enum Radix {
     #[serde(alias = "bin")]
     Binary,
     #[serde(alias = "oct")]
     Octal,
     #[serde(alias = "dec")]
+    #[default]
     Decimal,
     #[serde(alias = "hex")]
     Hexadecimal,
@@ -116,14 +501,26 @@ impl Radix {
         }
     }
 }
-impl Default for Radix {
-    fn default() -> Self {
-        Self::Decimal
-    }
+
+/// How octal numbers are marked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum OctalStyle {
+    /// `0o` prefix, as in Rust and Python
+    #[serde(alias = "rust")]
+    #[default]
+    Rust,
+    /// Leading zero, as in C
+    #[serde(alias = "c")]
+    C,
+    /// No prefix
+    #[serde(alias = "none")]
+    None,
 }
 
 #[cfg(test)]
 mod test_formatting {
+    use std::f64;
+
     use super::*;
 
     #[test]
@@ -186,4 +583,325 @@ mod test_formatting {
         assert_eq!(f.format(0b1010_0101), "0b1010_0101");
         assert_eq!(f.format(0b1111_0000_1100_0011), "0b1111_0000_1100_0011");
     }
+
+    #[test]
+    fn test_integer_format_dec_grouping() {
+        let mut f = IntegerFormat {
+            underscores: 3,
+            ..Default::default()
+        };
+        assert_eq!(f.format(0), "0");
+        assert_eq!(f.format(999), "999");
+        assert_eq!(f.format(1000), "1_000");
+        assert_eq!(f.format(1_000_000), "1_000_000");
+        assert_eq!(f.format(-12_345_678), "-12_345_678");
+
+        f.separator = Some(',');
+        assert_eq!(f.format(1_000_000), "1,000,000");
+        f.separator = Some('\'');
+        assert_eq!(f.format(1_000_000), "1'000'000");
+
+        let f: IntegerFormat =
+            toml::from_str("radix = \"dec\"\nunderscores = 3\nseparator = \",\"").unwrap();
+        assert_eq!(f.format(65536), "65,536");
+        assert!(toml::from_str::<IntegerFormat>("separator = \",,\"").is_err());
+    }
+
+    #[test]
+    fn test_integer_format_oct() {
+        let mut f = IntegerFormat {
+            radix: Radix::Octal,
+            ..Default::default()
+        };
+        assert_eq!(f.format(64), "0o100");
+        assert_eq!(f.format(0), "0o0");
+
+        f.octal_style = OctalStyle::C;
+        assert_eq!(f.format(64), "0100");
+        assert_eq!(f.format(-64), "-0100");
+        assert_eq!(f.format(0), "0");
+        f.zero_pad = 4;
+        assert_eq!(f.format(64), "0100");
+        assert_eq!(f.format(8), "0010");
+
+        f.octal_style = OctalStyle::None;
+        assert_eq!(f.format(64), "0100");
+        f.zero_pad = 0;
+        assert_eq!(f.format(64), "100");
+
+        let f: IntegerFormat = toml::from_str("radix = \"oct\"\noctal_style = \"c\"").unwrap();
+        assert_eq!(f.format(0o755), "0755");
+        let f: IntegerFormat = toml::from_str("radix = \"hex\"\noctal_style = \"c\"").unwrap();
+        assert_eq!(f.format(0x10), "0x10");
+    }
+
+    #[test]
+    fn test_integer_format_min() {
+        let f = IntegerFormat::default();
+        assert_eq!(f.format(i128::MIN), i128::MIN.to_string());
+
+        let f = IntegerFormat {
+            radix: Radix::Hexadecimal,
+            ..Default::default()
+        };
+        assert_eq!(f.format(i128::MIN), "-0x80000000000000000000000000000000");
+    }
+
+    #[test]
+    fn test_list_format() {
+        let items = vec![Primitive::Integer(10), Primitive::Boolean(true)];
+        let mut f = Format::default();
+        assert_eq!(f.format(&Primitive::List(items.clone())).unwrap(), "[10, true]");
+
+        f.integer = Some(IntegerFormat {
+            radix: Radix::Hexadecimal,
+            ..Default::default()
+        });
+        f.list = Some(ListFormat {
+            open: "{".to_owned(),
+            separator: ",".to_owned(),
+            close: "}".to_owned(),
+        });
+        assert_eq!(f.format(&Primitive::List(items)).unwrap(), "{0xa,true}");
+    }
+
+    #[test]
+    fn test_negative_style() {
+        let format = |style: &str| {
+            let text = format!("radix = \"hex\"\nnegative_style = \"{}\"", style);
+            toml::from_str::<IntegerFormat>(&text).unwrap().format(-5)
+        };
+        assert_eq!(format("sign_magnitude"), "-0x5");
+        assert_eq!(format("parenthesized"), "(-0x5)");
+        // Without a width from the type
+        assert_eq!(format("twos_complement"), "-0x5");
+    }
+
+    #[test]
+    fn test_twos_complement_bits() {
+        let format = |text: &str, value: i128| {
+            let f = Format {
+                integer: Some(toml::from_str(text).unwrap()),
+                ..Default::default()
+            };
+            f.format(&Primitive::Integer(value))
+        };
+        let hex = |bits: u8| {
+            format!("radix = \"hex\"\nnegative_style = \"twos_complement\"\nbits = {}", bits)
+        };
+        assert_eq!(format(&hex(8), -1), Ok("0xff".to_owned()));
+        assert_eq!(format(&hex(16), -1), Ok("0xffff".to_owned()));
+        assert_eq!(format(&hex(8), -128), Ok("0x80".to_owned()));
+        assert_eq!(format(&hex(8), 5), Ok("0x5".to_owned()));
+        assert_eq!(format(&hex(8), 255), Ok("0xff".to_owned()));
+        for value in &[-129, 256, 300] {
+            let error = format(&hex(8), *value);
+            assert!(matches!(error, Err(FormatError::OutOfRange { bits: 8, .. })), "{:?}", error);
+        }
+        assert_eq!(format(&hex(127), 5), Ok("0x5".to_owned()));
+        assert_eq!(format(&hex(127), i128::MAX), Ok(format!("0x{:x}", i128::MAX)));
+        let error = format(&hex(127), i128::MIN);
+        assert!(matches!(error, Err(FormatError::OutOfRange { bits: 127, .. })), "{:?}", error);
+        assert_eq!(format(&hex(127), -1), Ok(format!("0x{:x}", u128::MAX >> 1)));
+        assert_eq!(format(&hex(128), -1), Ok(format!("0x{:x}", u128::MAX)));
+        assert_eq!(format(&hex(128), i128::MIN), Ok(format!("0x{:x}", 1u128 << 127)));
+
+        // Decimal too, like the width of types
+        let text = "negative_style = \"twos_complement\"\nbits = 8";
+        assert_eq!(format(text, -1), Ok("255".to_owned()));
+
+        // Only with two's complement selected
+        assert_eq!(format("radix = \"hex\"\nbits = 8", -1), Ok("-0x1".to_owned()));
+        let text = "radix = \"hex\"\nnegative_style = \"parenthesized\"\nbits = 8";
+        assert_eq!(format(text, -1), Ok("(-0x1)".to_owned()));
+
+        for bits in &[0, 129, 255] {
+            let text = format!("bits = {}", bits);
+            assert!(toml::from_str::<IntegerFormat>(&text).is_err(), "{}", bits);
+        }
+    }
+
+    #[test]
+    fn test_parenthesize_negative() {
+        let f: Format = toml::from_str(
+            r#"
+            integer = { negative_style = "parenthesized" }
+            float = { negative_style = "parenthesized" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(f.format(&Primitive::Integer(-5)).unwrap(), "(-5)");
+        assert_eq!(f.format(&Primitive::Integer(5)).unwrap(), "5");
+        assert_eq!(f.format(&Primitive::Float(-0.5)).unwrap(), "(-0.5)");
+        assert_eq!(f.format(&Primitive::Float(0.5)).unwrap(), "0.5");
+        assert_eq!(f.format(&Primitive::String("-".to_owned())).unwrap(), "\"-\"");
+        let items = vec![Primitive::Integer(-1), Primitive::Integer(2)];
+        assert_eq!(f.format(&Primitive::List(items)).unwrap(), "[(-1), 2]");
+
+        // Fixed-point floats and scaled values are integers
+        let f: Format = toml::from_str(
+            r#"
+            integer = { negative_style = "parenthesized" }
+            float = { fixed_point = { scale_bits = 1 } }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(f.format(&Primitive::Float(-1.5)).unwrap(), "(-3)");
+        let scaled = Format {
+            scale: Some(10.0),
+            ..f.clone()
+        };
+        assert_eq!(scaled.format(&Primitive::Float(-0.5)).unwrap(), "(-5)");
+
+        // Overrides replace the integer format with its style
+        let off = toml::from_str("integer = {}").unwrap();
+        assert_eq!(f.merged(&off).format(&Primitive::Integer(-5)).unwrap(), "-5");
+        assert_eq!(f.merged(&Format::default()).format(&Primitive::Integer(-5)).unwrap(), "(-5)");
+    }
+
+    #[test]
+    fn test_literal_suffix() {
+        let f = IntegerFormat {
+            literal_suffix: "u32".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(f.format(100), "100u32");
+
+        let f = IntegerFormat {
+            literal_suffix: "i32".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(f.format(-100), "-100i32");
+
+        let f = IntegerFormat {
+            radix: Radix::Hexadecimal,
+            literal_suffix: "i64".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(f.format(-0xff), "-0xffi64");
+
+        let f = FloatFormat {
+            literal_suffix: "f32".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(f.format(1.5), "1.5f32");
+        assert_eq!(f.format(-1.5), "-1.5f32");
+    }
+
+    #[test]
+    fn test_fixed_point() {
+        let mut f = Format {
+            float: Some(FloatFormat {
+                fixed_point: Some(FixedPoint { scale_bits: 16 }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(f.format(&Primitive::Float(1.25)).unwrap(), "81920");
+        assert_eq!(f.format(&Primitive::Float(0.1)).unwrap(), "6554");
+        assert_eq!(f.format(&Primitive::Float(f64::consts::PI)).unwrap(), "205887");
+        assert_eq!(f.format(&Primitive::Float(-0.5)).unwrap(), "-32768");
+        assert_eq!(f.format(&Primitive::Integer(3)).unwrap(), "3");
+
+        f.integer = Some(IntegerFormat {
+            radix: Radix::Hexadecimal,
+            ..Default::default()
+        });
+        assert_eq!(f.format(&Primitive::Float(1.0)).unwrap(), "0x10000");
+
+        assert_eq!(
+            f.format(&Primitive::Float(1e36)),
+            Err(FormatError::FixedPointOverflow {
+                value: 1e36,
+                scale_bits: 16
+            })
+        );
+        assert!(f.format(&Primitive::Float(f64::NAN)).is_err());
+    }
+
+    #[test]
+    fn test_scale() {
+        let mut f = Format {
+            scale: Some(100.0),
+            ..Default::default()
+        };
+        assert_eq!(f.format(&Primitive::Float(0.95)).unwrap(), "95");
+        assert_eq!(f.format(&Primitive::Float(0.125)).unwrap(), "13");
+        assert_eq!(f.format(&Primitive::Float(-0.125)).unwrap(), "-13");
+        assert_eq!(f.format(&Primitive::Integer(2)).unwrap(), "200");
+        assert_eq!(f.format(&Primitive::Boolean(true)).unwrap(), "true");
+        assert_eq!(
+            f.format(&Primitive::List(vec![Primitive::Float(0.5), Primitive::Float(0.25)]))
+                .unwrap(),
+            "[50, 25]"
+        );
+
+        f.integer = Some(IntegerFormat {
+            literal_suffix: "u8".to_owned(),
+            ..Default::default()
+        });
+        assert_eq!(f.format(&Primitive::Float(0.95)).unwrap(), "95u8");
+        assert_eq!(
+            f.format(&Primitive::Float(1e300)),
+            Err(FormatError::ScaleOverflow {
+                value: 1e300,
+                scale: 100.0
+            })
+        );
+    }
+
+    #[test]
+    fn test_non_finite() {
+        let mut f = Format::default();
+        assert!(matches!(
+            f.format(&Primitive::Float(f64::NAN)),
+            Err(FormatError::NonFinite(v)) if v.is_nan()
+        ));
+        assert_eq!(
+            f.format(&Primitive::Float(f64::INFINITY)),
+            Err(FormatError::NonFinite(f64::INFINITY))
+        );
+
+        f.float = Some(FloatFormat {
+            infinity: Some("f64::INFINITY".to_owned()),
+            literal_suffix: "f64".to_owned(),
+            ..Default::default()
+        });
+        assert_eq!(f.format(&Primitive::Float(f64::INFINITY)).unwrap(), "f64::INFINITY");
+        assert_eq!(f.format(&Primitive::Float(f64::NEG_INFINITY)).unwrap(), "-f64::INFINITY");
+        assert_eq!(f.format(&Primitive::Float(1.0)).unwrap(), "1.0f64");
+        assert!(f.format(&Primitive::Float(f64::NAN)).is_err());
+
+        f.float = Some(FloatFormat {
+            nan: Some("NAN".to_owned()),
+            infinity: Some("INFINITY".to_owned()),
+            neg_infinity: Some("(-INFINITY)".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(f.format(&Primitive::Float(f64::NAN)).unwrap(), "NAN");
+        assert_eq!(f.format(&Primitive::Float(f64::NEG_INFINITY)).unwrap(), "(-INFINITY)");
+        assert_eq!(
+            f.format(&Primitive::List(vec![Primitive::Float(f64::INFINITY)])).unwrap(),
+            "[INFINITY]"
+        );
+    }
+
+    #[test]
+    fn test_float_literal() {
+        assert_eq!(float_literal(0.1), "0.1");
+        assert_eq!(float_literal(1.0), "1.0");
+        assert_eq!(float_literal(-1.0), "-1.0");
+        assert_eq!(float_literal(0.0), "0.0");
+        assert_eq!(float_literal(1.0 / 3.0), "0.3333333333333333");
+        assert_eq!(float_literal(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(float_literal(123_456.0), "123456.0");
+        assert_eq!(float_literal(-12.5), "-12.5");
+        assert_eq!(float_literal(0.000_015), "0.000015");
+        assert_eq!(float_literal(1.5e-7), "1.5e-7");
+        assert_eq!(float_literal(1e17), "1e17");
+        assert_eq!(float_literal(-2.5e300), "-2.5e300");
+        assert_eq!(float_literal(f64::INFINITY), "inf");
+
+        assert_eq!(Format::default().format(&Primitive::Float(2.0)).unwrap(), "2.0");
+    }
 }
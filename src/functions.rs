@@ -1,12 +1,15 @@
 use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::rc::Rc;
 
 use crate::expr::{EvalError, EvalErrorMessage, Expr, ExprValue, Location};
 use crate::value::Primitive;
 
 type R = Result<Expr, EvalError>;
-type F = fn(Location, Vec<Expr>) -> R;
+type F = Rc<dyn Fn(Location, Vec<Expr>) -> R>;
 
-#[derive(Debug)]
+#[derive(Clone)]
 pub struct Functions(HashMap<String, F>);
 impl Functions {
     pub fn new() -> Self {
@@ -20,18 +23,69 @@ impl Functions {
         result.insert("or", f_or);
         result.insert("add", f_add);
         result.insert("mul", f_mul);
+        result.insert("div", f_div);
+        result.insert("mod", f_mod);
+        result.insert("floor_div", f_floor_div);
+        result.insert("euclid_mod", f_euclid_mod);
+        result.insert("bits", f_bits);
+        result.insert("wadd", f_wadd);
+        result.insert("wsub", f_wsub);
+        result.insert("wmul", f_wmul);
         result.insert("fract", f_fract);
+        result.insert("next_pow2", f_next_pow2);
+        result.insert("is_pow2", f_is_pow2);
+        result.insert("trailing_zeros", f_trailing_zeros);
+        result.insert("leading_zeros", f_leading_zeros);
+        result.insert("align_up", f_align_up);
+        result.insert("align_down", f_align_down);
+        result.insert("typeof", f_typeof);
+        result.insert("type_min", f_type_min);
+        result.insert("type_max", f_type_max);
+        result.insert("list", f_list);
+        result.insert("count", f_count);
+        result.insert("sum", f_sum);
+        result.insert("min", f_min);
+        result.insert("max", f_max);
+        result.insert("repeat", f_repeat);
+        result.insert("hash", f_hash);
+        result.insert("size", f_size);
+        result.insert("bool", f_bool);
+        result.insert("parse_int", f_parse_int);
+        result.insert("parse_float", f_parse_float);
         result
     }
 
-    pub fn insert(&mut self, key: &str, value: F) {
-        self.0.insert(key.to_string(), value);
+    /// Register `env`, which makes the output depend on the environment
+    /// in addition to the input files
+    pub fn allow_env(&mut self) {
+        self.insert("env", f_env);
+    }
+
+    pub fn insert(&mut self, key: &str, value: fn(Location, Vec<Expr>) -> R) {
+        self.0.insert(key.to_string(), Rc::new(value));
+    }
+
+    /// Functions that capture state, e.g. user-defined macros
+    pub fn insert_closure<C>(&mut self, key: &str, value: C)
+    where C: Fn(Location, Vec<Expr>) -> R + 'static {
+        self.0.insert(key.to_string(), Rc::new(value));
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.contains_key(key)
     }
 
     pub fn get(&self, key: &str) -> Option<&F> {
         self.0.get(key)
     }
 }
+impl fmt::Debug for Functions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let mut names: Vec<&String> = self.0.keys().collect();
+        names.sort();
+        f.debug_tuple("Functions").field(&names).finish()
+    }
+}
 
 macro_rules! check_argc_exact {
     ($c:expr; $location:expr, $args:expr) => {
@@ -115,21 +169,98 @@ fn f_add(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
     })
 }
 
+/// `(bits FLAG ...)`, all flags combined with bitwise or
+fn f_bits(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_min!(1; location, args);
+    let mut acc = Primitive::Integer(0);
+    for arg in args {
+        acc = acc.bit_or(&value!(arg)).map_err(|err| arg.error_here(err))?;
+    }
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
 fn f_mul(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
     check_argc_min!(2; location, args);
     let mut acc = value!(args[0]);
     for arg in args.into_iter().skip(1) {
-        acc = acc.mul(&value!(arg)).map_err(|message| EvalError {
-            location: arg.location,
-            message,
-        })?;
+        acc = acc.mul(&value!(arg)).map_err(|err| arg.error_here(err))?;
+    }
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_division(
+    location: Location, args: Vec<Expr>,
+    op: fn(&Primitive, &Primitive) -> Result<Primitive, EvalErrorMessage>,
+) -> Result<Expr, EvalError> {
+    check_argc_exact!(2; location, args);
+    let acc = op(&value!(args[0]), &value!(args[1])).map_err(|err| args[1].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+/// Truncating division: `(div -7 2)` is `-3`
+fn f_div(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    f_division(location, args, Primitive::div)
+}
+
+/// Truncating remainder: `(mod -7 2)` is `-1`
+fn f_mod(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    f_division(location, args, Primitive::rem)
+}
+
+/// Floor division: `(floor_div -7 2)` is `-4`
+fn f_floor_div(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    f_division(location, args, Primitive::floor_div)
+}
+
+/// Euclidean remainder: `(euclid_mod -7 2)` is `1`
+fn f_euclid_mod(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    f_division(location, args, Primitive::euclid_mod)
+}
+
+/// Width of wrapping operations when not given
+const DEFAULT_WRAPPING_WIDTH: i128 = 64;
+
+fn f_wrapping(
+    location: Location, args: Vec<Expr>,
+    op: fn(&Primitive, &Primitive, &Primitive) -> Result<Primitive, EvalErrorMessage>,
+) -> Result<Expr, EvalError> {
+    check_argc_min!(2; location, args);
+    if args.len() > 3 {
+        return Err(location.error_here(EvalErrorMessage::ArgumentCount));
     }
+    let width = args
+        .get(2)
+        .map(|arg| value!(arg))
+        .unwrap_or(Primitive::Integer(DEFAULT_WRAPPING_WIDTH));
+    let acc = op(&value!(args[0]), &value!(args[1]), &width)
+        .map_err(|err| args[0].error_here(err))?;
     Ok(Expr {
         location,
         value: ExprValue::Primitive(acc),
     })
 }
 
+fn f_wadd(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    f_wrapping(location, args, Primitive::wrapping_add)
+}
+
+fn f_wsub(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    f_wrapping(location, args, Primitive::wrapping_sub)
+}
+
+fn f_wmul(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    f_wrapping(location, args, Primitive::wrapping_mul)
+}
+
 fn f_fract(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
     check_argc_exact!(1; location, args);
     if let Primitive::Float(p) = value!(args[0]) {
@@ -143,3 +274,252 @@ fn f_fract(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
         )))
     }
 }
+
+fn f_next_pow2(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .next_pow2()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_is_pow2(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .is_pow2()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_trailing_zeros(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .trailing_zeros()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_leading_zeros(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .leading_zeros()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_align_up(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(2; location, args);
+    let acc = value!(args[0])
+        .align_up(&value!(args[1]))
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_align_down(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(2; location, args);
+    let acc = value!(args[0])
+        .align_down(&value!(args[1]))
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_typeof(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let type_name = value!(args[0]).type_name().to_owned();
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(Primitive::String(type_name)),
+    })
+}
+
+fn f_type_min(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .type_min()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_type_max(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .type_max()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_list(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    let items = args.iter().map(|arg| value!(arg)).collect();
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(Primitive::List(items)),
+    })
+}
+
+fn f_count(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    if let Primitive::List(items) = value!(args[0]) {
+        Ok(Expr {
+            location,
+            value: ExprValue::Primitive(Primitive::Integer(items.len() as i128)),
+        })
+    } else {
+        Err(args[0].error_here(EvalErrorMessage::InvalidArgument(
+            "Only lists can be counted".to_owned(),
+        )))
+    }
+}
+
+fn f_sum(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .sum()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_min(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .min()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_max(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .max()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_repeat(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(2; location, args);
+    let acc = value!(args[0])
+        .repeat(&value!(args[1]))
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_hash(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .hash()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+/// `(env "NAME" [default])`, the value of an environment variable as a string
+fn f_env(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_min!(1; location, args);
+    if args.len() > 2 {
+        return Err(location.error_here(EvalErrorMessage::ArgumentCount));
+    }
+    let name = match value!(args[0]) {
+        Primitive::String(name) => name,
+        other => {
+            return Err(args[0].error_here(EvalErrorMessage::InvalidArgument(format!(
+                "Cannot (env {:?})",
+                other
+            ))));
+        },
+    };
+    let acc = match (env::var(&name), args.get(1)) {
+        (Ok(value), _) => Primitive::String(value),
+        (Err(env::VarError::NotPresent), Some(default)) => value!(default),
+        (Err(error), _) => {
+            return Err(args[0].error_here(EvalErrorMessage::InvalidArgument(format!(
+                "Environment variable {:?}: {}",
+                name, error
+            ))));
+        },
+    };
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_size(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .size()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_bool(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .to_bool()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_parse_int(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .parse_int()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
+
+fn f_parse_float(location: Location, args: Vec<Expr>) -> Result<Expr, EvalError> {
+    check_argc_exact!(1; location, args);
+    let acc = value!(args[0])
+        .parse_float()
+        .map_err(|err| args[0].error_here(err))?;
+    Ok(Expr {
+        location,
+        value: ExprValue::Primitive(acc),
+    })
+}
@@ -0,0 +1,1956 @@
+//! Share constants between languages
+//!
+//! Constants are resolved with [`resolve`] and then rendered
+//! for all enabled languages with [`generate`].
+
+#![deny(unused_must_use)]
+#![allow(clippy::cast_lossless)]
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod constants;
+mod expr;
+mod format_value;
+mod functions;
+mod options;
+mod presets;
+mod suggest;
+mod template;
+mod timing;
+mod value;
+
+pub use self::constants::{Constant, Constants, Macro};
+pub use self::expr::{parse_literal, EvalError, EvalErrorMessage, Location};
+pub use self::format_value::FormatError;
+pub use self::options::{Commands, ConstantError, LangOptions, Options};
+pub use self::timing::Timings;
+pub use self::value::{Context, Primitive};
+
+use self::constants::{checksum, Evaluator};
+use self::functions::Functions;
+use self::options::{ConstantSort, SortScope};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse { path: PathBuf, error: toml::de::Error },
+    ParseJson { path: PathBuf, error: serde_json::Error },
+    MissingArgument(&'static str),
+    /// Options combined from language presets are invalid
+    InvalidOptions(String),
+    InvalidStem(OsString),
+    Evaluation(String, EvalError),
+    /// Condition of a language could not be evaluated to a boolean
+    Condition { language: String, error: EvalError },
+    DuplicateConstant(String),
+    /// Constant given by name on the command line is not defined
+    UnknownConstant(String),
+    /// Input exceeds a limit of `ResolveOptions`
+    LimitExceeded(String),
+    /// Chain of references from a constant, ending at a symbol that
+    /// is either not defined at all or defined after its referrer
+    UnknownSymbol { chain: Vec<String>, undefined: bool, error: EvalError },
+    DuplicateFunction(String),
+    ExtendsCycle(Vec<PathBuf>),
+    Formatter(String),
+    CreateTargetDir { path: PathBuf, error: io::Error },
+    ProtectedTarget { path: PathBuf, reason: &'static str },
+    /// Enabled languages that would write the same target file
+    TargetCollision { path: PathBuf, languages: Vec<String> },
+    OutOfDate(Vec<PathBuf>),
+    ImportsNotSupported { language: String },
+    TypeRequired { language: String, constant: String },
+    Format { language: String, constant: String, error: FormatError },
+    /// A type has a `value_command`, but running commands was not allowed
+    CommandsNotAllowed { language: String, type_: String },
+    /// The `value_command` of the type of a constant failed
+    ValueCommand { language: String, constant: String, message: String },
+    /// Output name of a constant is not a valid identifier in a language
+    InvalidIdentifier { language: String, constant: String, name: String },
+    /// Intro or outro uses `$stamp_KEY`, but no such stamp was given
+    MissingStamp { language: String, key: String },
+    /// Several errors collected with `resolve_keep_going`
+    Multiple(Vec<Error>),
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::Parse { path, error } => {
+                write!(f, "Could not parse {:?}: {}", path, error)?;
+                write_suggestion(f, &error.to_string())
+            },
+            Self::ParseJson { path, error } => write!(f, "Could not parse {:?}: {}", path, error),
+            Self::MissingArgument(name) => write!(
+                f,
+                "Argument {} must be given on the command line or in constcodegen.toml",
+                name
+            ),
+            Self::InvalidOptions(message) => {
+                write!(f, "Invalid options: {}", message)?;
+                write_suggestion(f, message)
+            },
+            Self::InvalidStem(stem) => write!(f, "Filename stem {:?} is not valid UTF-8", stem),
+            Self::Evaluation(name, error) => write!(f, "In constant {:?}:\n{}", name, error),
+            Self::Condition { language, error } => {
+                write!(f, "In condition of language {:?}:\n{}", language, error)
+            },
+            Self::DuplicateConstant(name) => write!(f, "Duplicate constant definition {:?}", name),
+            Self::UnknownConstant(name) => write!(f, "Constant {:?} is not defined", name),
+            Self::LimitExceeded(message) => write!(f, "Limit exceeded: {}", message),
+            Self::UnknownSymbol {
+                chain,
+                undefined,
+                error,
+            } => {
+                write!(f, "Constant {:?} references {:?}", chain[0], chain[1])?;
+                for name in &chain[2..] {
+                    write!(f, ", which references {:?}", name)?;
+                }
+                if *undefined {
+                    write!(f, ", which is not defined")?;
+                } else {
+                    write!(f, ", which is defined after it")?;
+                }
+                write!(f, "\n{}", error)
+            },
+            Self::DuplicateFunction(name) => {
+                write!(f, "Macro {:?} is already defined as a function", name)
+            },
+            Self::ExtendsCycle(paths) => {
+                write!(f, "Constants files extend each other in a cycle:")?;
+                for path in paths {
+                    write!(f, "\n  {}", path.display())?;
+                }
+                Ok(())
+            },
+            Self::ImportsNotSupported { language } => write!(
+                f,
+                "Language {:?} does not specify import syntax, but it is required",
+                language
+            ),
+            Self::TypeRequired { language, constant } => write!(
+                f,
+                "Language {:?} requires types, but constant {:?} does not provide one",
+                language, constant
+            ),
+            Self::Format {
+                language,
+                constant,
+                error,
+            } => write!(
+                f,
+                "Cannot format constant {:?} for language {:?}: {}",
+                constant, language, error
+            ),
+            Self::CommandsNotAllowed { language, type_ } => write!(
+                f,
+                "Type {:?} of language {:?} has a value_command, which only runs with \
+                 --allow-commands",
+                type_, language
+            ),
+            Self::ValueCommand {
+                language,
+                constant,
+                message,
+            } => write!(
+                f,
+                "Value command for constant {:?} in language {:?} failed: {}",
+                constant, language, message
+            ),
+            Self::InvalidIdentifier {
+                language,
+                constant,
+                name,
+            } => write!(
+                f,
+                "Constant {:?} is output as {:?}, which is not a valid identifier \
+                 in language {:?}; give it another name with `rename`",
+                constant, name, language
+            ),
+            Self::MissingStamp { language, key } => write!(
+                f,
+                "Language {:?} uses $stamp_{}, which is not set; pass --stamp {}=VALUE",
+                language, key, key
+            ),
+            Self::CreateTargetDir { path, error } => {
+                write!(f, "Could not create target directory {:?}: {}", path, error)
+            },
+            Self::ProtectedTarget { path, reason } => write!(
+                f,
+                "Refusing to overwrite {:?}, which {}; use --force to replace it",
+                path, reason
+            ),
+            Self::TargetCollision { path, languages } => write!(
+                f,
+                "Languages {} would all write {:?}; give them different file extensions",
+                languages.iter().map(|l| format!("{:?}", l)).collect::<Vec<_>>().join(", "),
+                path
+            ),
+            Self::OutOfDate(paths) => {
+                write!(f, "Generated files are out of date:")?;
+                for path in paths {
+                    write!(f, "\n  {}", path.display())?;
+                }
+                Ok(())
+            },
+            Self::Formatter(message) => write!(f, "Formatter failed: {}", message),
+            Self::Multiple(errors) => {
+                write!(f, "{} errors occurred:", errors.len())?;
+                for error in errors {
+                    write!(f, "\n{}", error)?;
+                }
+                Ok(())
+            },
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// Suggest a valid field if `message` is about an unknown one
+fn write_suggestion(f: &mut fmt::Formatter, message: &str) -> Result<(), fmt::Error> {
+    match suggest::unknown_field(message) {
+        Some(field) => write!(f, "\nDid you mean `{}`?", field),
+        None => Ok(()),
+    }
+}
+
+impl Error {
+    /// Process exit code for this error category:
+    /// * 1: generated files out of date (`--check`)
+    /// * 2: invalid configuration or arguments
+    /// * 3: reading or writing files failed
+    /// * 4: parsing an input file failed
+    /// * 5: evaluating constants failed
+    /// * 6: running the formatter or a value command failed
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::OutOfDate(_) => 1,
+            Self::MissingArgument(_)
+            | Self::InvalidOptions(_)
+            | Self::InvalidStem(_)
+            | Self::ExtendsCycle(_)
+            | Self::UnknownConstant(_)
+            | Self::TargetCollision { .. }
+            | Self::ImportsNotSupported { .. }
+            | Self::TypeRequired { .. }
+            | Self::CommandsNotAllowed { .. }
+            | Self::MissingStamp { .. } => 2,
+            Self::Io(_) | Self::CreateTargetDir { .. } | Self::ProtectedTarget { .. } => 3,
+            Self::Parse { .. } | Self::ParseJson { .. } => 4,
+            Self::Evaluation(..)
+            | Self::Condition { .. }
+            | Self::DuplicateConstant(_)
+            | Self::UnknownSymbol { .. }
+            | Self::DuplicateFunction(_)
+            | Self::LimitExceeded(_)
+            | Self::Format { .. }
+            | Self::InvalidIdentifier { .. } => 5,
+            Self::Formatter(_) | Self::ValueCommand { .. } => 6,
+            Self::Multiple(errors) => errors.first().map_or(0, Self::exit_code),
+        }
+    }
+
+    /// Short name of the error variant, for machine-readable output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Io(_) => "io",
+            Self::Parse { .. } | Self::ParseJson { .. } => "parse",
+            Self::MissingArgument(_) => "missing_argument",
+            Self::InvalidOptions(_) => "invalid_options",
+            Self::InvalidStem(_) => "invalid_stem",
+            Self::Evaluation(..) => "evaluation",
+            Self::Condition { .. } => "condition",
+            Self::DuplicateConstant(_) => "duplicate_constant",
+            Self::UnknownConstant(_) => "unknown_constant",
+            Self::LimitExceeded(_) => "limit_exceeded",
+            Self::UnknownSymbol { .. } => "unknown_symbol",
+            Self::DuplicateFunction(_) => "duplicate_function",
+            Self::ExtendsCycle(_) => "extends_cycle",
+            Self::Formatter(_) => "formatter",
+            Self::CreateTargetDir { .. } => "create_target_dir",
+            Self::ProtectedTarget { .. } => "protected_target",
+            Self::TargetCollision { .. } => "target_collision",
+            Self::OutOfDate(_) => "out_of_date",
+            Self::ImportsNotSupported { .. } => "imports_not_supported",
+            Self::TypeRequired { .. } => "type_required",
+            Self::Format { .. } => "format",
+            Self::CommandsNotAllowed { .. } => "commands_not_allowed",
+            Self::ValueCommand { .. } => "value_command",
+            Self::InvalidIdentifier { .. } => "invalid_identifier",
+            Self::MissingStamp { .. } => "missing_stamp",
+            Self::Multiple(_) => "multiple",
+        }
+    }
+
+    /// Machine-readable description with `kind` and `message`, and where applicable
+    /// `constant`, `file`, and one-based `line` and `column`. For evaluation errors
+    /// the line and column are within the expression. Errors collected with
+    /// `resolve_keep_going` are listed in `errors`.
+    pub fn to_json(&self) -> serde_json::Value {
+        use serde_json::json;
+
+        let mut object = json!({
+            "kind": self.kind(),
+            "message": self.to_string(),
+        });
+        let constant = match self {
+            Self::Evaluation(name, _)
+            | Self::DuplicateConstant(name)
+            | Self::UnknownConstant(name) => Some(name),
+            Self::UnknownSymbol { chain, .. } => chain.first(),
+            Self::TypeRequired { constant, .. }
+            | Self::Format { constant, .. }
+            | Self::ValueCommand { constant, .. }
+            | Self::InvalidIdentifier { constant, .. } => Some(constant),
+            _ => None,
+        };
+        if let Some(constant) = constant {
+            object["constant"] = json!(constant);
+        }
+        let file = match self {
+            Self::Parse { path, .. }
+            | Self::ParseJson { path, .. }
+            | Self::CreateTargetDir { path, .. }
+            | Self::ProtectedTarget { path, .. }
+            | Self::TargetCollision { path, .. } => Some(path),
+            _ => None,
+        };
+        if let Some(file) = file {
+            object["file"] = json!(file.display().to_string());
+        }
+        let line_column = match self {
+            Self::Parse { error, .. } => error.line_col().map(|(l, c)| (l + 1, c + 1)),
+            Self::ParseJson { error, .. } => Some((error.line(), error.column())),
+            Self::Evaluation(_, error)
+            | Self::Condition { error, .. }
+            | Self::UnknownSymbol { error, .. } => Some(error.location.line_column()),
+            _ => None,
+        };
+        if let Some((line, column)) = line_column {
+            object["line"] = json!(line);
+            object["column"] = json!(column);
+        }
+        if let Self::Multiple(errors) = self {
+            object["errors"] = errors.iter().map(Self::to_json).collect();
+        }
+        object
+    }
+}
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+impl From<(Constant, EvalError)> for Error {
+    fn from((c, error): (Constant, EvalError)) -> Self {
+        Self::Evaluation(c.name, error)
+    }
+}
+
+/// Resolve the values of constants in order.
+/// Constants can only refer to constants before them,
+/// and macros can only call macros before them.
+pub fn resolve(constants: &mut [Constant], macros: &[Macro]) -> Result<Context, Error> {
+    resolve_with(constants, macros, &ResolveOptions::default())
+}
+
+/// Like [`resolve`], but continues past constants that fail to resolve.
+/// The failing constants are left out of the context, so constants
+/// referring to them fail as well. All errors are returned together.
+pub fn resolve_keep_going(constants: &mut [Constant], macros: &[Macro]) -> Result<Context, Error> {
+    let options = ResolveOptions {
+        keep_going: true,
+        ..ResolveOptions::default()
+    };
+    resolve_with(constants, macros, &options)
+}
+
+/// Settings for [`resolve_with`]
+#[derive(Debug, Clone, Default)]
+pub struct ResolveOptions {
+    /// Continue past failing constants, as in [`resolve_keep_going`]
+    pub keep_going: bool,
+    /// Provide the `(env "NAME" [default])` function.
+    /// Off by default, as it makes the output depend on the environment.
+    pub allow_env: bool,
+    /// Values available to all constants without being evaluated.
+    /// A constant with the same name takes the seeded value instead of its own.
+    pub seeds: Context,
+    /// Values of constants from extended files, available to all constants.
+    /// A constant with the same name replaces the inherited value for the ones after it.
+    pub inherited: Context,
+    /// Maximum number of constants, for untrusted input
+    pub max_constants: Option<usize>,
+    /// Maximum length of an expression or macro body in bytes
+    pub max_expression_length: Option<usize>,
+    /// Maximum nesting of calls in a single expression or macro body
+    pub max_expression_depth: Option<usize>,
+}
+impl ResolveOptions {
+    /// Fail if the input is larger than the limits allow
+    fn check_limits(&self, constants: &[Constant], macros: &[Macro]) -> Result<(), Error> {
+        if let Some(max) = self.max_constants {
+            if constants.len() > max {
+                return Err(Error::LimitExceeded(format!(
+                    "{} constants given, but at most {} are allowed",
+                    constants.len(),
+                    max
+                )));
+            }
+        }
+        let expressions = constants
+            .iter()
+            .filter_map(|c| Some((&c.name, c.expression()?)))
+            .chain(macros.iter().map(|m| (&m.name, m.expr.as_str())));
+        for (name, text) in expressions {
+            if let Some(max) = self.max_expression_length {
+                if text.len() > max {
+                    return Err(Error::LimitExceeded(format!(
+                        "Expression of {:?} is {} bytes long, but at most {} are allowed",
+                        name,
+                        text.len(),
+                        max
+                    )));
+                }
+            }
+            if let Some(max) = self.max_expression_depth {
+                let depth = expr::nesting_depth(text);
+                if depth > max {
+                    return Err(Error::LimitExceeded(format!(
+                        "Expression of {:?} is nested {} deep, but at most {} is allowed",
+                        name, depth, max
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Like [`resolve`], with explicit settings
+pub fn resolve_with(
+    constants: &mut [Constant], macros: &[Macro], options: &ResolveOptions,
+) -> Result<Context, Error> {
+    resolve_inner(constants, macros, options)
+        .map(Evaluator::into_context)
+        .map_err(combine_errors)
+}
+
+/// Steps of resolving the constant `name`, one per line: the parsed expression,
+/// the expression with symbols substituted, each function call, and the result.
+/// Constants before it are resolved first. Constants without an expression,
+/// such as formats, aliases and seeded constants, only have a result.
+pub fn explain(
+    constants: &mut [Constant], macros: &[Macro], options: &ResolveOptions, name: &str,
+) -> Result<Vec<String>, Error> {
+    let index = constants
+        .iter()
+        .position(|c| c.name == name)
+        .ok_or_else(|| Error::UnknownConstant(name.to_owned()))?;
+    match constants[index].expression() {
+        Some(text) if !options.seeds.contains_key(name) => {
+            let text = text.to_owned();
+            let evaluator =
+                resolve_inner(&mut constants[..index], macros, options).map_err(combine_errors)?;
+            evaluator
+                .explain(&text)
+                .map_err(|error| Error::Evaluation(name.to_owned(), error))
+        },
+        _ => {
+            let context = resolve_with(&mut constants[..=index], macros, options)?;
+            Ok(vec![format!("Result: {}", context[name])])
+        },
+    }
+}
+
+/// Single error, or all of them as `Error::Multiple`
+fn combine_errors(mut errors: Vec<Error>) -> Error {
+    if errors.len() == 1 {
+        errors.remove(0)
+    } else {
+        Error::Multiple(errors)
+    }
+}
+
+/// Built-in functions and macros
+fn functions(macros: &[Macro], options: &ResolveOptions) -> Result<Functions, Error> {
+    let mut fns = Functions::default();
+    if options.allow_env {
+        fns.allow_env();
+    }
+    for macro_ in macros {
+        if fns.contains(&macro_.name) {
+            return Err(Error::DuplicateFunction(macro_.name.clone()));
+        }
+        macro_.register(&mut fns);
+    }
+    Ok(fns)
+}
+
+/// Returns at least one error on failure
+fn resolve_inner(
+    constants: &mut [Constant], macros: &[Macro], options: &ResolveOptions,
+) -> Result<Evaluator, Vec<Error>> {
+    options.check_limits(constants, macros).map_err(|error| vec![error])?;
+    let fns = functions(macros, options).map_err(|error| vec![error])?;
+    let mut context = options.inherited.clone();
+    context.extend(options.seeds.clone());
+    let mut evaluator = Evaluator::new(fns, context);
+    let mut defined = HashSet::new();
+    let mut errors = Vec::new();
+    for index in 0..constants.len() {
+        let constant = &mut constants[index];
+        let result = if !defined.insert(constant.name.clone()) {
+            Err(Error::DuplicateConstant(constant.name.clone()))
+        } else if let Some(seed) = options.seeds.get(&constant.name) {
+            // Already available to other constants as a seed
+            constant.set_value(seed.clone());
+            Ok(())
+        } else {
+            evaluator.resolve(constant).map_err(|error| {
+                match &error.message {
+                    EvalErrorMessage::UnknownSymbol(symbol) => {
+                        unknown_symbol_error(constants, index, symbol, error.clone())
+                    },
+                    _ => None,
+                }
+                .unwrap_or_else(|| Error::from((constants[index].clone(), error)))
+            })
+        };
+        if let Err(error) = result {
+            errors.push(error);
+            if !options.keep_going {
+                break;
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(evaluator)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Explain an unknown symbol by following references from the constant at `index`
+/// until reaching a symbol that is not defined, or is defined after its referrer.
+/// Returns None if the symbol is defined earlier, i.e. it failed to resolve itself.
+fn unknown_symbol_error(
+    constants: &[Constant], index: usize, symbol: &str, error: EvalError,
+) -> Option<Error> {
+    let position = |name: &str| constants.iter().position(|c| c.name == name);
+    let mut chain = vec![constants[index].name.clone(), symbol.to_owned()];
+    let mut current = match position(symbol) {
+        Some(i) if i < index => return None,
+        Some(i) => i,
+        None => {
+            return Some(Error::UnknownSymbol {
+                chain,
+                undefined: true,
+                error,
+            });
+        },
+    };
+    loop {
+        let next = constants[current]
+            .references()
+            .into_iter()
+            .find(|name| position(name).map_or(true, |i| i >= current));
+        match next {
+            Some(name) if !chain.contains(&name) => {
+                chain.push(name.clone());
+                match position(&name) {
+                    Some(i) => current = i,
+                    None => {
+                        return Some(Error::UnknownSymbol {
+                            chain,
+                            undefined: true,
+                            error,
+                        });
+                    },
+                }
+            },
+            _ => {
+                return Some(Error::UnknownSymbol {
+                    chain,
+                    undefined: false,
+                    error,
+                });
+            },
+        }
+    }
+}
+
+/// Generate file contents for all enabled languages, keyed by language name.
+/// Constants must be resolved first. The `stem` of the output filenames
+/// is available to intro and outro templates.
+pub fn generate(
+    options: &Options, stem: &str, constants: &[Constant],
+) -> Result<HashMap<String, String>, Error> {
+    generate_timed(options, stem, constants, &mut Timings::new())
+}
+
+/// Like [`generate`], but records the durations of generating
+/// each language and running its formatter
+pub fn generate_timed(
+    options: &Options, stem: &str, constants: &[Constant], timings: &mut Timings,
+) -> Result<HashMap<String, String>, Error> {
+    options
+        .languages()
+        .into_iter()
+        .map(|(lang_name, lang_opts)| {
+            let buffer =
+                generate_language_timed(options, lang_name, lang_opts, stem, constants, timings)?;
+            Ok((lang_name.clone(), buffer))
+        })
+        .collect()
+}
+
+/// Generate a single output file in memory and run its formatter, if any,
+/// recording durations like [`generate_timed`]
+pub fn generate_language_timed(
+    options: &Options, lang_name: &str, lang_opts: &LangOptions, stem: &str,
+    constants: &[Constant], timings: &mut Timings,
+) -> Result<String, Error> {
+    let mut buffer = timings.record(&format!("generate {}", lang_name), || {
+        generate_file(options, lang_name, lang_opts, stem, constants)
+    })?;
+
+    // Run formatter if available
+    if let Some(f) = &lang_opts.formatter {
+        let timeout = options.codegen.formatter_timeout();
+        buffer = timings.record(&format!("formatter {}", lang_name), || {
+            run_formatter(f, &buffer, timeout)
+        })?;
+        buffer = with_final_newline(&buffer, options.codegen.final_newline);
+    }
+    Ok(buffer)
+}
+
+/// Generate the contents of a single output file, without running the formatter
+fn generate_file(
+    opts: &Options, lang_name: &str, lang_opts: &LangOptions, stem: &str, constants: &[Constant],
+) -> Result<String, Error> {
+    let mut buffer = Vec::new();
+    generate_to(opts, lang_name, lang_opts, stem, constants, &mut buffer)?;
+    Ok(String::from_utf8(buffer).expect("Generated from strings"))
+}
+
+/// Write a single output file to `out` as it is generated, so that the whole
+/// file is never held in memory. The formatter is not run, as it needs the
+/// whole file; use [`generate`] for languages that have one.
+pub fn generate_to<W: io::Write + ?Sized>(
+    opts: &Options, lang_name: &str, lang_opts: &LangOptions, stem: &str, constants: &[Constant],
+    out: &mut W,
+) -> Result<(), Error> {
+    let mut out = FinalNewline::new(out);
+    log::info!("Processing target {}", lang_name);
+    for type_ in lang_opts.unknown_types(constants) {
+        log::warn!(
+            "Language {:?} has no options for type {:?}, so it is formatted as untyped",
+            lang_name,
+            type_
+        );
+    }
+    let labels = &opts.codegen.section_labels;
+    let section_comment = |label: &str| {
+        if opts.codegen.comment_sections {
+            lang_opts.format_comment(label)
+        } else {
+            String::new()
+        }
+    };
+    // Templates are checked before writing anything
+    let missing_stamp = |key| Error::MissingStamp {
+        language: lang_name.to_owned(),
+        key,
+    };
+    let intro = lang_opts.format_intro(stem, &opts.codegen.stamp).map_err(missing_stamp)?;
+    let outro = lang_opts.format_outro(stem, &opts.codegen.stamp).map_err(missing_stamp)?;
+    let mut sections = Sections::new(opts.codegen.section_spacing);
+    sections.write(&mut out, &lang_opts.format_file_doc())?;
+
+    // Imports
+    if lang_opts.emit_imports {
+        let mut text = section_comment(&labels.imports);
+        for import in &lang_opts.imports(constants) {
+            let import = lang_opts.format_import(import, stem).ok_or_else(|| {
+                Error::ImportsNotSupported {
+                    language: lang_name.to_owned(),
+                }
+            })?;
+            text.push_str(&import);
+            text.push('\n');
+        }
+        sections.write(&mut out, &text)?;
+    }
+
+    // Intro
+    sections.write(&mut out, &(section_comment(&labels.start_body) + &intro))?;
+
+    // Actual constant values, streamed as there may be many
+    let comment = section_comment(&labels.constants);
+    if !comment.is_empty() || !constants.is_empty() {
+        sections.start(&mut out)?;
+        out.write_all(comment.as_bytes())?;
+    }
+    let constant_error = |constant: &Constant, error| match error {
+        ConstantError::TypeRequired => Error::TypeRequired {
+            language: lang_name.to_owned(),
+            constant: constant.name.to_owned(),
+        },
+        ConstantError::Format(error) => Error::Format {
+            language: lang_name.to_owned(),
+            constant: constant.name.to_owned(),
+            error,
+        },
+        ConstantError::Command(message) => Error::ValueCommand {
+            language: lang_name.to_owned(),
+            constant: constant.name.to_owned(),
+            message,
+        },
+        ConstantError::CommandsNotAllowed(type_) => Error::CommandsNotAllowed {
+            language: lang_name.to_owned(),
+            type_,
+        },
+        ConstantError::InvalidIdentifier(name) => Error::InvalidIdentifier {
+            language: lang_name.to_owned(),
+            constant: constant.name.to_owned(),
+            name,
+        },
+    };
+    let commands = opts.codegen.commands();
+    let with_expression = |line: String, constant: &Constant| {
+        if opts.codegen.show_expression {
+            lang_opts.with_expression(line, constant)
+        } else {
+            line
+        }
+    };
+    let declared: Vec<&Constant> = constants.iter().collect();
+    let mut by_value = declared.clone();
+    if opts.codegen.sort == ConstantSort::Value {
+        by_value.sort_by(|a, b| a.value().sort_order(&b.value()));
+    }
+    let (order, member_order) = match (opts.codegen.sort, opts.codegen.sort_scope) {
+        (ConstantSort::Declaration, _) => (&declared, &declared),
+        (ConstantSort::Value, SortScope::Global) => (&by_value, &by_value),
+        (ConstantSort::Value, SortScope::PerGroup) => (&declared, &by_value),
+    };
+    let mut emitted_enums = HashSet::new();
+    // Whether the last constant line still lacks its separator and line break
+    let mut unterminated = false;
+    for &constant in order {
+        match &constant.enum_ {
+            Some(enum_name) if lang_opts.supports_enums() => {
+                // Whole enum is emitted at the position of its first member
+                if !emitted_enums.insert(enum_name) {
+                    continue;
+                }
+                if unterminated {
+                    out.write_all(b"\n")?;
+                    unterminated = false;
+                }
+                out.write_all(lang_opts.format_enum_intro(enum_name).as_bytes())?;
+                let members = member_order.iter().filter(|c| c.enum_.as_ref() == Some(enum_name));
+                for &member in members {
+                    let line = lang_opts
+                        .format_enum_member(lang_name, enum_name, member, commands)
+                        .map_err(|e| constant_error(member, e))?;
+                    let line = with_expression(line, member);
+                    out.write_all(line.as_bytes())?;
+                    out.write_all(b"\n")?;
+                }
+                out.write_all(lang_opts.format_enum_outro(enum_name).as_bytes())?;
+            },
+            _ => {
+                let line = lang_opts
+                    .format_constant_with(lang_name, constant, commands)
+                    .map_err(|e| constant_error(constant, e))?;
+                let line = with_expression(line, constant);
+                if let (true, Some(separator)) = (unterminated, &lang_opts.constant_separator) {
+                    out.write_all(separator.as_bytes())?;
+                    out.write_all(b"\n")?;
+                }
+                out.write_all(line.as_bytes())?;
+                if lang_opts.constant_separator.is_some() {
+                    unterminated = true;
+                } else {
+                    out.write_all(b"\n")?;
+                }
+            },
+        }
+    }
+    if unterminated {
+        out.write_all(b"\n")?;
+    }
+
+    // Outro
+    sections.write(&mut out, &(section_comment(&labels.end_body) + &outro))?;
+
+    if opts.codegen.checksum {
+        let comment = format!("Checksum: crc32 {}", checksum(constants));
+        out.write_all(lang_opts.format_comment(&comment).as_bytes())?;
+    }
+
+    out.finish(opts.codegen.final_newline)?;
+    Ok(())
+}
+
+/// Writer holding back trailing line breaks, so that exactly one or none
+/// of them can be written by `finish`. Line breaks followed by other
+/// content are written unchanged.
+struct FinalNewline<W> {
+    inner: W,
+    pending: Vec<u8>,
+    empty: bool,
+}
+impl<W: io::Write> FinalNewline<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            pending: Vec::new(),
+            empty: true,
+        }
+    }
+
+    /// The line break is CRLF if the held back ones contain one
+    fn finish(mut self, final_newline: bool) -> io::Result<()> {
+        if final_newline && !self.empty {
+            let crlf = self.pending.windows(2).any(|pair| pair == b"\r\n");
+            self.inner.write_all(if crlf { b"\r\n" } else { b"\n" })?;
+        }
+        self.inner.flush()
+    }
+}
+impl<W: io::Write> io::Write for FinalNewline<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match buf.iter().rposition(|b| !matches!(b, b'\r' | b'\n')) {
+            Some(last) => {
+                self.inner.write_all(&self.pending)?;
+                self.inner.write_all(&buf[..=last])?;
+                self.pending = buf[last + 1..].to_vec();
+                self.empty = false;
+            },
+            None => self.pending.extend_from_slice(buf),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Separates non-empty sections of a generated file with blank lines
+struct Sections {
+    spacing: u8,
+    started: bool,
+}
+impl Sections {
+    fn new(spacing: u8) -> Self {
+        Self {
+            spacing,
+            started: false,
+        }
+    }
+
+    /// Begin a non-empty section, separating it from the previous one
+    fn start<W: io::Write + ?Sized>(&mut self, out: &mut W) -> io::Result<()> {
+        if self.started {
+            out.write_all("\n".repeat(self.spacing as usize).as_bytes())?;
+        }
+        self.started = true;
+        Ok(())
+    }
+
+    /// Write a whole section, nothing if it is empty
+    fn write<W: io::Write + ?Sized>(&mut self, out: &mut W, text: &str) -> io::Result<()> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.start(out)?;
+        out.write_all(text.as_bytes())
+    }
+}
+
+/// Apply the `final_newline` option to already generated text
+fn with_final_newline(text: &str, final_newline: bool) -> String {
+    let mut buffer = Vec::new();
+    let mut out = FinalNewline::new(&mut buffer);
+    out.write_all(text.as_bytes()).expect("Writing to memory");
+    out.finish(final_newline).expect("Writing to memory");
+    String::from_utf8(buffer).expect("Line breaks are removed only at the end")
+}
+
+/// Run `cmd` with `source` as its stdin, returning its stdout.
+/// The formatter is killed if it does not exit within `timeout`.
+fn run_formatter(
+    cmd: &[String], source: &str, timeout: Option<Duration>,
+) -> Result<String, Error> {
+    log::info!("Running formatter {:?}", cmd);
+    run_command(cmd, source, timeout).map_err(|error| match error {
+        CommandError::Io(error) => Error::Io(error),
+        CommandError::Failed(message) => Error::Formatter(message),
+    })
+}
+
+/// Failure of [`run_command`]
+enum CommandError {
+    /// Starting or communicating with the process failed
+    Io(io::Error),
+    /// The process did not produce valid output
+    Failed(String),
+}
+impl From<io::Error> for CommandError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::Io(error) => write!(f, "{}", error),
+            Self::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Run `cmd` with `input` as its stdin, returning its stdout.
+/// The command is killed if it does not exit within `timeout`.
+fn run_command(
+    cmd: &[String], input: &str, timeout: Option<Duration>,
+) -> Result<String, CommandError> {
+    use std::process::{Command, Stdio};
+
+    if cmd.is_empty() {
+        return Err(CommandError::Failed("Command empty".to_owned()));
+    }
+
+    let mut p = Command::new(cmd[0].clone())
+        .args(&cmd[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    // Written and read on other threads, so that a command that
+    // never consumes its input or never exits can still be killed
+    let mut stdin = p
+        .stdin
+        .take()
+        .ok_or_else(|| CommandError::Failed("Could not open stdin".to_owned()))?;
+    let input = input.to_owned();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let mut stdout = p.stdout.take().expect("Stdout is piped");
+    let reader = thread::spawn(move || -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        io::Read::read_to_end(&mut stdout, &mut buffer)?;
+        Ok(buffer)
+    });
+
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    let status = match (timeout, deadline) {
+        (Some(timeout), Some(deadline)) => loop {
+            if let Some(status) = p.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                p.kill()?;
+                p.wait()?;
+                return Err(CommandError::Failed(format!(
+                    "Did not finish within {:?} and was killed",
+                    timeout
+                )));
+            }
+            thread::sleep(Duration::from_millis(10));
+        },
+        _ => p.wait()?,
+    };
+    let write_result = writer.join().expect("Writer thread panicked");
+    let stdout = reader.join().expect("Reader thread panicked")?;
+
+    if !status.success() {
+        return Err(CommandError::Failed(format!(
+            "Returned with non-zero exit code {:?}",
+            status.code()
+        )));
+    }
+
+    // The command may exit before consuming all input, which is only
+    // reported if it claims success anyway
+    if let Err(error) = write_result {
+        return Err(CommandError::Failed(format!("Could not write input: {}", error)));
+    }
+
+    String::from_utf8(stdout).map_err(|error| {
+        let bytes = error.as_bytes();
+        let valid_up_to = error.utf8_error().valid_up_to();
+        let start = valid_up_to.saturating_sub(16);
+        let end = (valid_up_to + 16).min(bytes.len());
+        CommandError::Failed(format!(
+            "Produced invalid UTF-8 at byte {}: {:?}",
+            valid_up_to,
+            String::from_utf8_lossy(&bytes[start..end])
+        ))
+    })
+}
+
+#[cfg(test)]
+mod test_generate {
+    use super::{
+        generate_file, resolve, with_final_newline, Constant, Constants, Error, FormatError,
+        Options,
+    };
+
+    const CONSTANTS: &str = r#"
+        [[constant]]
+        name = "A"
+        value = "1"
+    "#;
+
+    /// Generates the output of a single language from TOML source
+    fn generate_language(options: &str, language: &str, constants: &str) -> Result<String, Error> {
+        let options: Options = toml::from_str(options).unwrap();
+        let (lang_name, lang_opts) = options
+            .languages()
+            .into_iter()
+            .find(|(name, _)| *name == language)
+            .expect("Language not enabled");
+        let mut constants: Vec<Constant> =
+            toml::from_str::<Constants>(constants).unwrap().constants;
+        resolve(&mut constants, &[]).unwrap();
+        generate_file(&options, lang_name, lang_opts, "constants", &constants)
+    }
+
+    #[test]
+    fn test_final_newline() {
+        let options = r#"
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+            outro = "\n"
+        "#;
+        let output = generate_language(options, "python", CONSTANTS).unwrap();
+        assert_eq!(output, "A = 1\n");
+
+        let options = format!("[codegen]\nfinal_newline = false\n{}", options);
+        let output = generate_language(&options, "python", CONSTANTS).unwrap();
+        assert_eq!(output, "A = 1");
+        assert_eq!(generate_language(&options, "python", "").unwrap(), "");
+    }
+
+    #[test]
+    fn test_final_newline_crlf() {
+        assert_eq!(with_final_newline("a\r\nb\r\n\r\n", true), "a\r\nb\r\n");
+        assert_eq!(with_final_newline("a\r\nb\r\n", false), "a\r\nb");
+        assert_eq!(with_final_newline("a\n\nb", true), "a\n\nb\n");
+        assert_eq!(with_final_newline("\n\n", true), "");
+    }
+
+    #[test]
+    fn test_checksum() {
+        let options = r##"
+            [codegen]
+            checksum = true
+
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+            comment = "# $comment"
+        "##;
+        let first = generate_language(options, "python", CONSTANTS).unwrap();
+        assert!(first.starts_with("A = 1\n# Checksum: crc32 "), "{}", first);
+        assert_eq!(first, generate_language(options, "python", CONSTANTS).unwrap());
+
+        let changed = generate_language(options, "python", &CONSTANTS.replace('1', "2")).unwrap();
+        assert_ne!(first.lines().last(), changed.lines().last());
+    }
+
+    #[test]
+    fn test_emit_imports_disabled() {
+        let options = r##"
+            [codegen]
+            comment_sections = true
+
+            [lang.rust]
+            file_ext = ".rs"
+            template = "pub const $name: $type = $value;"
+            import = "use $import;"
+            comment = "// $comment"
+
+            [lang.rust.type.PhysAddr]
+            import = ["x86_64::PhysAddr"]
+
+            [lang.inline]
+            file_ext = ".txt"
+            template = "$name: $type = $value"
+            comment = "# $comment"
+            emit_imports = false
+
+            [lang.inline.type.PhysAddr]
+            import = ["x86_64::PhysAddr"]
+        "##;
+        let constants = r#"
+            [[constant]]
+            name = "A"
+            type = "PhysAddr"
+            value = "1"
+        "#;
+
+        let output = generate_language(options, "rust", constants).unwrap();
+        assert!(output.contains("// Imports\nuse x86_64::PhysAddr;\n"), "{}", output);
+
+        let output = generate_language(options, "inline", constants).unwrap();
+        assert!(!output.contains("Imports"), "{}", output);
+        assert!(!output.contains("x86_64"), "{}", output);
+        assert!(output.contains("A: PhysAddr = 1\n"), "{}", output);
+    }
+
+    #[test]
+    fn test_type_boolean_format() {
+        let options = r##"
+            [lang.c]
+            file_ext = ".h"
+            template = "const $type $name = $value;"
+            import = "#include $import"
+            format.boolean = { true = "1", false = "0" }
+
+            [lang.c.type.bool]
+            import = ["<stdbool.h>"]
+            format.boolean = { true = "true", false = "false" }
+
+            [lang.c.type.flag]
+            name = "int"
+        "##;
+        let constants = r#"
+            [[constant]]
+            name = "ENABLED"
+            type = "bool"
+            value = "true"
+
+            [[constant]]
+            name = "LEGACY"
+            type = "flag"
+            value = "false"
+        "#;
+        assert_eq!(
+            generate_language(options, "c", constants).unwrap(),
+            concat!(
+                "#include <stdbool.h>\n",
+                "const bool ENABLED = true;\n",
+                "const int LEGACY = 0;\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_boolean_as_int() {
+        let options = r##"
+            [lang.c]
+            file_ext = ".h"
+            template = "#define $name $value"
+            format.boolean = { as_int = true }
+            type.flag.format.boolean = { true = "1u", false = "0u" }
+        "##;
+        let constants = r#"
+            [[constant]]
+            name = "ENABLED"
+            value = "true"
+
+            [[constant]]
+            name = "LEGACY"
+            value = "false"
+
+            [[constant]]
+            name = "FLAG"
+            type = "flag"
+            value = "true"
+        "#;
+        assert_eq!(
+            generate_language(options, "c", constants).unwrap(),
+            "#define ENABLED 1\n#define LEGACY 0\n#define FLAG 1u\n"
+        );
+
+        for boolean in &[
+            "{ as_int = true, true = \"1\" }",
+            "{ true = \"1\" }",
+            "{ as_int = false }",
+        ] {
+            let options = format!(
+                "[lang.c]\nfile_ext = \".h\"\ntemplate = \"\"\nformat.boolean = {}",
+                boolean
+            );
+            assert!(toml::from_str::<Options>(&options).is_err(), "{}", boolean);
+        }
+    }
+
+    #[test]
+    fn test_json_preset() {
+        let options = Options::with_language_presets(&["json".to_owned()], None).unwrap();
+        let (_, lang_opts) = options.languages()[0];
+        let generate = |constants: &str| {
+            let mut constants = toml::from_str::<Constants>(constants).unwrap().constants;
+            resolve(&mut constants, &[]).unwrap();
+            generate_file(&options, "json", lang_opts, "constants", &constants).unwrap()
+        };
+        let constants = r#"
+            [[constant]]
+            name = "A"
+            value = "1"
+
+            [[constant]]
+            name = "B"
+            value = '"b"'
+
+            [[constant]]
+            name = "C"
+            value = "(list 1 2)"
+        "#;
+        assert_eq!(generate(constants), "{\n  \"A\": 1,\n  \"B\": \"b\",\n  \"C\": [1, 2]\n}\n");
+        assert_eq!(generate(""), "{\n}\n");
+    }
+
+    #[test]
+    fn test_literal_format() {
+        let options = r#"
+            [lang.rust]
+            file_ext = ".rs"
+            template = "pub const $name: $type = $value;"
+
+            [lang.rust.type.u8]
+            format.integer = { radix = "hex" }
+        "#;
+        let constants = r#"
+            [[constant]]
+            name = "A"
+            type = "u32"
+            value = "10"
+
+            [[constant]]
+            name = "FLAGS"
+            type = "u32"
+            value = "10"
+            literal_format.integer = { radix = "bin", zero_pad = 8, underscores = 4 }
+
+            [[constant]]
+            name = "C"
+            type = "u8"
+            value = "10"
+
+            [[constant]]
+            name = "D"
+            type = "u8"
+            value = "10"
+            literal_format.integer = { radix = "dec" }
+        "#;
+        assert_eq!(
+            generate_language(options, "rust", constants).unwrap(),
+            concat!(
+                "pub const A: u32 = 10;\n",
+                "pub const FLAGS: u32 = 0b0000_1010;\n",
+                "pub const C: u8 = 0xa;\n",
+                "pub const D: u8 = 10;\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_non_finite_float() {
+        let options = r##"
+            [lang.c]
+            file_ext = ".h"
+            template = "const $type $name = $value;"
+            import = "#include $import"
+
+            [lang.c.type.double]
+            import = ["<math.h>"]
+            format.float = { infinity = "INFINITY" }
+
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+        "##;
+        let constants = r#"
+            [[constant]]
+            name = "LIMIT"
+            type = "double"
+            value = "(add 1.0e308 1.0e308)"
+        "#;
+        assert_eq!(
+            generate_language(options, "c", constants).unwrap(),
+            "#include <math.h>\nconst double LIMIT = INFINITY;\n"
+        );
+        match generate_language(options, "python", constants).unwrap_err() {
+            Error::Format { constant, error, .. } => {
+                assert_eq!(constant, "LIMIT");
+                assert_eq!(error, FormatError::NonFinite(f64::INFINITY));
+            },
+            other => panic!("Unexpected error {:?}", other),
+        }
+
+        let constants = r#"
+            [[constant]]
+            name = "INF"
+            type = "double"
+            value = "(add 1.0e308 1.0e308)"
+
+            [[constant]]
+            name = "UNDEFINED"
+            type = "double"
+            value = "(add INF (mul -1 INF))"
+        "#;
+        assert!(matches!(
+            generate_language(options, "c", constants).unwrap_err(),
+            Error::Format { constant, error: FormatError::NonFinite(v), .. }
+                if constant == "UNDEFINED" && v.is_nan()
+        ));
+    }
+
+    #[test]
+    fn test_relative_imports() {
+        let options = r#"
+            [lang.typescript]
+            file_ext = ".ts"
+            template = "export const $name: $type = $value;"
+            import = "import { $import } from './$stem.types$ext';"
+
+            [lang.typescript.type.Size]
+            import = ["Size"]
+
+            [lang.python]
+            file_ext = ".py"
+            template = "$name: $type = $value"
+            import = "from $import import *"
+            import_separator = "."
+
+            [lang.python.type.Size]
+            import = ["./units/size"]
+        "#;
+        let constants = r#"
+            [[constant]]
+            name = "A"
+            type = "Size"
+            value = "1"
+        "#;
+        assert_eq!(
+            generate_language(options, "typescript", constants).unwrap(),
+            "import { Size } from './constants.types.ts';\nexport const A: Size = 1;\n"
+        );
+        assert_eq!(
+            generate_language(options, "python", constants).unwrap(),
+            "from .units.size import *\nA: Size = 1\n"
+        );
+
+        // Imports relative to the target directory, from an output file in a subdirectory
+        let options: Options = toml::from_str(
+            r#"
+            [lang.typescript]
+            file_ext = ".ts"
+            template = "export const $name: $type = $value;"
+            import = "import { Size } from '$import.js'; // $module_dir"
+            relative_imports = true
+            type.Size.import = ["types/units", "./local"]
+
+            [lang.python]
+            file_ext = ".py"
+            template = "$name: $type = $value"
+            import = "from $import import *"
+            import_separator = "."
+            relative_imports = true
+            type.Size.import = ["api.units"]
+            "#,
+        )
+        .unwrap();
+        let mut constants = toml::from_str::<Constants>(constants).unwrap().constants;
+        resolve(&mut constants, &[]).unwrap();
+        let languages = options.languages();
+        let generate = |index: usize| {
+            let (lang_name, lang_opts) = languages[index];
+            generate_file(&options, lang_name, lang_opts, "api/v1/constants", &constants).unwrap()
+        };
+        assert_eq!(generate(0), "from ..units import *\nA: Size = 1\n");
+        assert_eq!(
+            generate(1),
+            concat!(
+                "import { Size } from './local.js'; // api/v1\n",
+                "import { Size } from '../../types/units.js'; // api/v1\n",
+                "export const A: Size = 1;\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_import_order() {
+        let options = r#"
+            [lang.rust]
+            file_ext = ".rs"
+            template = "pub const $name: $type = $value;"
+            import = "use $import;"
+            import_sort = "as_is"
+
+            [lang.rust.type.VirtAddr]
+            import = ["x86_64::VirtAddr", "core::fmt"]
+
+            [lang.rust.type.PhysAddr]
+            import = ["x86_64::PhysAddr", "core::fmt", "alloc::vec"]
+        "#;
+        let constants = r#"
+            [[constant]]
+            name = "A"
+            type = "VirtAddr"
+            value = "1"
+
+            [[constant]]
+            name = "B"
+            type = "PhysAddr"
+            value = "2"
+        "#;
+        let output = generate_language(options, "rust", constants).unwrap();
+        assert!(
+            output.starts_with(concat!(
+                "use x86_64::VirtAddr;\n",
+                "use core::fmt;\n",
+                "use x86_64::PhysAddr;\n",
+                "use alloc::vec;\n",
+            )),
+            "{}",
+            output
+        );
+
+        let options = options.replace("\"as_is\"", "\"sorted\"");
+        let output = generate_language(&options, "rust", constants).unwrap();
+        assert!(
+            output.starts_with(concat!(
+                "use alloc::vec;\n",
+                "use core::fmt;\n",
+                "use x86_64::PhysAddr;\n",
+                "use x86_64::VirtAddr;\n",
+            )),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn test_count_parameter() {
+        let options = r#"
+            [lang.c]
+            file_ext = ".h"
+            template = "const int $name[$count] = $value;"
+            format.list = { open = "{", separator = ", ", close = "}" }
+        "#;
+        let constants = r#"
+            [[constant]]
+            name = "A"
+            value = "(list 1 2 3)"
+
+            [[constant]]
+            name = "B"
+            value = "4"
+        "#;
+        assert_eq!(
+            generate_language(options, "c", constants).unwrap(),
+            "const int A[3] = {1, 2, 3};\nconst int B[1] = 4;\n"
+        );
+    }
+
+    #[test]
+    fn test_section_labels() {
+        let options = r##"
+            [codegen]
+            comment_sections = true
+            section_labels = { start_body = "Alku", end_body = "Loppu" }
+
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+            comment = "# $comment"
+        "##;
+        assert_eq!(
+            generate_language(options, "python", CONSTANTS).unwrap(),
+            "# Imports\n# Alku\n# Constants\nA = 1\n# Loppu\n"
+        );
+    }
+
+    #[test]
+    fn test_file_doc() {
+        let options = r##"
+            [lang.python]
+            file_ext = ".py"
+            template = "$name: $type = $value"
+            import = "import $import"
+            comment = "# $comment"
+            file_doc = """
+Hardware constants
+Regenerate with `constcodegen`"""
+
+            [lang.python.type.Addr]
+            import = ["addr"]
+
+            [lang.plain]
+            file_ext = ".txt"
+            template = "$name = $value"
+            file_doc = "Not emitted without comments"
+        "##;
+        let constants = r#"
+            [[constant]]
+            name = "A"
+            type = "Addr"
+            value = "1"
+        "#;
+        assert_eq!(
+            generate_language(options, "python", constants).unwrap(),
+            "# Hardware constants\n# Regenerate with `constcodegen`\nimport addr\nA: Addr = 1\n"
+        );
+        assert_eq!(generate_language(options, "plain", CONSTANTS).unwrap(), "A = 1\n");
+    }
+
+    #[test]
+    fn test_value_overrides() {
+        let options = r##"
+            [lang.c]
+            file_ext = ".h"
+            template = "#define $name (($type)$value)"
+
+            [lang.c.type.ptr]
+            name = "void*"
+            value_prefix = "0x"
+
+            [lang.ts]
+            file_ext = ".ts"
+            template = "export const $name = $value;"
+        "##;
+        let constants = r#"
+            [[constant]]
+            name = "NULL_PTR"
+            type = "ptr"
+            value = "0"
+            overrides = { ts = "null" }
+        "#;
+        assert_eq!(
+            generate_language(options, "c", constants).unwrap(),
+            "#define NULL_PTR ((void*)0x0)\n"
+        );
+        assert_eq!(
+            generate_language(options, "ts", constants).unwrap(),
+            "export const NULL_PTR = null;\n"
+        );
+    }
+
+    #[test]
+    fn test_notes() {
+        let options = r##"
+            [lang.rust]
+            file_ext = ".rs"
+            template = "const $name: u64 = $value;"
+            comment = "// $comment"
+            inline_comment = "// $comment"
+
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+            comment = "# $comment"
+
+            [lang.json]
+            file_ext = ".json"
+            template = "\"$name\": $value,"
+        "##;
+        let constants = r#"
+            [[constant]]
+            name = "X"
+            value = "1"
+            note = "bytes"
+
+            [[constant]]
+            name = "Y"
+            value = "2"
+        "#;
+        assert_eq!(
+            generate_language(options, "rust", constants).unwrap(),
+            "const X: u64 = 1; // bytes\nconst Y: u64 = 2;\n"
+        );
+        assert_eq!(
+            generate_language(options, "python", constants).unwrap(),
+            "# bytes\nX = 1\nY = 2\n"
+        );
+        assert_eq!(
+            generate_language(options, "json", constants).unwrap(),
+            "\"X\": 1,\n\"Y\": 2,\n"
+        );
+    }
+
+    #[test]
+    fn test_show_expression() {
+        let options = r##"
+            [codegen]
+            show_expression = true
+
+            [lang.rust]
+            file_ext = ".rs"
+            template = "const $name: u64 = $value;"
+            comment = "// $comment"
+            inline_comment = "// $comment"
+
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+            comment = "# $comment"
+
+            [lang.json]
+            file_ext = ".json"
+            template = "\"$name\": $value,"
+        "##;
+        let constants = r#"
+            [[constant]]
+            name = "X"
+            value = "(mul 1 4096)"
+
+            [[constant]]
+            name = "Y"
+            format = "${X}"
+        "#;
+        assert_eq!(
+            generate_language(options, "rust", constants).unwrap(),
+            "const X: u64 = 4096; // (mul 1 4096)\nconst Y: u64 = \"4096\";\n"
+        );
+        assert_eq!(
+            generate_language(options, "python", constants).unwrap(),
+            "# (mul 1 4096)\nX = 4096\nY = \"4096\"\n"
+        );
+        assert_eq!(
+            generate_language(options, "json", constants).unwrap(),
+            "\"X\": 4096,\n\"Y\": \"4096\",\n"
+        );
+    }
+
+    #[test]
+    fn test_enums() {
+        let options = r#"
+            [lang.rust]
+            file_ext = ".rs"
+            template = "pub const $name: u8 = $value;"
+            enum_intro = "pub enum $enum {"
+            enum_member = "    $name = $value,"
+            enum_outro = "}"
+
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+        "#;
+        let constants = r#"
+            [[constant]]
+            name = "Red"
+            enum = "Color"
+            value = "1"
+
+            [[constant]]
+            name = "LIMIT"
+            value = "10"
+
+            [[constant]]
+            name = "Green"
+            enum = "Color"
+            value = "2"
+
+            [[constant]]
+            name = "Small"
+            enum = "Size"
+            value = "0"
+
+            [[constant]]
+            name = "Blue"
+            enum = "Color"
+            value = "3"
+        "#;
+        assert_eq!(
+            generate_language(options, "rust", constants).unwrap(),
+            concat!(
+                "pub enum Color {\n",
+                "    Red = 1,\n",
+                "    Green = 2,\n",
+                "    Blue = 3,\n",
+                "}\n",
+                "pub const LIMIT: u8 = 10;\n",
+                "pub enum Size {\n",
+                "    Small = 0,\n",
+                "}\n",
+            )
+        );
+        assert_eq!(
+            generate_language(options, "python", constants).unwrap(),
+            "Red = 1\nLIMIT = 10\nGreen = 2\nSmall = 0\nBlue = 3\n"
+        );
+    }
+
+    #[test]
+    fn test_section_spacing() {
+        let options = |spacing: u8, outro: &str| {
+            format!(
+                r#"
+                [codegen]
+                section_spacing = {}
+
+                [lang.python]
+                file_ext = ".py"
+                template = "$name: $type = $value"
+                import = "from $import import *"
+                intro = "class Constants:"
+                {}
+
+                [lang.python.type.Size]
+                import = ["units"]
+                "#,
+                spacing, outro
+            )
+        };
+        let constants = r#"
+            [[constant]]
+            name = "A"
+            type = "Size"
+            value = "1"
+        "#;
+        assert_eq!(
+            generate_language(&options(2, "outro = \"# end\""), "python", constants).unwrap(),
+            "from units import *\n\n\nclass Constants:\n\n\nA: Size = 1\n\n\n# end\n"
+        );
+        // Empty sections are not separated
+        assert_eq!(
+            generate_language(&options(1, ""), "python", "").unwrap(),
+            "class Constants:\n"
+        );
+        assert_eq!(
+            generate_language(&options(0, ""), "python", constants).unwrap(),
+            "from units import *\nclass Constants:\nA: Size = 1\n"
+        );
+    }
+
+    #[test]
+    fn test_sort_scope() {
+        let options = |scope: &str| {
+            format!(
+                r#"
+                [codegen]
+                sort = "value"
+                sort_scope = "{}"
+
+                [lang.rust]
+                file_ext = ".rs"
+                template = "pub const $name: u8 = $value;"
+                enum_intro = "pub enum $enum {{"
+                enum_member = "    $name = $value,"
+                enum_outro = "}}"
+                "#,
+                scope
+            )
+        };
+        let constants = r#"
+            [[constant]]
+            name = "Large"
+            enum = "Size"
+            value = "9"
+
+            [[constant]]
+            name = "LIMIT"
+            value = "5"
+
+            [[constant]]
+            name = "Blue"
+            enum = "Color"
+            value = "3"
+
+            [[constant]]
+            name = "Small"
+            enum = "Size"
+            value = "1"
+
+            [[constant]]
+            name = "Red"
+            enum = "Color"
+            value = "2"
+
+            [[constant]]
+            name = "NAME"
+            value = '"x"'
+        "#;
+        assert_eq!(
+            generate_language(&options("per_group"), "rust", constants).unwrap(),
+            concat!(
+                "pub enum Size {\n",
+                "    Small = 1,\n",
+                "    Large = 9,\n",
+                "}\n",
+                "pub const LIMIT: u8 = 5;\n",
+                "pub enum Color {\n",
+                "    Red = 2,\n",
+                "    Blue = 3,\n",
+                "}\n",
+                "pub const NAME: u8 = \"x\";\n",
+            )
+        );
+        assert_eq!(
+            generate_language(&options("global"), "rust", constants).unwrap(),
+            concat!(
+                "pub enum Size {\n",
+                "    Small = 1,\n",
+                "    Large = 9,\n",
+                "}\n",
+                "pub enum Color {\n",
+                "    Red = 2,\n",
+                "    Blue = 3,\n",
+                "}\n",
+                "pub const LIMIT: u8 = 5;\n",
+                "pub const NAME: u8 = \"x\";\n",
+            )
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_formatter {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        generate, generate_timed, resolve, run_formatter, ConstantError, Constants, Error, Options,
+        Timings,
+    };
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["sh".to_owned(), "-c".to_owned(), script.to_owned()]
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_formatter_passthrough() {
+        assert_eq!(run_formatter(&sh("cat"), "x = 1\n", None).unwrap(), "x = 1\n");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_formatter_invalid_utf8() {
+        match run_formatter(&sh("printf 'ok\\377\\376'"), "", None) {
+            Err(Error::Formatter(message)) => {
+                assert!(message.contains("invalid UTF-8 at byte 2"), "{}", message);
+                assert!(message.contains("ok"), "{}", message);
+            },
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_timings() {
+        let options: Options = toml::from_str(
+            r#"
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+            formatter = ["sh", "-c", "cat"]
+
+            [lang.nasm]
+            file_ext = ".asm"
+            template = "%define $name $value"
+            "#,
+        )
+        .unwrap();
+        let mut timings = Timings::new();
+        generate_timed(&options, "constants", &[], &mut timings).unwrap();
+
+        let phases: Vec<&str> = timings.phases().map(|(phase, _)| phase).collect();
+        assert_eq!(phases, vec![
+            "generate nasm",
+            "generate python",
+            "formatter python"
+        ]);
+        let report = timings.to_string();
+        assert_eq!(report.lines().count(), 3);
+        assert!(report.lines().all(|line| line.ends_with(" ms")), "{}", report);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_formatter_timeout() {
+        let start = Instant::now();
+        let timeout = Some(Duration::from_millis(200));
+        match run_formatter(&sh("exec sleep 10"), "x = 1\n", timeout) {
+            Err(Error::Formatter(message)) => assert!(message.contains("killed"), "{}", message),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        assert_eq!(run_formatter(&sh("cat"), "x = 1\n", timeout).unwrap(), "x = 1\n");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_value_command() {
+        let mut options: Options = toml::from_str(
+            r#"
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+            type.secret = { value_command = ["tr", "a-z", "A-Z"] }
+            type.broken = { value_command = ["sh", "-c", "exit 2"] }
+            type.slow = { value_command = ["sh", "-c", "exec sleep 10"] }
+            "#,
+        )
+        .unwrap();
+        let mut constants = toml::from_str::<Constants>(
+            r#"
+            [[constant]]
+            name = "KEY"
+            type = "secret"
+            value = '"abc"'
+
+            [[constant]]
+            name = "PLAIN"
+            value = '"abc"'
+            "#,
+        )
+        .unwrap()
+        .constants;
+        resolve(&mut constants, &[]).unwrap();
+
+        match generate(&options, "constants", &constants).unwrap_err() {
+            Error::CommandsNotAllowed { type_, .. } => assert_eq!(type_, "secret"),
+            other => panic!("Unexpected error {:?}", other),
+        }
+        let (_, python) = options.languages()[0];
+        assert_eq!(
+            python.format_constant("python", &constants[0]),
+            Err(ConstantError::CommandsNotAllowed("secret".to_owned()))
+        );
+
+        options.codegen.allow_commands = true;
+        let outputs = generate(&options, "constants", &constants).unwrap();
+        assert_eq!(outputs["python"], "KEY = \"ABC\"\nPLAIN = \"abc\"\n");
+
+        constants[0].type_ = Some("broken".to_owned());
+        let error = generate(&options, "constants", &constants).unwrap_err();
+        assert!(matches!(&error, Error::ValueCommand { constant, .. } if constant == "KEY"));
+        assert!(error.to_string().contains("Some(2)"), "{}", error);
+        assert_eq!(error.exit_code(), 6);
+
+        constants[0].type_ = Some("slow".to_owned());
+        options.codegen.formatter_timeout = Some(0.2);
+        let start = Instant::now();
+        let error = generate(&options, "constants", &constants).unwrap_err();
+        assert!(error.to_string().contains("killed"), "{}", error);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_formatter_exits_early() {
+        let source = "x = 1\n".repeat(100_000);
+        match run_formatter(&sh("exit 3"), &source, None) {
+            Err(Error::Formatter(message)) => assert!(message.contains("Some(3)"), "{}", message),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
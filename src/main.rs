@@ -1,27 +1,71 @@
 #![deny(unused_must_use)]
-#![deny(mutable_borrow_reservation_conflict)]
-#![allow(clippy::cast_lossless)]
 
-use std::ffi::OsString;
-use std::fmt;
+use std::env;
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io::{self};
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use log::LevelFilter;
 use structopt::{self, StructOpt};
 
-mod constants;
-mod expr;
-mod format_value;
-mod functions;
-mod options;
-mod template;
-mod value;
+use constcodegen::{
+    explain, generate_language_timed, generate_timed, generate_to, resolve_with, Constants,
+    Context, Error, LangOptions, Options, Primitive, ResolveOptions, Timings,
+};
 
-use self::constants::{Constant, Constants};
-use self::expr::EvalError;
-use self::options::Options;
-use self::value::Context;
+mod profile;
+
+use self::profile::Profile;
+
+/// How errors are printed to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ErrorFormat {
+    #[default]
+    Human,
+    /// A single-line JSON object, see `Error::to_json`
+    Json,
+}
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown error format {:?}, expected human or json", s)),
+        }
+    }
+}
+
+/// Syntax of constants files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConstantsFormat {
+    Toml,
+    Json,
+}
+impl ConstantsFormat {
+    /// JSON for `.json` files, TOML otherwise
+    fn detect(path: &Path) -> Self {
+        if path.extension() == Some(OsStr::new("json")) {
+            Self::Json
+        } else {
+            Self::Toml
+        }
+    }
+}
+impl FromStr for ConstantsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(Self::Toml),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown constants format {:?}, expected toml or json", s)),
+        }
+    }
+}
 
 #[derive(Debug, StructOpt, Default)]
 #[structopt(author, about)]
@@ -29,7 +73,7 @@ use self::value::Context;
 pub struct Config {
     /// Target directory for generated files
     #[structopt(short, long, parse(from_os_str))]
-    pub target_dir: PathBuf,
+    pub target_dir: Option<PathBuf>,
 
     /// Do not actually write files
     #[structopt(short, long)]
@@ -39,195 +83,1505 @@ pub struct Config {
     #[structopt(short, long)]
     pub print_files: bool,
 
-    /// Target filename stem
-    #[structopt(short, long, parse(from_os_str), default_value = "constants")]
-    pub stem: OsString,
+    /// Log nothing, unless RUST_LOG is set. Errors ending the run are still printed.
+    #[structopt(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Log debug messages, unless RUST_LOG is set
+    #[structopt(short, long)]
+    pub verbose: bool,
+
+    /// Do not write files, but fail if they are not up to date
+    #[structopt(long)]
+    pub check: bool,
+
+    /// Print unified diffs of out-of-date files to stderr, implies --check
+    #[structopt(long)]
+    pub diff: bool,
+
+    /// Print the effective options, including defaults, and exit
+    #[structopt(long)]
+    pub print_config: bool,
+
+    /// Overwrite target files that are read-only or symbolic links
+    #[structopt(short, long)]
+    pub force: bool,
+
+    /// Print durations of processing phases to stderr
+    #[structopt(long)]
+    pub timing: bool,
+
+    /// Report all constants that fail to evaluate instead of stopping at the first one
+    #[structopt(long)]
+    pub keep_going: bool,
+
+    /// Allow constants to read environment variables with (env "NAME" [default])
+    #[structopt(long)]
+    pub allow_env: bool,
+
+    /// Allow types to pipe values through their `value_command`
+    #[structopt(long)]
+    pub allow_commands: bool,
+
+    /// Literal value available to all constants, replacing any constant of the same name.
+    /// Also available to language conditions as a build flag.
+    #[structopt(long, value_name = "NAME=VALUE", number_of_values = 1)]
+    #[structopt(parse(try_from_str = parse_seed))]
+    pub seed_constant: Vec<(String, Primitive)>,
+
+    /// Only emit constants whose name matches one of these patterns,
+    /// where `*` matches any characters and `?` matches one.
+    /// Other constants can still be referenced.
+    #[structopt(long, value_name = "GLOB", number_of_values = 1)]
+    pub include: Vec<String>,
+
+    /// Do not emit constants whose name matches one of these patterns
+    #[structopt(long, value_name = "GLOB", number_of_values = 1)]
+    pub exclude: Vec<String>,
+
+    /// Metadata for intro and outro templates as `$stamp_KEY`, such as a commit hash.
+    /// Unlike a timestamp, passing the same stamp keeps --check stable.
+    #[structopt(long, value_name = "KEY=VALUE", number_of_values = 1)]
+    #[structopt(parse(try_from_str = parse_stamp))]
+    pub stamp: Vec<(String, String)>,
+
+    /// Fail if more constants are given, for untrusted input
+    #[structopt(long, value_name = "N")]
+    pub max_constants: Option<usize>,
+
+    /// Fail if an expression or macro body is longer, in bytes
+    #[structopt(long, value_name = "BYTES")]
+    pub max_expression_length: Option<usize>,
+
+    /// Fail if calls in an expression or macro body are nested deeper
+    #[structopt(long, value_name = "N")]
+    pub max_expression_depth: Option<usize>,
+
+    /// Kill formatters and value commands that take longer, overriding `codegen.formatter_timeout`
+    #[structopt(long, value_name = "SECONDS")]
+    pub formatter_timeout: Option<f64>,
+
+    /// Print the parsed expression of a constant and each step of evaluating it, and exit
+    #[structopt(long, value_name = "CONSTANT")]
+    pub explain: Option<String>,
+
+    /// Write a Makefile rule listing the generated files and the input files they depend on,
+    /// profile included, all as absolute paths
+    #[structopt(long, value_name = "PATH", parse(from_os_str))]
+    pub emit_dependencies: Option<PathBuf>,
+
+    /// Print errors as human-readable text or as JSON: human, json
+    #[structopt(long, default_value = "human")]
+    pub error_format: ErrorFormat,
+
+    /// Target filename stem [default: constants]
+    #[structopt(short, long, parse(from_os_str))]
+    pub stem: Option<OsString>,
 
     /// File specifying generation options
     #[structopt(long = "--options", parse(from_os_str))]
-    pub options_file: PathBuf,
+    pub options_file: Option<PathBuf>,
 
-    /// File specifying constants
+    /// Built-in language options to use, overridden by the options file:
+    /// rust, c, cpp, python, typescript, go, json
+    #[structopt(long, number_of_values = 1, use_delimiter = true)]
+    pub language_preset: Vec<String>,
+
+    /// Syntax of constants files: toml, json.
+    /// Detected from the file extension by default, and TOML for stdin.
+    #[structopt(long)]
+    pub constants_format: Option<ConstantsFormat>,
+
+    /// File specifying constants, or `-` for stdin
     #[structopt(parse(from_os_str))]
     pub constants_file: Vec<PathBuf>,
+
+    /// Profile that missing arguments were read from
+    #[structopt(skip)]
+    pub profile_file: Option<PathBuf>,
 }
 
-#[derive(Debug)]
-pub enum Error {
-    Io(io::Error),
-    Evaluation(String, EvalError),
-    DuplicateConstant(String),
-    Formatter(String),
-    ImportsNotSupported { language: String },
-    TypeRequired { language: String, constant: String },
+/// Parse `NAME=VALUE`, where the value is a single literal
+fn parse_seed(text: &str) -> Result<(String, Primitive), String> {
+    let (name, value) = text
+        .split_once('=')
+        .ok_or_else(|| format!("Expected NAME=VALUE, got {:?}", text))?;
+    let value = Primitive::parse(value).map_err(|error| error.to_string())?;
+    Ok((name.to_owned(), value))
 }
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        match self {
-            Self::Evaluation(name, error) => write!(f, "In constant {:?}: {}", name, error),
-            Self::DuplicateConstant(name) => write!(f, "Duplicate constant definition {:?}", name),
-            Self::ImportsNotSupported { language } => write!(
-                f,
-                "Language {:?} does not specify import syntax, but it is required",
-                language
-            ),
-            Self::TypeRequired { language, constant } => write!(
-                f,
-                "Language {:?} requires types, but constant {:?} does not provide one",
-                language, constant
-            ),
-            _ => write!(f, "{:?}", self),
-        }
+
+/// Parse `KEY=VALUE`, where the key can be used as a template parameter
+fn parse_stamp(text: &str) -> Result<(String, String), String> {
+    let (key, value) = text
+        .split_once('=')
+        .ok_or_else(|| format!("Expected KEY=VALUE, got {:?}", text))?;
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("Invalid stamp key {:?}", key));
     }
+    Ok((key.to_owned(), value.to_owned()))
 }
-impl From<io::Error> for Error {
-    fn from(error: io::Error) -> Self {
-        Self::Io(error)
+
+/// Match `name` against a pattern where `*` matches any characters and `?` matches one
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position after the last `*` and the name position it currently matches up to
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, n));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    backtrack = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                },
+                None => return false,
+            },
+        }
     }
+    pattern[p..].iter().all(|&c| c == '*')
 }
-impl From<(Constant, EvalError)> for Error {
-    fn from((c, error): (Constant, EvalError)) -> Self {
-        Self::Evaluation(c.name, error)
+
+impl Config {
+    /// Whether a constant is emitted according to `--include` and `--exclude`
+    fn emits(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| glob_matches(p, name)))
+            && !self.exclude.iter().any(|p| glob_matches(p, name))
+    }
+
+    /// Fill in arguments missing from the command line
+    pub fn apply_profile(&mut self, profile: Profile) {
+        self.profile_file = Some(profile.path);
+        if self.target_dir.is_none() {
+            self.target_dir = profile.target_dir;
+        }
+        if self.stem.is_none() {
+            self.stem = profile.stem.map(OsString::from);
+        }
+        if self.options_file.is_none() {
+            self.options_file = profile.options;
+        }
+        if self.constants_file.is_empty() {
+            self.constants_file = profile.constants;
+        }
+    }
+
+    /// Log level selected by flags, None if RUST_LOG should be used instead.
+    /// Only errors are logged without either.
+    pub fn log_level(&self, rust_log: Option<&str>) -> Option<LevelFilter> {
+        if rust_log.is_some() {
+            None
+        } else if self.quiet {
+            Some(LevelFilter::Off)
+        } else if self.verbose {
+            Some(LevelFilter::Debug)
+        } else {
+            Some(LevelFilter::Error)
+        }
+    }
+
+    pub fn target_dir(&self) -> Result<&Path, Error> {
+        self.target_dir
+            .as_deref()
+            .ok_or(Error::MissingArgument("--target-dir"))
+    }
+
+    /// Templates and formatting operate on UTF-8, so the stem must be valid UTF-8 too
+    pub fn stem(&self) -> Result<&str, Error> {
+        let stem = self
+            .stem
+            .as_deref()
+            .unwrap_or_else(|| OsStr::new("constants"));
+        stem.to_str().ok_or_else(|| Error::InvalidStem(stem.to_owned()))
+    }
+
+    /// Resolve a relative target directory against the directory of the options file
+    pub fn target_dir_relative_to_options(&mut self) {
+        if let (Some(target), Some(options)) = (&self.target_dir, &self.options_file) {
+            if target.is_relative() {
+                let base = options.parent().unwrap_or_else(|| Path::new(""));
+                self.target_dir = Some(base.join(target));
+            }
+        }
+    }
+
+    pub fn options_file(&self) -> Result<&Path, Error> {
+        self.options_file
+            .as_deref()
+            .ok_or(Error::MissingArgument("--options"))
     }
 }
 
 #[paw::main]
 fn main(args: Config) {
-    pretty_env_logger::init();
+    let rust_log = env::var("RUST_LOG").ok();
+    let mut logger = pretty_env_logger::formatted_builder();
+    if let Some(level) = args.log_level(rust_log.as_deref()) {
+        logger.filter_level(level);
+    } else if let Some(filters) = &rust_log {
+        logger.parse_filters(filters);
+    }
+    logger.init();
+
+    let error_format = args.error_format;
     if let Err(e) = inner_main(args) {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
+        match error_format {
+            ErrorFormat::Human => eprintln!("Error: {}", e),
+            ErrorFormat::Json => eprintln!("{}", e.to_json()),
+        }
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn inner_main(mut args: Config) -> Result<(), Error> {
+    if let Some(profile) = Profile::discover(&env::current_dir()?)? {
+        args.apply_profile(profile);
+    }
+    run_config(args)
+}
+
+fn parse_toml<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    let c = fs::read(path)?;
+    toml::from_slice(&c).map_err(|error| Error::Parse {
+        path: path.to_owned(),
+        error,
+    })
+}
+
+/// Parse a constants file, or stdin if `path` is `-`
+fn parse_constants(path: &Path, format: Option<ConstantsFormat>) -> Result<Constants, Error> {
+    let (c, detected) = if path == Path::new("-") {
+        let mut c = Vec::new();
+        io::Read::read_to_end(&mut io::stdin(), &mut c)?;
+        (c, ConstantsFormat::Toml)
+    } else {
+        (fs::read(path)?, ConstantsFormat::detect(path))
+    };
+    match format.unwrap_or(detected) {
+        ConstantsFormat::Toml => toml::from_slice(&c).map_err(|error| Error::Parse {
+            path: path.to_owned(),
+            error,
+        }),
+        ConstantsFormat::Json => serde_json::from_slice(&c).map_err(|error| Error::ParseJson {
+            path: path.to_owned(),
+            error,
+        }),
+    }
+}
+
+/// Load a constants file, with the files it extends appended to `inherited`,
+/// each after its own parents. Files already in `loaded` are skipped,
+/// `stack` is used for cycle detection.
+/// Stdin extends files relative to the working directory.
+fn load_constants(
+    path: &Path, format: Option<ConstantsFormat>, loaded: &mut Vec<PathBuf>,
+    stack: &mut Vec<PathBuf>, inherited: &mut Constants,
+) -> Result<Constants, Error> {
+    let canonical = if path == Path::new("-") {
+        env::current_dir()?.join("-")
+    } else {
+        path.canonicalize()?
+    };
+    if stack.contains(&canonical) {
+        stack.push(canonical);
+        return Err(Error::ExtendsCycle(stack.clone()));
+    }
+    if loaded.contains(&canonical) {
+        return Ok(Constants::default());
+    }
+
+    let t = parse_constants(path, format)?;
+    if let Some(parent) = t.extends.clone() {
+        let parent = canonical.parent().expect("File has a parent").join(parent);
+        stack.push(canonical.clone());
+        let parent = load_constants(&parent, format, loaded, stack, inherited)?;
+        inherited.extend(parent);
+        stack.pop();
+    }
+    loaded.push(canonical);
+    Ok(t)
+}
+
+/// Options from the options file, on top of any built-in language presets
+fn load_options(args: &Config) -> Result<Options, Error> {
+    if args.language_preset.is_empty() {
+        let path = args.options_file()?;
+        return Options::from_slice(&fs::read(path)?).map_err(|error| Error::Parse {
+            path: path.to_owned(),
+            error,
+        });
     }
+    let user = match &args.options_file {
+        Some(path) => Some(parse_toml(path)?),
+        None => None,
+    };
+    Options::with_language_presets(&args.language_preset, user).map_err(Error::InvalidOptions)
 }
 
-fn inner_main(args: Config) -> Result<(), Error> {
-    let c = fs::read(args.options_file).unwrap();
-    let opts: Options = toml::from_slice(&c).unwrap();
+fn run_config(args: Config) -> Result<(), Error> {
+    let timing = args.timing;
+    let mut timings = Timings::new();
+    let result = run_timed(args, &mut timings);
+    if timing {
+        eprint!("{}", timings);
+    }
+    result
+}
 
-    let mut constants = Vec::new();
-    for p in args.constants_file {
-        let c = fs::read(p).unwrap();
-        let t: Constants = toml::from_slice(&c).unwrap();
-        constants.extend(t.constants);
+/// Run with the durations of each phase recorded in `timings`
+fn run_timed(mut args: Config, timings: &mut Timings) -> Result<(), Error> {
+    let mut opts: Options = timings.record("parse options", || load_options(&args))?;
+    if opts.codegen.paths_relative_to_config {
+        args.target_dir_relative_to_options();
+    }
+    opts.codegen.stamp.extend(args.stamp.iter().cloned());
+    opts.codegen.allow_commands = args.allow_commands;
+    if args.formatter_timeout.is_some() {
+        opts.codegen.formatter_timeout = args.formatter_timeout;
+    }
+    let stem = args.stem()?;
+    if args.print_config {
+        print!("{}", opts.to_toml());
+        return Ok(());
     }
+    let seeds: Context = args.seed_constant.iter().cloned().collect();
+    opts.apply_conditions(&seeds)
+        .map_err(|(language, error)| Error::Condition { language, error })?;
+    check_target_collisions(&args, &opts)?;
 
-    // Resolve constant values
-    let mut context: Context = Context::new();
-    for constant in constants.iter_mut() {
-        if context.contains_key(&constant.name) {
-            return Err(Error::DuplicateConstant(constant.name.clone()));
+    let mut loaded = Vec::new();
+    let mut inherited = Constants::default();
+    let all = timings.record("parse constants", || -> Result<_, Error> {
+        let mut all = Constants::default();
+        for p in &args.constants_file {
+            let format = args.constants_format;
+            all.extend(load_constants(p, format, &mut loaded, &mut Vec::new(), &mut inherited)?);
         }
-        constant
-            .resolve_value(&context)
-            .map_err(|err| (constant.clone(), err))?;
-        context.insert(constant.name.clone(), constant.value());
+        Ok(all)
+    })?;
+    let mut constants = all.constants;
+
+    // Resolve constant values, those of extended files first
+    let mut resolve_options = ResolveOptions {
+        keep_going: args.keep_going,
+        allow_env: args.allow_env,
+        seeds,
+        max_constants: args.max_constants,
+        max_expression_length: args.max_expression_length,
+        max_expression_depth: args.max_expression_depth,
+        ..ResolveOptions::default()
+    };
+    let mut base = inherited.constants;
+    let mut macros = inherited.macros;
+    resolve_options.inherited = timings.record("resolve extended", || {
+        resolve_with(&mut base, &macros, &resolve_options)
+    })?;
+    macros.extend(all.macros);
+    if let Some(name) = &args.explain {
+        for step in explain(&mut constants, &macros, &resolve_options, name)? {
+            println!("{}", step);
+        }
+        return Ok(());
     }
+    timings.record("resolve", || resolve_with(&mut constants, &macros, &resolve_options))?;
+    constants.retain(|constant| args.emits(&constant.name));
 
-    // Generate files to memory
-    let outputs = opts
-        .languages()
-        .into_iter()
-        .map(|(lang_name, lang_opts)| {
-            log::info!("Processing target {}", lang_name);
-            let mut buffer = String::new();
+    let result = if args.check || args.diff {
+        // Generate files to memory
+        let mut buffers = generate_timed(&opts, stem, &constants, timings)?;
+        let outputs: Vec<_> = opts
+            .languages()
+            .into_iter()
+            .map(|(lang_name, lang_opts)| {
+                let buffer = buffers.remove(lang_name).expect("Generated for every language");
+                (lang_name, lang_opts, buffer)
+            })
+            .collect();
+        timings.record("check", || check_outputs(&args, outputs))
+    } else {
+        // Only files with a formatter are generated to memory, others are streamed
+        let mut outputs = Vec::new();
+        for (lang_name, lang_opts) in opts.languages() {
+            let buffer = match lang_opts.formatter {
+                Some(_) => Some(generate_language_timed(
+                    &opts,
+                    lang_name,
+                    lang_opts,
+                    stem,
+                    &constants,
+                    timings,
+                )?),
+                None => None,
+            };
+            outputs.push((lang_name, lang_opts, buffer));
+        }
+        let stream = |lang_name: &str, lang_opts: &LangOptions, out: &mut dyn io::Write| {
+            generate_to(&opts, lang_name, lang_opts, stem, &constants, out)
+        };
+        // Streamed languages are recorded while writing, before the write itself
+        let mut streamed = Timings::new();
+        let mut write = Timings::new();
+        let result =
+            write.record("write", || write_outputs(&args, outputs, stream, &mut streamed));
+        timings.extend(streamed);
+        timings.extend(write);
+        result
+    };
+    result?;
 
-            // Imports
-            if opts.codegen.comment_sections {
-                buffer.push_str(&lang_opts.format_comment("Imports"));
-            }
-            let mut imports: Vec<String> = constants
-                .iter()
-                .flat_map(|c| lang_opts.constant_imports(c))
-                .collect();
-            imports.sort();
-            imports.dedup();
-            for import in &imports {
-                buffer.push_str(&lang_opts.format_import(import).ok_or_else(|| {
-                    Error::ImportsNotSupported {
-                        language: lang_name.to_owned(),
-                    }
-                })?);
-                buffer.push('\n');
+    match &args.emit_dependencies {
+        Some(path) if !(args.dry_run || args.check || args.diff) => {
+            let mut inputs = Vec::new();
+            for input in args.profile_file.iter().chain(&args.options_file) {
+                inputs.push(input.canonicalize()?);
             }
+            // Stdin is not a file that make could check
+            inputs.extend(loaded.into_iter().filter(|p| !p.ends_with("-")));
+            fs::write(path, dependency_rule(&args, &opts, &inputs)?)?;
+            Ok(())
+        },
+        _ => Ok(()),
+    }
+}
 
-            // Intro
-            if opts.codegen.comment_sections {
-                buffer.push_str(&lang_opts.format_comment("Start body block"));
-            }
-            buffer.push_str(&lang_opts.format_intro());
+/// Makefile rule making every generated file depend on every input file.
+/// Targets are made absolute, like the canonical paths of the inputs.
+fn dependency_rule(args: &Config, opts: &Options, inputs: &[PathBuf]) -> Result<String, Error> {
+    let current_dir = env::current_dir()?;
+    let mut targets = Vec::new();
+    for (_, lang_opts) in opts.languages() {
+        targets.push(make_escape(&current_dir.join(target_file(args, lang_opts)?)));
+    }
+    let inputs: Vec<_> = inputs.iter().map(|path| make_escape(path)).collect();
+    Ok(format!("{}: {}\n", targets.join(" "), inputs.join(" \\\n  ")))
+}
 
-            // Actual constant values
-            if opts.codegen.comment_sections {
-                buffer.push_str(&lang_opts.format_comment("Constants"));
-            }
-            for constant in &constants {
-                buffer.push_str(&lang_opts.format_constant(constant).ok_or_else(|| {
-                    Error::TypeRequired {
-                        language: lang_name.to_owned(),
-                        constant: constant.name.to_owned(),
-                    }
-                })?);
-                buffer.push('\n');
-            }
+/// Escape characters that are special in Makefile target and prerequisite lists
+fn make_escape(path: &Path) -> String {
+    let mut result = String::new();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            ' ' | '#' => {
+                result.push('\\');
+                result.push(c);
+            },
+            '$' => result.push_str("$$"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
 
-            // Outro
-            if opts.codegen.comment_sections {
-                buffer.push_str(&lang_opts.format_comment("End body block"));
-            }
-            buffer.push_str(&lang_opts.format_outro());
+/// Generated file contents with the language they were generated for
+type Output<'a> = (&'a String, &'a LangOptions, String);
 
-            // Run formatter if available
-            if let Some(f) = &lang_opts.formatter {
-                buffer = run_formatter(f, &buffer)?;
+/// Like [`Output`], but without contents for files generated while writing them
+type StreamedOutput<'a> = (&'a String, &'a LangOptions, Option<String>);
+
+/// Compare against existing files instead of writing
+fn check_outputs(args: &Config, outputs: Vec<Output>) -> Result<(), Error> {
+    let mut out_of_date = Vec::new();
+    for (lang_name, lang_opts, buffer) in outputs.into_iter() {
+        let target_file = target_file(args, lang_opts)?;
+        let existing = match fs::read_to_string(&target_file) {
+            Ok(text) => text,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error.into()),
+        };
+        if existing != buffer {
+            log::warn!("Outdated {} file: {:?}", lang_name, target_file);
+            if args.diff {
+                eprint!("{}", unified_diff(&target_file, &existing, &buffer));
             }
+            out_of_date.push(target_file);
+        }
+    }
+    if out_of_date.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::OutOfDate(out_of_date))
+    }
+}
 
-            Ok((lang_name, lang_opts, buffer))
-        })
-        .collect::<Result<Vec<_>, Error>>()?;
+/// Actually write generated files, calling `stream` to generate files without contents.
+/// Every file is written next to its target first, and only moved in place once all
+/// of them have been generated, so that errors leave all targets unchanged.
+fn write_outputs<S>(
+    args: &Config, outputs: Vec<StreamedOutput>, stream: S, timings: &mut Timings,
+) -> Result<(), Error>
+where S: Fn(&str, &LangOptions, &mut dyn io::Write) -> Result<(), Error> {
+    let target_dir = args.target_dir()?;
+    if !args.dry_run && !target_dir.is_dir() {
+        log::info!("Creating target directory {:?}", target_dir);
+        fs::create_dir_all(target_dir).map_err(|error| Error::CreateTargetDir {
+            path: target_dir.to_owned(),
+            error,
+        })?;
+    }
+
+    // Check all targets before writing any of them
+    let mut protected = Vec::new();
+    for (_, lang_opts, _) in &outputs {
+        let target_file = target_file(args, lang_opts)?;
+        if let Some(reason) = protected_target(&target_file)? {
+            protected.push((target_file, reason));
+        }
+    }
+    if !args.force && !args.dry_run {
+        if let Some((path, reason)) = protected.into_iter().next() {
+            return Err(Error::ProtectedTarget { path, reason });
+        }
+    }
 
-    // Actually write generated files
+    let mut written = Vec::new();
     for (lang_name, lang_opts, buffer) in outputs.into_iter() {
-        let target_file = args.target_dir.join(format!(
-            "{}{}",
-            args.stem.to_str().unwrap(),
-            lang_opts.file_ext
-        ));
+        let target_file = target_file(args, lang_opts)?;
+        let phase = format!("generate {}", lang_name);
+        if args.dry_run && args.print_files {
+            println!("{}", target_file.to_str().unwrap());
+        }
+        match buffer {
+            Some(_) if args.dry_run => {},
+            None if args.dry_run => {
+                timings.record(&phase, || stream(lang_name, lang_opts, &mut io::sink()))?
+            },
+            Some(buffer) => {
+                let (temporary, file) = TemporaryFile::create(&target_file)?;
+                let mut file = io::BufWriter::new(file);
+                file.write_all(buffer.as_bytes())?;
+                file.flush()?;
+                written.push((lang_name, target_file, temporary));
+            },
+            None => {
+                let (temporary, file) = TemporaryFile::create(&target_file)?;
+                let mut file = io::BufWriter::new(file);
+                timings.record(&phase, || stream(lang_name, lang_opts, &mut file))?;
+                file.flush()?;
+                written.push((lang_name, target_file, temporary));
+            },
+        }
+    }
+
+    for (lang_name, target_file, temporary) in written {
         if args.print_files {
             println!("{}", target_file.to_str().unwrap());
         }
-        if !args.dry_run {
-            log::info!("Writing {} file: {:?}", lang_name, target_file);
-            fs::write(target_file, buffer.as_bytes())?;
+        if args.force && protected_target(&target_file)?.is_some() {
+            // Replace the file itself instead of following the link
+            log::warn!("Replacing protected file: {:?}", target_file);
+            fs::remove_file(&target_file)?;
         }
+        log::info!("Writing {} file: {:?}", lang_name, target_file);
+        temporary.persist(&target_file)?;
     }
 
     Ok(())
 }
 
-fn run_formatter(cmd: &[String], source: &str) -> Result<String, Error> {
-    use std::io::Write;
-    use std::process::{Command, Stdio};
+/// File next to a target, removed when dropped unless moved to the target.
+/// Removal on drop also covers errors and panics while generating its contents.
+struct TemporaryFile(Option<PathBuf>);
+impl TemporaryFile {
+    fn create(target_file: &Path) -> Result<(Self, fs::File), Error> {
+        let mut path = target_file.to_owned().into_os_string();
+        path.push(".tmp");
+        let path = PathBuf::from(path);
+        let file = fs::File::create(&path)?;
+        Ok((Self(Some(path)), file))
+    }
+
+    fn persist(mut self, target_file: &Path) -> Result<(), Error> {
+        let path = self.0.take().expect("Not persisted yet");
+        if let Err(error) = fs::rename(&path, target_file) {
+            let _ = fs::remove_file(&path);
+            return Err(error.into());
+        }
+        Ok(())
+    }
+}
+impl Drop for TemporaryFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn target_file(args: &Config, lang_opts: &LangOptions) -> Result<PathBuf, Error> {
+    Ok(args
+        .target_dir()?
+        .join(format!("{}{}", args.stem()?, lang_opts.file_ext)))
+}
+
+/// Fail if several enabled languages would write the same file,
+/// as each would silently replace the output of the previous one
+fn check_target_collisions(args: &Config, opts: &Options) -> Result<(), Error> {
+    let mut targets: Vec<(PathBuf, Vec<String>)> = Vec::new();
+    for (lang_name, lang_opts) in opts.languages() {
+        let target_file = target_file(args, lang_opts)?;
+        match targets.iter_mut().find(|(path, _)| *path == target_file) {
+            Some((_, languages)) => languages.push(lang_name.clone()),
+            None => targets.push((target_file, vec![lang_name.clone()])),
+        }
+    }
+    match targets.into_iter().find(|(_, languages)| languages.len() > 1) {
+        Some((path, languages)) => Err(Error::TargetCollision { path, languages }),
+        None => Ok(()),
+    }
+}
+
+/// Reason for refusing to overwrite an existing target file without `--force`
+fn protected_target(path: &Path) -> Result<Option<&'static str>, Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    Ok(if metadata.file_type().is_symlink() {
+        Some("is a symbolic link")
+    } else if metadata.permissions().readonly() {
+        Some("is read-only")
+    } else {
+        None
+    })
+}
+
+/// Unified diff from the existing file contents to the generated ones
+fn unified_diff(path: &Path, existing: &str, generated: &str) -> String {
+    similar::TextDiff::from_lines(existing, generated)
+        .unified_diff()
+        .header(
+            &format!("{} (existing)", path.display()),
+            &format!("{} (generated)", path.display()),
+        )
+        .to_string()
+}
+
+#[cfg(test)]
+mod test_main {
+    use std::fs;
+    use std::path::Path;
+
+    use structopt::StructOpt;
+
+    use super::{
+        glob_matches, inner_main, make_escape, run_config, run_timed, unified_diff, Config, Error,
+        ErrorFormat,
+    };
+    use constcodegen::Timings;
+    use crate::profile::Profile;
+
+    const OPTIONS: &str = r#"
+        [codegen]
+        enabled = ["python"]
+
+        [lang.python]
+        file_ext = ".py"
+        template = "$name = $value"
+    "#;
+
+    const CONSTANTS: &str = r#"
+        [[constant]]
+        name = "A"
+        value = "1"
+    "#;
+
+    /// Writes the input files to `dir` and runs the generator with extra arguments
+    fn run(dir: &Path, options: &str, constants: &str, args: &[&str]) -> Result<(), Error> {
+        inner_main(config(dir, options, constants, args))
+    }
+
+    /// Writes the input files to `dir` and parses the arguments to run with them
+    fn config(dir: &Path, options: &str, constants: &str, args: &[&str]) -> Config {
+        let options_file = dir.join("options.toml");
+        let constants_file = dir.join("constants.toml");
+        fs::write(&options_file, options).unwrap();
+        fs::write(&constants_file, constants).unwrap();
+
+        let mut argv = vec![
+            "constcodegen".to_owned(),
+            "--options".to_owned(),
+            options_file.to_str().unwrap().to_owned(),
+        ];
+        argv.extend(args.iter().map(|a| (*a).to_owned()));
+        argv.push(constants_file.to_str().unwrap().to_owned());
+        Config::from_iter(argv)
+    }
+
+    #[test]
+    fn test_creates_target_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("gen").join("nested");
+        run(dir.path(), OPTIONS, CONSTANTS, &["-t", target.to_str().unwrap()]).unwrap();
+        assert_eq!(fs::read_to_string(target.join("constants.py")).unwrap(), "A = 1\n");
+    }
 
-    if cmd.is_empty() {
-        return Err(Error::Formatter("Formatter command empty".to_owned()));
+    #[test]
+    fn test_stem_in_intro() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        let options = r##"
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+            intro = "# Generated: $stem$ext"
+            outro = "# End of $stem"
+        "##;
+        run(dir.path(), options, CONSTANTS, &["-t", target, "-s", "layout"]).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("layout.py")).unwrap(),
+            "# Generated: layout.py\nA = 1\n# End of layout\n"
+        );
     }
 
-    log::info!("Running formatter {:?}", cmd);
-    let mut p = Command::new(cmd[0].clone())
-        .args(&cmd[1..])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_stem() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
 
-    p.stdin.as_mut().unwrap().write_all(source.as_bytes())?;
-    let output = p.wait_with_output().expect("failed to wait on child");
+        let dir = tempfile::tempdir().unwrap();
+        let options_file = dir.path().join("options.toml");
+        let constants_file = dir.path().join("constants.toml");
+        fs::write(&options_file, OPTIONS).unwrap();
+        fs::write(&constants_file, CONSTANTS).unwrap();
+
+        let stem = OsString::from_vec(vec![b'c', 0xff]);
+        let argv: Vec<OsString> = vec![
+            "constcodegen".into(),
+            "--options".into(),
+            options_file.into(),
+            "-t".into(),
+            dir.path().into(),
+            "-s".into(),
+            stem.clone(),
+            constants_file.into(),
+        ];
+        let error = inner_main(Config::from_iter(argv)).unwrap_err();
+        assert!(matches!(&error, Error::InvalidStem(s) if *s == stem));
+        assert_eq!(error.exit_code(), 2);
+    }
+
+    #[test]
+    fn test_protected_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        let file = dir.path().join("constants.py");
+        fs::write(&file, "old\n").unwrap();
+        let mut permissions = fs::metadata(&file).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&file, permissions).unwrap();
+
+        let error = run(dir.path(), OPTIONS, CONSTANTS, &["-t", target]).unwrap_err();
+        assert!(matches!(&error, Error::ProtectedTarget { path, .. } if *path == file));
+        assert!(error.to_string().contains("read-only"), "{}", error);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "old\n");
+
+        run(dir.path(), OPTIONS, CONSTANTS, &["-t", target, "--force"]).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "A = 1\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("gen");
+        fs::create_dir(&target).unwrap();
+        let outside = dir.path().join("outside.py");
+        fs::write(&outside, "outside\n").unwrap();
+        std::os::unix::fs::symlink(&outside, target.join("constants.py")).unwrap();
+
+        let args = ["-t", target.to_str().unwrap()];
+        let error = run(dir.path(), OPTIONS, CONSTANTS, &args).unwrap_err();
+        assert!(error.to_string().contains("symbolic link"), "{}", error);
+
+        run(dir.path(), OPTIONS, CONSTANTS, &[args[0], args[1], "-f"]).unwrap();
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "outside\n");
+        assert_eq!(fs::read_to_string(target.join("constants.py")).unwrap(), "A = 1\n");
+        let metadata = fs::symlink_metadata(target.join("constants.py")).unwrap();
+        assert!(!metadata.file_type().is_symlink());
+    }
+
+    #[test]
+    fn test_language_presets() {
+        let dir = tempfile::tempdir().unwrap();
+        let constants_file = dir.path().join("constants.toml");
+        fs::write(
+            &constants_file,
+            r#"
+            [[constant]]
+            name = "PAGE_SIZE"
+            type = "u32"
+            value = "0x1000"
+
+            [[constant]]
+            name = "ENABLED"
+            type = "bool"
+            value = "true"
+
+            [[constant]]
+            name = "NAME"
+            type = "&str"
+            value = '"paging"'
+            "#,
+        )
+        .unwrap();
+        let argv = [
+            "constcodegen",
+            "--language-preset",
+            "rust,c",
+            "-t",
+            dir.path().to_str().unwrap(),
+            constants_file.to_str().unwrap(),
+        ];
+        inner_main(Config::from_iter(&argv)).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("constants.rs")).unwrap(),
+            concat!(
+                "pub const PAGE_SIZE: u32 = 4096;\n",
+                "pub const ENABLED: bool = true;\n",
+                "pub const NAME: &str = \"paging\";\n",
+            )
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("constants.h")).unwrap(),
+            concat!(
+                "#pragma once\n",
+                "#define PAGE_SIZE 4096\n",
+                "#define ENABLED 1\n",
+                "#define NAME \"paging\"\n",
+            )
+        );
+
+        let options = "[lang.c]\nfile_ext = \".hh\"\n[codegen]\nenabled = [\"c\"]";
+        let args = ["--language-preset", "c,rust", "-t", dir.path().to_str().unwrap()];
+        run(dir.path(), options, CONSTANTS, &args).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("constants.hh")).unwrap(),
+            "#pragma once\n#define A 1\n"
+        );
+
+        // The preset takes one value, so the constants file is not taken as another one
+        let config = Config::from_iter(&[
+            "constcodegen",
+            "--language-preset",
+            "rust",
+            "--language-preset",
+            "c",
+            "x.toml",
+        ]);
+        assert_eq!(config.language_preset, vec!["rust", "c"]);
+        assert_eq!(config.constants_file, vec![Path::new("x.toml")]);
+
+        let args = ["--language-preset", "cobol", "-t", dir.path().to_str().unwrap()];
+        let error = run(dir.path(), OPTIONS, CONSTANTS, &args).unwrap_err();
+        assert!(matches!(error, Error::InvalidOptions(_)), "{:?}", error);
+        assert_eq!(error.exit_code(), 2);
+    }
 
-    if !output.status.success() {
-        return Err(Error::Formatter(format!(
-            "Formatter returned with non-zero exit code {:?}",
-            output.status.code()
-        )));
+    #[test]
+    fn test_paths_relative_to_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config");
+        fs::create_dir(&config).unwrap();
+        let options = OPTIONS.replace("[codegen]", "[codegen]\npaths_relative_to_config = true");
+        run(&config, &options, CONSTANTS, &["-t", "gen"]).unwrap();
+        let output = config.join("gen").join("constants.py");
+        assert_eq!(fs::read_to_string(output).unwrap(), "A = 1\n");
     }
 
-    Ok(String::from_utf8(output.stdout).expect("Non-utf8 output from formatter"))
+    #[test]
+    fn test_streamed_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        run(dir.path(), OPTIONS, CONSTANTS, &["-t", target]).unwrap();
+        run(dir.path(), OPTIONS, CONSTANTS, &["-t", target, "--check"]).unwrap();
+        assert!(!dir.path().join("constants.py.tmp").exists());
+
+        // A failing constant leaves the previous file intact
+        let options = OPTIONS.replace("$value", "$value: $type");
+        let error = run(dir.path(), &options, CONSTANTS, &["-t", target]).unwrap_err();
+        assert!(matches!(error, Error::TypeRequired { .. }), "{:?}", error);
+        assert_eq!(fs::read_to_string(dir.path().join("constants.py")).unwrap(), "A = 1\n");
+        assert!(!dir.path().join("constants.py.tmp").exists());
+
+        // A failing language leaves the files of all other languages intact
+        let options = r##"
+            [lang.c]
+            file_ext = ".h"
+            template = "#define $name $value"
+
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+        "##;
+        run(dir.path(), options, CONSTANTS, &["-t", target]).unwrap();
+        let constants = CONSTANTS.replace("value = \"1\"", "value = \"2\"");
+        let failing = format!("{}name_suffix = \"_X\"\nreserved_words = [\"A_X\"]\n", options);
+        let error = run(dir.path(), &failing, &constants, &["-t", target]).unwrap_err();
+        assert!(matches!(error, Error::InvalidIdentifier { .. }), "{:?}", error);
+        assert!(error.to_string().contains("output as \"A_X\""), "{}", error);
+        assert_eq!(fs::read_to_string(dir.path().join("constants.h")).unwrap(), "#define A 1\n");
+        assert_eq!(fs::read_to_string(dir.path().join("constants.py")).unwrap(), "A = 1\n");
+        assert!(!dir.path().join("constants.h.tmp").exists());
+        assert!(!dir.path().join("constants.py.tmp").exists());
+    }
+
+    #[test]
+    fn test_streamed_matches_buffered() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        // The formatter makes the second language generated to memory instead
+        let options = r##"
+            [codegen]
+            comment_sections = true
+            checksum = true
+
+            [lang.rust]
+            file_ext = ".rs"
+            template = "pub const $name: $type = $value;"
+            import = "use $import;"
+            comment = "// $comment"
+            file_doc = "Generated"
+            enum_intro = "pub enum $enum {"
+            enum_member = "    $name = $value,"
+            enum_outro = "}"
+            type.PhysAddr.import = ["x86_64::PhysAddr"]
+
+            [lang.buffered]
+            file_ext = ".buffered.rs"
+            template = "pub const $name: $type = $value;"
+            import = "use $import;"
+            comment = "// $comment"
+            file_doc = "Generated"
+            enum_intro = "pub enum $enum {"
+            enum_member = "    $name = $value,"
+            enum_outro = "}"
+            type.PhysAddr.import = ["x86_64::PhysAddr"]
+            formatter = ["cat"]
+        "##;
+        let constants = r#"
+            [[constant]]
+            name = "A"
+            type = "PhysAddr"
+            value = "1"
+
+            [[constant]]
+            name = "B"
+            type = "u8"
+            enum = "Kind"
+            value = "2"
+        "#;
+        run(dir.path(), options, constants, &["-t", target]).unwrap();
+        let streamed = fs::read_to_string(dir.path().join("constants.rs")).unwrap();
+        let buffered = fs::read_to_string(dir.path().join("constants.buffered.rs")).unwrap();
+        assert!(streamed.contains("pub enum Kind {\n"), "{}", streamed);
+        assert_eq!(streamed, buffered);
+
+        // Checking generates every language to memory
+        run(dir.path(), options, constants, &["-t", target, "--check"]).unwrap();
+    }
+
+    #[test]
+    fn test_timing_phases() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        let options = r#"
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+
+            [lang.formatted]
+            file_ext = ".formatted.py"
+            template = "$name = $value"
+            formatter = ["cat"]
+
+            [lang.nasm]
+            file_ext = ".asm"
+            template = "%define $name $value"
+        "#;
+        let phases = |args: &[&str]| {
+            let mut timings = Timings::new();
+            run_timed(config(dir.path(), options, CONSTANTS, args), &mut timings).unwrap();
+            timings.phases().map(|(phase, _)| phase.to_owned()).collect::<Vec<_>>()
+        };
+        for args in &[vec!["-t", target], vec!["-t", target, "--check"], vec!["-t", target, "-d"]] {
+            let phases = phases(args);
+            for language in &["formatted", "nasm", "python"] {
+                let phase = format!("generate {}", language);
+                assert!(phases.contains(&phase), "{:?}: {:?}", args, phases);
+            }
+            assert!(phases.contains(&"formatter formatted".to_owned()), "{:?}", phases);
+        }
+    }
+
+    #[test]
+    fn test_seed_constant() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        let constants = r#"
+            [[constant]]
+            name = "B"
+            value = "(add BASE 1)"
+
+            [[constant]]
+            name = "MODE"
+            value = '"debug"'
+        "#;
+        let args = ["-t", target, "--seed-constant", "BASE=0x1000"];
+        run(dir.path(), OPTIONS, constants, &args).unwrap();
+        let output = fs::read_to_string(dir.path().join("constants.py")).unwrap();
+        assert_eq!(output, "B = 4097\nMODE = \"debug\"\n");
+
+        let args = ["-t", target, "--seed-constant", "BASE=0", "--seed-constant", "MODE=\"ci\""];
+        run(dir.path(), OPTIONS, constants, &args).unwrap();
+        let output = fs::read_to_string(dir.path().join("constants.py")).unwrap();
+        assert_eq!(output, "B = 1\nMODE = \"ci\"\n");
+
+        // Seeds are also the build flags of language conditions
+        let options = format!("{}condition = \"(not SKIP)\"\n", OPTIONS);
+        let target = dir.path().join("conditional");
+        let args = ["-t", target.to_str().unwrap(), "--seed-constant", "SKIP=true"];
+        run(dir.path(), &options, CONSTANTS, &args).unwrap();
+        assert!(!target.join("constants.py").exists());
+
+        for invalid in &["BASE", "BASE=(add 1 2)", "BASE=OTHER"] {
+            let argv = ["constcodegen", "--seed-constant", invalid];
+            assert!(Config::from_iter_safe(&argv).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_stamp() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        let options = format!("{}intro = \"# Generated at commit $stamp_commit\"\n", OPTIONS);
+        let args = ["-t", target, "--stamp", "commit=abc123"];
+        run(dir.path(), &options, CONSTANTS, &args).unwrap();
+        let output = fs::read_to_string(dir.path().join("constants.py")).unwrap();
+        assert_eq!(output, "# Generated at commit abc123\nA = 1\n");
+
+        // The same stamp keeps the output up to date
+        let args = ["-t", target, "--check", "--stamp", "commit=abc123"];
+        run(dir.path(), &options, CONSTANTS, &args).unwrap();
+        let args = ["-t", target, "--check", "--stamp", "commit=def456"];
+        let error = run(dir.path(), &options, CONSTANTS, &args).unwrap_err();
+        assert!(matches!(error, Error::OutOfDate(_)), "{:?}", error);
+
+        // A stamp used by the intro must be given
+        let error = run(dir.path(), &options, CONSTANTS, &["-t", target]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Language \"python\" uses $stamp_commit, which is not set; pass --stamp commit=VALUE"
+        );
+        assert_eq!(error.exit_code(), 2);
+        let output = fs::read_to_string(dir.path().join("constants.py")).unwrap();
+        assert_eq!(output, "# Generated at commit abc123\nA = 1\n");
+        assert!(!dir.path().join("constants.py.tmp").exists());
+
+        for invalid in &["commit", "=abc", "a-b=c"] {
+            let argv = ["constcodegen", "--stamp", invalid];
+            assert!(Config::from_iter_safe(&argv).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_include_exclude() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        let constants = r#"
+            [[constant]]
+            name = "PRIV_BASE"
+            value = "0x1000"
+
+            [[constant]]
+            name = "PUB_SIZE"
+            value = "(add PRIV_BASE 1)"
+
+            [[constant]]
+            name = "PUB_INTERNAL"
+            value = "2"
+
+            [[constant]]
+            name = "OTHER_PUB"
+            value = "3"
+        "#;
+        run(dir.path(), OPTIONS, constants, &["-t", target, "--include", "PUB_*"]).unwrap();
+        let output = fs::read_to_string(dir.path().join("constants.py")).unwrap();
+        assert_eq!(output, "PUB_SIZE = 4097\nPUB_INTERNAL = 2\n");
+
+        let args = ["-t", target, "--include", "PUB_*", "--exclude", "*INTERNAL"];
+        run(dir.path(), OPTIONS, constants, &args).unwrap();
+        let output = fs::read_to_string(dir.path().join("constants.py")).unwrap();
+        assert_eq!(output, "PUB_SIZE = 4097\n");
+
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("A?C", "ABC"));
+        assert!(glob_matches("*_*_END", "A_B_C_END"));
+        assert!(!glob_matches("A?C", "AC"));
+        assert!(!glob_matches("PUB_*", "OTHER_PUB"));
+    }
+
+    #[test]
+    fn test_target_collision() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        let options = r##"
+            [lang.c]
+            file_ext = ".h"
+            template = "#define $name $value"
+
+            [lang.cpp]
+            file_ext = ".h"
+            template = "constexpr auto $name = $value;"
+
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+        "##;
+        let error = run(dir.path(), options, CONSTANTS, &["-t", target]).unwrap_err();
+        match &error {
+            Error::TargetCollision { path, languages } => {
+                assert_eq!(path, &dir.path().join("constants.h"));
+                assert_eq!(languages, &["c", "cpp"]);
+            },
+            other => panic!("Unexpected error {:?}", other),
+        }
+        assert_eq!(error.exit_code(), 2);
+        assert!(error.to_string().starts_with("Languages \"c\", \"cpp\" would all write"));
+        assert!(!dir.path().join("constants.py").exists());
+    }
+
+    #[test]
+    fn test_target_dir_blocked_by_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("gen");
+        fs::write(&target, "").unwrap();
+        match run(dir.path(), OPTIONS, CONSTANTS, &["-t", target.to_str().unwrap()]) {
+            Err(Error::CreateTargetDir { path, .. }) => assert_eq!(path, target),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        match run(dir.path(), OPTIONS, CONSTANTS, &["-t", target, "--check"]) {
+            Err(Error::OutOfDate(paths)) => {
+                assert_eq!(paths, vec![dir.path().join("constants.py")])
+            },
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(!dir.path().join("constants.py").exists());
+
+        run(dir.path(), OPTIONS, CONSTANTS, &["-t", target]).unwrap();
+        run(dir.path(), OPTIONS, CONSTANTS, &["-t", target, "--check"]).unwrap();
+        let changed = CONSTANTS.replace("\"1\"", "\"2\"");
+        assert!(run(dir.path(), OPTIONS, &changed, &["-t", target, "--diff"]).is_err());
+    }
+
+    #[test]
+    fn test_diff_output() {
+        let diff = unified_diff(Path::new("constants.py"), "A = 1\nB = 2\n", "A = 1\nB = 3\n");
+        assert!(diff.contains("-B = 2\n"), "{}", diff);
+        assert!(diff.contains("+B = 3\n"), "{}", diff);
+        assert!(!diff.contains("-A = 1"), "{}", diff);
+    }
+
+    #[test]
+    fn test_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("options.toml"), OPTIONS).unwrap();
+        fs::write(dir.path().join("constants.toml"), CONSTANTS).unwrap();
+        fs::write(
+            dir.path().join(Profile::FILE_NAME),
+            r#"
+                target_dir = "from_profile"
+                stem = "custom"
+                options = "options.toml"
+                constants = ["constants.toml"]
+            "#,
+        )
+        .unwrap();
+        let subdir = dir.path().join("sub");
+        fs::create_dir(&subdir).unwrap();
+
+        let target = dir.path().join("from_cli");
+        let mut args = Config::from_iter(&["constcodegen", "-t", target.to_str().unwrap()]);
+        args.apply_profile(Profile::discover(&subdir).unwrap().unwrap());
+        run_config(args).unwrap();
+
+        assert_eq!(fs::read_to_string(target.join("custom.py")).unwrap(), "A = 1\n");
+        assert!(!dir.path().join("from_profile").exists());
+    }
+
+    #[test]
+    fn test_missing_argument() {
+        let args = Config::from_iter(&["constcodegen", "-t", "target"]);
+        match run_config(args) {
+            Err(Error::MissingArgument(name)) => assert_eq!(name, "--options"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unknown_field_suggestion() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        let options = OPTIONS.replace("[codegen]", "[codegen]\ncomment_section = true");
+        let error = run(dir.path(), &options, CONSTANTS, &["-t", target]).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("options.toml"), "{}", message);
+        assert!(message.ends_with("Did you mean `comment_sections`?"), "{}", message);
+    }
+
+    #[test]
+    fn test_error_format() {
+        let args = Config::from_iter(&["constcodegen", "--error-format", "json"]);
+        assert_eq!(args.error_format, ErrorFormat::Json);
+        assert_eq!(Config::from_iter(&["constcodegen"]).error_format, ErrorFormat::Human);
+        assert!(Config::from_iter_safe(&["constcodegen", "--error-format", "xml"]).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        let error = run(dir.path(), OPTIONS, "[[constant]]", &["-t", target]).unwrap_err();
+        let json = error.to_json();
+        assert_eq!(json["kind"], "parse");
+        assert!(json["file"].as_str().unwrap().ends_with("constants.toml"), "{}", json);
+    }
+
+    #[test]
+    fn test_constants_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        let json = r#"{"constant": [{"name": "A", "value": "(add 1 2)"}]}"#;
+        run(dir.path(), OPTIONS, json, &["-t", target, "--constants-format", "json"]).unwrap();
+        let output = fs::read_to_string(dir.path().join("constants.py")).unwrap();
+        assert_eq!(output, "A = 3\n");
+
+        // Detected from the extension
+        let constants_file = dir.path().join("constants.json");
+        fs::write(&constants_file, json.replace('2', "3")).unwrap();
+        let options_file = dir.path().join("options.toml");
+        let argv = [
+            "constcodegen",
+            "--options",
+            options_file.to_str().unwrap(),
+            "-t",
+            target,
+            constants_file.to_str().unwrap(),
+        ];
+        inner_main(Config::from_iter(&argv)).unwrap();
+        let output = fs::read_to_string(dir.path().join("constants.py")).unwrap();
+        assert_eq!(output, "A = 4\n");
+
+        let args = ["-t", target, "--constants-format", "json"];
+        let error = run(dir.path(), OPTIONS, CONSTANTS, &args).unwrap_err();
+        assert!(matches!(error, Error::ParseJson { .. }), "{:?}", error);
+        assert_eq!(error.to_json()["line"], 2);
+        assert!(Config::from_iter_safe(&["constcodegen", "--constants-format", "yaml"]).is_err());
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+
+        let parse_error = run(dir.path(), OPTIONS, "[[constant]]", &["-t", target]).unwrap_err();
+        assert!(matches!(parse_error, Error::Parse { .. }));
+
+        let constants = CONSTANTS.replace("\"1\"", "\"(add 1)\"");
+        let eval_error = run(dir.path(), OPTIONS, &constants, &["-t", target]).unwrap_err();
+        assert!(matches!(eval_error, Error::Evaluation(..)));
+
+        assert_ne!(parse_error.exit_code(), eval_error.exit_code());
+        assert_ne!(parse_error.exit_code(), 0);
+        assert_ne!(eval_error.exit_code(), 0);
+    }
+
+    #[test]
+    fn test_keep_going() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        let constants = r#"
+            [[constant]]
+            name = "A"
+            value = "(add 1)"
+
+            [[constant]]
+            name = "B"
+            value = "2"
+
+            [[constant]]
+            name = "C"
+            value = "(mul B true)"
+        "#;
+
+        let error = run(dir.path(), OPTIONS, constants, &["-t", target]).unwrap_err();
+        assert!(matches!(&error, Error::Evaluation(name, _) if name == "A"));
+
+        let error =
+            run(dir.path(), OPTIONS, constants, &["-t", target, "--keep-going"]).unwrap_err();
+        match &error {
+            Error::Multiple(errors) => {
+                assert_eq!(errors.len(), 2);
+                assert!(matches!(&errors[0], Error::Evaluation(name, _) if name == "A"));
+                assert!(matches!(&errors[1], Error::Evaluation(name, _) if name == "C"));
+            },
+            other => panic!("Unexpected error {:?}", other),
+        }
+        let message = error.to_string();
+        assert!(message.starts_with("2 errors occurred:"), "{}", message);
+        assert!(message.contains("\"A\"") && message.contains("\"C\""), "{}", message);
+        assert_eq!(error.exit_code(), 5);
+        assert!(!dir.path().join("constants.py").exists());
+    }
+
+    #[test]
+    fn test_extends() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        fs::create_dir(dir.path().join("base")).unwrap();
+        fs::write(
+            dir.path().join("base").join("base.toml"),
+            "[[constant]]\nname = \"BASE\"\nvalue = \"0x1000\"\n",
+        )
+        .unwrap();
+        let constants = r#"
+            extends = "base/base.toml"
+
+            [[constant]]
+            name = "A"
+            value = "(add BASE 1)"
+        "#;
+        run(dir.path(), OPTIONS, constants, &["-t", target]).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("constants.py")).unwrap(), "A = 4097\n");
+    }
+
+    #[test]
+    fn test_extends_override() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        fs::write(
+            dir.path().join("base.toml"),
+            "[[constant]]\nname = \"SIZE\"\nvalue = \"4\"\n\
+             [[constant]]\nname = \"END\"\nvalue = \"(add SIZE 1)\"\n",
+        )
+        .unwrap();
+        let constants = r#"
+            extends = "base.toml"
+
+            [[constant]]
+            name = "BEFORE"
+            value = "SIZE"
+
+            [[constant]]
+            name = "SIZE"
+            value = "8"
+
+            [[constant]]
+            name = "AFTER"
+            value = "(add SIZE END)"
+        "#;
+        run(dir.path(), OPTIONS, constants, &["-t", target]).unwrap();
+        // Constants of the base file keep the values they were resolved with
+        assert_eq!(
+            fs::read_to_string(dir.path().join("constants.py")).unwrap(),
+            "BEFORE = 4\nSIZE = 8\nAFTER = 13\n"
+        );
+    }
+
+    #[test]
+    fn test_emit_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("gen");
+        let deps = dir.path().join("constants.d");
+        fs::write(dir.path().join("base.toml"), "[[constant]]\nname = \"B\"\nvalue = \"2\"\n")
+            .unwrap();
+        let options = r##"
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+
+            [lang.c]
+            file_ext = ".h"
+            template = "#define $name $value"
+        "##;
+        let constants = "extends = \"base.toml\"\n[[constant]]\nname = \"A\"\nvalue = \"1\"\n";
+        let args = ["-t", target.to_str().unwrap(), "--emit-dependencies", deps.to_str().unwrap()];
+        run(dir.path(), options, constants, &args).unwrap();
+
+        let canonical = |name: &str| dir.path().join(name).canonicalize().unwrap();
+        assert_eq!(
+            fs::read_to_string(&deps).unwrap(),
+            format!(
+                "{} {}: {} \\\n  {} \\\n  {}\n",
+                target.join("constants.h").display(),
+                target.join("constants.py").display(),
+                canonical("options.toml").display(),
+                canonical("base.toml").display(),
+                canonical("constants.toml").display(),
+            )
+        );
+
+        // Checking writes nothing
+        fs::remove_file(&deps).unwrap();
+        run(dir.path(), options, constants, &[&args[..], &["--check"]].concat()).unwrap();
+        assert!(!deps.exists());
+
+        // The profile is an input too
+        fs::write(dir.path().join(Profile::FILE_NAME), "stem = \"constants\"").unwrap();
+        let mut config = config(dir.path(), options, constants, &args);
+        config.apply_profile(Profile::discover(dir.path()).unwrap().unwrap());
+        run_config(config).unwrap();
+        let rule = fs::read_to_string(&deps).unwrap();
+        let profile = format!(": {} \\\n", canonical(Profile::FILE_NAME).display());
+        assert!(rule.contains(&profile), "{}", rule);
+    }
+
+    #[test]
+    fn test_make_escape() {
+        assert_eq!(make_escape(Path::new("a b/#$.toml")), "a\\ b/\\#$$.toml");
+    }
+
+    #[test]
+    fn test_extends_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().to_str().unwrap();
+        fs::write(dir.path().join("other.toml"), "extends = \"constants.toml\"").unwrap();
+        let constants = "extends = \"other.toml\"";
+        match run(dir.path(), OPTIONS, constants, &["-t", target]) {
+            Err(Error::ExtendsCycle(paths)) => {
+                let names: Vec<_> = paths.iter().map(|p| p.file_name().unwrap()).collect();
+                assert_eq!(names, vec!["constants.toml", "other.toml", "constants.toml"]);
+            },
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_log_level() {
+        let default = Config::from_iter(&["constcodegen"]);
+        assert_eq!(default.log_level(None), Some(log::LevelFilter::Error));
+
+        let quiet = Config::from_iter(&["constcodegen", "--quiet"]);
+        assert!(quiet.log_level(None).unwrap() <= default.log_level(None).unwrap());
+        assert_eq!(quiet.log_level(None), Some(log::LevelFilter::Off));
+        assert_eq!(quiet.log_level(Some("debug")), None);
+
+        let verbose = Config::from_iter(&["constcodegen", "-v"]);
+        assert!(log::Level::Debug <= verbose.log_level(None).unwrap());
+
+        assert!(Config::from_iter_safe(&["constcodegen", "-q", "-v"]).is_err());
+    }
 }
@@ -1,106 +1,547 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
 
-use serde::Deserialize;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 
 use crate::constants::Constant;
+use crate::expr::{evaluate, EvalError, EvalErrorMessage, Location};
 use crate::format_value::*;
+use crate::functions::Functions;
+use crate::presets;
 use crate::template;
+use crate::value::{Context, Primitive};
 
-#[derive(Debug, Deserialize, Default)]
-#[serde(default, deny_unknown_fields)]
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(try_from = "toml::Value")]
 pub struct Options {
     /// Top-level code generation options
     pub codegen: CodegenOptions,
 
     /// Per-language settings
     lang: HashMap<String, LangOptions>,
+
+    /// User-defined type presets, usable in `type_presets` of languages
+    type_preset: HashMap<String, HashMap<String, toml::Value>>,
+}
+impl TryFrom<toml::Value> for Options {
+    type Error = String;
+
+    fn try_from(mut value: toml::Value) -> Result<Self, Self::Error> {
+        presets::expand_type_presets(&mut value)?;
+        let raw: RawOptions = value.try_into().map_err(|error| error.to_string())?;
+        if raw.codegen.enabled.is_none() {
+            log::info!("No enabled languages specified, generating all defined languages");
+        }
+        Ok(Self {
+            codegen: raw.codegen,
+            lang: raw.lang,
+            type_preset: raw.type_preset,
+        })
+    }
+}
+
+/// Fields of `Options` before type presets are expanded
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawOptions {
+    codegen: CodegenOptions,
+    lang: HashMap<String, LangOptions>,
+    type_preset: HashMap<String, HashMap<String, toml::Value>>,
 }
+
 impl Options {
+    /// Parse an options file. The document is first checked as written, so that
+    /// errors point at their line and column, which is lost once type presets are
+    /// expanded. Deserializing `Options` directly reports errors without location.
+    pub fn from_slice(text: &[u8]) -> Result<Self, toml::de::Error> {
+        toml::from_slice::<RawOptions>(text)?;
+        toml::from_slice(text)
+    }
+
+    /// Options with built-in language presets, overridden by `user` options
+    pub fn with_language_presets(
+        presets: &[String], user: Option<toml::Value>,
+    ) -> Result<Self, String> {
+        let mut options = presets::language_presets(presets)?;
+        if let (toml::Value::Table(base), Some(toml::Value::Table(user))) = (&mut options, user) {
+            presets::merge(base, user);
+        }
+        Self::try_from(options)
+    }
+
+    /// Enabled languages, sorted by name
     pub fn languages(&self) -> Vec<(&String, &LangOptions)> {
-        self.lang
-            .iter()
-            .filter(|(ref name, _)| self.codegen.enabled.contains(name))
-            .collect()
+        let mut result: Vec<_> = if let Some(enabled) = &self.codegen.enabled {
+            self.lang
+                .iter()
+                .filter(|(name, _)| enabled.contains(name))
+                .collect()
+        } else {
+            self.lang.iter().collect()
+        };
+        result.sort_by_key(|(name, _)| *name);
+        result
+    }
+
+    /// Remove languages whose `condition` evaluates to false with `flags` as constants.
+    /// On failure, returns the name of the language with the invalid condition.
+    pub fn apply_conditions(&mut self, flags: &Context) -> Result<(), (String, EvalError)> {
+        let fns = Functions::default();
+        let mut disabled = Vec::new();
+        let mut languages: Vec<_> = self.lang.iter().collect();
+        languages.sort_by_key(|(name, _)| *name);
+        for (name, lang_opts) in languages {
+            if let Some(condition) = &lang_opts.condition {
+                match evaluate(condition, flags, &fns).map_err(|e| (name.clone(), e))? {
+                    Primitive::Boolean(true) => {},
+                    Primitive::Boolean(false) => disabled.push(name.clone()),
+                    other => {
+                        let message = EvalErrorMessage::InvalidArgument(format!(
+                            "Condition must be a boolean, got {:?}",
+                            other
+                        ));
+                        let location = Location::new(condition, 0, condition.len());
+                        return Err((name.clone(), location.error_here(message)));
+                    },
+                }
+            }
+        }
+        for name in disabled {
+            log::info!("Skipping language {}, as its condition is false", name);
+            self.lang.remove(&name);
+        }
+        Ok(())
+    }
+
+    /// Effective options, including defaults, as TOML
+    pub fn to_toml(&self) -> String {
+        toml::Value::try_from(self)
+            .expect("Options are always representable in TOML")
+            .to_string()
     }
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct CodegenOptions {
-    /// Languages to generate files for
-    enabled: Vec<String>,
+    /// Languages to generate files for.
+    /// All defined languages are generated if this is None.
+    #[serde(default)]
+    enabled: Option<Vec<String>>,
 
     // Comment sections
     #[serde(default)]
     pub comment_sections: bool,
+
+    /// Texts of the section comments
+    #[serde(default)]
+    pub section_labels: SectionLabels,
+
+    /// Resolve a relative target directory against the directory of the
+    /// options file instead of the working directory
+    #[serde(default)]
+    pub paths_relative_to_config: bool,
+
+    /// Append a checksum of the resolved constants as a comment to each file,
+    /// so that hand-edits of generated files can be detected.
+    /// Languages without a `comment` template get no checksum.
+    #[serde(default)]
+    pub checksum: bool,
+
+    /// End each non-empty file with exactly one line break if true, or none if false
+    #[serde(default = "default_true")]
+    pub final_newline: bool,
+
+    /// Emit the `value` expression of each constant as a comment.
+    /// Languages without comment templates are not affected.
+    #[serde(default)]
+    pub show_expression: bool,
+
+    /// Seconds to wait for a formatter or `value_command` before killing it, forever if None
+    #[serde(default)]
+    pub formatter_timeout: Option<f64>,
+
+    /// Metadata available to intro and outro templates as `$stamp_KEY`,
+    /// extended by `--stamp KEY=VALUE`
+    #[serde(default)]
+    pub stamp: BTreeMap<String, String>,
+
+    /// Allow running the `value_command` of types. Only set by `--allow-commands`,
+    /// so that an options file alone cannot run arbitrary commands.
+    #[serde(skip)]
+    pub allow_commands: bool,
+
+    /// Blank lines between the file description, imports, intro, constants and outro.
+    /// Empty sections get no blank lines.
+    #[serde(default)]
+    pub section_spacing: u8,
+
+    /// Order of constants in generated files
+    #[serde(default)]
+    pub sort: ConstantSort,
+
+    /// Constants ordered by `sort` relative to each other
+    #[serde(default)]
+    pub sort_scope: SortScope,
+}
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        Self {
+            enabled: None,
+            comment_sections: false,
+            section_labels: SectionLabels::default(),
+            paths_relative_to_config: false,
+            checksum: false,
+            final_newline: true,
+            show_expression: false,
+            formatter_timeout: None,
+            stamp: BTreeMap::new(),
+            allow_commands: false,
+            section_spacing: 0,
+            sort: ConstantSort::default(),
+            sort_scope: SortScope::default(),
+        }
+    }
+}
+
+impl CodegenOptions {
+    /// Negative timeouts are zero, and timeouts too long to represent are infinite
+    pub fn formatter_timeout(&self) -> Option<Duration> {
+        self.formatter_timeout
+            .and_then(|secs| Duration::try_from_secs_f64(secs.max(0.0)).ok())
+    }
+
+    /// How `value_command`s of types are run
+    pub fn commands(&self) -> Commands {
+        Commands {
+            allowed: self.allow_commands,
+            timeout: self.formatter_timeout(),
+        }
+    }
+}
+
+/// Whether and how the `value_command`s of types are run
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Commands {
+    /// Formatting a constant that needs a command fails if not allowed
+    pub allowed: bool,
+    /// Commands taking longer are killed
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SectionLabels {
+    pub imports: String,
+    pub start_body: String,
+    pub constants: String,
+    pub end_body: String,
+}
+impl Default for SectionLabels {
+    fn default() -> Self {
+        Self {
+            imports: "Imports".to_owned(),
+            start_body: "Start body block".to_owned(),
+            constants: "Constants".to_owned(),
+            end_body: "End body block".to_owned(),
+        }
+    }
+}
+
+/// Reasons a single constant cannot be rendered for a language
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConstantError {
+    /// Template uses `$type`, but the constant doesn't have one
+    TypeRequired,
+    Format(FormatError),
+    /// The `value_command` of the type failed
+    Command(String),
+    /// The type has a `value_command`, but running commands is not allowed
+    CommandsNotAllowed(String),
+    /// Output name fails `ident_pattern` or is one of `reserved_words`
+    InvalidIdentifier(String),
+}
+impl fmt::Display for ConstantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::TypeRequired => write!(f, "Type required"),
+            Self::Format(error) => write!(f, "{}", error),
+            Self::Command(message) => write!(f, "Value command failed: {}", message),
+            Self::CommandsNotAllowed(type_) => {
+                write!(f, "Type {:?} has a value_command, but commands are not allowed", type_)
+            },
+            Self::InvalidIdentifier(name) => write!(f, "{:?} is not a valid identifier", name),
+        }
+    }
 }
 
 /// Options for a single programming language or other data format
 /// All templates described here are always followed by a linebreak
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 #[serde(deny_unknown_fields)]
 pub struct LangOptions {
     /// File extension for this language
     pub file_ext: String,
 
+    /// Expression deciding whether this language is generated,
+    /// with build flags given as seeded constants
+    #[serde(default)]
+    condition: Option<String>,
+
     /// Template for generating a single constant.
+    /// `$count` is the number of elements for lists, and one for other values.
+    /// `$type` is the `name` of the type options, or the type of the constant
+    /// verbatim if no such options or name exist.
     template: String,
 
-    /// Template for importing a dependency.
+    /// Template for importing a dependency, with `$import` as the dependency.
+    /// `$stem`, `$ext` and `$module_dir`, the directory of the stem, are available
+    /// for imports relative to the output file.
     /// Dependencies in types are not allowed if this is None.
     #[serde(default)]
     import: Option<String>,
 
+    /// Path separator of dependencies, e.g. `.` for Python or `/` for TypeScript.
+    /// Separators between module segments are replaced with this, and relative paths
+    /// use the matching prefix, e.g. `..` or `../`. Segments are separated by `/`
+    /// in paths containing one, so that `./types.js` keeps its extension, and by `.`
+    /// otherwise. Absolute paths are kept as is.
+    #[serde(default)]
+    import_separator: Option<String>,
+
+    /// Dependencies not starting with `.` or `/` are paths relative to the target
+    /// directory, and are imported relative to the directory of the output file,
+    /// e.g. `types/units` as `../types/units` with the stem `api/constants`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    relative_imports: bool,
+
+    /// Emit the imports section.
+    /// Type dependencies are silently dropped if this is false.
+    #[serde(default = "default_true")]
+    pub emit_imports: bool,
+
+    /// Order of the imports section, which never contains duplicates
+    #[serde(default)]
+    pub import_sort: ImportSort,
+
     /// Template for a comment.
     /// Comments are not emitted if this is None.
     #[serde(default)]
     comment: Option<String>,
 
-    /// Template for the start of the constants block
+    /// Template for a comment after a constant on the same line, used for notes.
+    /// Notes are emitted on the preceding line using `comment` if this is None.
+    #[serde(default)]
+    inline_comment: Option<String>,
+
+    /// Description emitted as comments at the top of the file,
+    /// regardless of `comment_sections`
+    #[serde(default)]
+    file_doc: Option<String>,
+
+    /// Template for the start of the constants block.
+    /// `$stem` and `$ext` are the parts of the output filename,
+    /// and each `stamp` entry is available as `$stamp_KEY`.
     #[serde(default)]
     intro: Option<String>,
 
-    /// Template for the end of the constants block
+    /// Template for the end of the constants block, with the parameters of `intro`
     #[serde(default)]
     outro: Option<String>,
 
+    /// Template for the start of an enum, with `$enum` as its name
+    #[serde(default)]
+    enum_intro: Option<String>,
+
+    /// Template for a single enum member, with the parameters of `template`.
+    /// Constants with `enum` set are emitted as plain constants if this is None.
+    #[serde(default)]
+    enum_member: Option<String>,
+
+    /// Template for the end of an enum, with `$enum` as its name
+    #[serde(default)]
+    enum_outro: Option<String>,
+
+    /// Written after every plain constant but the last one, e.g. `,` for JSON objects.
+    /// Enums are not separated from the surrounding constants.
+    #[serde(default)]
+    pub constant_separator: Option<String>,
+
     /// Literal formatting
     #[serde(default)]
     format: Format,
 
+    /// Output names of constants whose name is not usable as is, e.g. reserved words
+    #[serde(default)]
+    rename: HashMap<String, String>,
+
+    /// Convert output names of constants to this case, unless given in `rename`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name_case: Option<CaseStyle>,
+
+    /// Added to output names of constants after `rename` or `name_case`, e.g. `MYLIB_`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    name_prefix: String,
+
+    /// Appended to output names of constants, like `name_prefix`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    name_suffix: String,
+
+    /// Regex that output names of constants must match as a whole,
+    /// e.g. `[A-Za-z_][A-Za-z0-9_]*`
+    #[serde(default, with = "ident_pattern", skip_serializing_if = "Option::is_none")]
+    ident_pattern: Option<Regex>,
+
+    /// Output names of constants that are rejected, e.g. keywords of the language
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reserved_words: Vec<String>,
+
+    /// Literals replacing integer values verbatim, e.g. `UINT32_MAX` for `0xffffffff`.
+    /// Keys are integer literals in expression syntax.
+    #[serde(default, with = "special_values")]
+    special_values: BTreeMap<i128, String>,
+
     /// Formatter command.
     /// Must accept input from stdin and output formatted code to stdout.
     #[serde(default)]
     pub formatter: Option<Vec<String>>,
 
+    /// Presets of type options, see `presets.rs` for the built-in ones.
+    /// Options in `types` override those from presets.
+    #[serde(default)]
+    type_presets: Vec<String>,
+
     /// Types
     #[serde(default, rename = "type")]
     pub types: HashMap<String, LangTypeOptions>,
 }
 impl LangOptions {
-    /// Returns None if `type` field is required but `None`
-    pub fn format_constant(&self, constant: &Constant) -> Option<String> {
+    /// `lang_name` selects the value override of the constant, if any
+    pub fn format_constant(
+        &self, lang_name: &str, constant: &Constant,
+    ) -> Result<String, ConstantError> {
+        self.format_constant_with(lang_name, constant, Commands::default())
+    }
+
+    /// Like `format_constant`, running the `value_command` of its type if `commands` allows
+    pub fn format_constant_with(
+        &self, lang_name: &str, constant: &Constant, commands: Commands,
+    ) -> Result<String, ConstantError> {
+        let t_ctx = self.constant_context(lang_name, &self.template, constant, commands)?;
+        Ok(self.with_note(template::replace_parameters(&self.template, &t_ctx), constant))
+    }
+
+    /// Attach the note of the constant to its formatted line
+    fn with_note(&self, line: String, constant: &Constant) -> String {
+        match &constant.note {
+            Some(note) => self.with_comment(line, note),
+            None => line,
+        }
+    }
+
+    /// Attach the expression of the constant to its formatted line, for `show_expression`
+    pub fn with_expression(&self, line: String, constant: &Constant) -> String {
+        match constant.expression() {
+            Some(expression) => self.with_comment(line, expression),
+            None => line,
+        }
+    }
+
+    /// Comment on the same line if possible, otherwise on the preceding line
+    fn with_comment(&self, line: String, comment: &str) -> String {
+        match &self.inline_comment {
+            Some(inline) => {
+                let mut t_ctx = HashMap::new();
+                t_ctx.insert("$comment", comment.to_owned());
+                format!("{} {}", line, template::replace_parameters(inline, &t_ctx))
+            },
+            None => format!("{}{}", self.format_comment(comment), line),
+        }
+    }
+
+    /// Format of the language, overridden by the type and then by the constant itself
+    fn constant_format(&self, constant: &Constant) -> Format {
+        let type_opts = constant.type_.as_ref().and_then(|t| self.types.get(t));
+        let mut format = type_opts
+            .map(|t_opts| self.format.merged(&t_opts.format))
+            .unwrap_or_else(|| self.format.clone());
+        if let Some(literal_format) = &constant.literal_format {
+            format = format.merged(literal_format);
+        }
+        format
+    }
+
+    /// Template parameters for a single constant.
+    /// Value overrides and special values are used verbatim, without type-specific formatting.
+    fn constant_context(
+        &self, lang_name: &str, template: &str, constant: &Constant, commands: Commands,
+    ) -> Result<HashMap<&'static str, String>, ConstantError> {
         let mut t_ctx = HashMap::new();
-        t_ctx.insert("$name", constant.name.clone());
-        t_ctx.insert(
-            "$value",
-            constant
-                .type_
-                .clone()
-                .and_then(|t| self.types.get(&t))
-                .map(|t_opts| t_opts.format.clone())
-                .unwrap_or_else(|| self.format.clone())
-                .format(&constant.value()),
-        );
-
-        if template::contains_parameter(&self.template, "$type") {
-            let type_ = constant.type_.clone()?;
+        let name = match (self.rename.get(&constant.name), self.name_case) {
+            (Some(name), _) => name.clone(),
+            (None, Some(case)) => case.convert(&constant.name),
+            (None, None) => constant.name.clone(),
+        };
+        let name = format!("{}{}{}", self.name_prefix, name, self.name_suffix);
+        if !self.is_valid_identifier(&name) {
+            return Err(ConstantError::InvalidIdentifier(name));
+        }
+        t_ctx.insert("$name", name);
+        t_ctx.insert("$count", constant.value().count().to_string());
+
+        let value_override = constant.overrides.get(lang_name);
+        let type_opts = constant.type_.as_ref().and_then(|t| self.types.get(t));
+        let special_value = match constant.value() {
+            Primitive::Integer(v) => self.special_values.get(&v),
+            _ => None,
+        };
+        if let Some(value) = value_override.or(special_value) {
+            t_ctx.insert("$value", value.clone());
+        } else {
+            let format = match type_opts {
+                Some(t_opts) if t_opts.bits.is_some() => {
+                    self.constant_format(constant).without_bits()
+                },
+                _ => self.constant_format(constant),
+            };
+            // Numbers already in the parentheses of `value_prefix` and `value_suffix`
+            let wrapped = type_opts.is_some_and(LangTypeOptions::wraps_value)
+                && template::contains_parameter(template, "$type")
+                && !matches!(constant.value(), Primitive::List(_));
+            let format = if wrapped { format.without_parentheses() } else { format };
+            let value = match type_opts {
+                Some(t_opts) => t_opts
+                    .fit_formatted(&constant.value(), &format)
+                    .map_err(ConstantError::Format)?,
+                None => constant.value(),
+            };
+            let mut formatted = format.format(&value).map_err(ConstantError::Format)?;
+            if let Some(cmd) = type_opts.and_then(|t_opts| t_opts.value_command.as_ref()) {
+                if !commands.allowed {
+                    let type_ = constant.type_.clone().expect("Has type options");
+                    return Err(ConstantError::CommandsNotAllowed(type_));
+                }
+                formatted = crate::run_command(cmd, &formatted, commands.timeout)
+                    .map_err(|error| ConstantError::Command(error.to_string()))?;
+                if formatted.ends_with('\n') {
+                    formatted.pop();
+                }
+            }
+            t_ctx.insert("$value", formatted);
+        }
+
+        if template::contains_parameter(template, "$type") {
+            let type_ = constant.type_.clone().ok_or(ConstantError::TypeRequired)?;
             t_ctx.insert("$type", type_.clone());
-            if let Some(type_opts) = self.types.get(&type_) {
+            if let Some(type_opts) = type_opts {
                 if let Some(type_name) = &type_opts.name {
                     t_ctx.insert("$type", type_name.clone());
                 }
-
+            }
+            if let (Some(type_opts), None, None) = (type_opts, value_override, special_value) {
                 let old_value = t_ctx["$value"].clone();
                 t_ctx.insert(
                     "$value",
@@ -112,13 +553,63 @@ impl LangOptions {
             }
         }
 
-        Some(template::replace_parameters(&self.template, &t_ctx))
+        Ok(t_ctx)
     }
 
-    /// Returns None if the language doesn't support imports
-    pub fn format_import(&self, import: &str) -> Option<String> {
+    /// Whether `name` matches `ident_pattern` and is not one of `reserved_words`
+    fn is_valid_identifier(&self, name: &str) -> bool {
+        self.ident_pattern.as_ref().map_or(true, |pattern| pattern.is_match(name))
+            && !self.reserved_words.iter().any(|word| word == name)
+    }
+
+    pub fn supports_enums(&self) -> bool {
+        self.enum_member.is_some()
+    }
+
+    pub fn format_enum_intro(&self, enum_name: &str) -> String {
+        let mut t_ctx = HashMap::new();
+        t_ctx.insert("$enum", enum_name.to_owned());
+        self.enum_intro
+            .clone()
+            .map(|c| format!("{}\n", template::replace_parameters(&c, &t_ctx)))
+            .unwrap_or_default()
+    }
+
+    /// Uses `template` if the language doesn't support enums
+    pub fn format_enum_member(
+        &self, lang_name: &str, enum_name: &str, constant: &Constant, commands: Commands,
+    ) -> Result<String, ConstantError> {
+        let member = self.enum_member.as_ref().unwrap_or(&self.template);
+        let mut t_ctx = self.constant_context(lang_name, member, constant, commands)?;
+        t_ctx.insert("$enum", enum_name.to_owned());
+        Ok(self.with_note(template::replace_parameters(member, &t_ctx), constant))
+    }
+
+    pub fn format_enum_outro(&self, enum_name: &str) -> String {
         let mut t_ctx = HashMap::new();
-        t_ctx.insert("$import", import.to_owned());
+        t_ctx.insert("$enum", enum_name.to_owned());
+        self.enum_outro
+            .clone()
+            .map(|c| format!("{}\n", template::replace_parameters(&c, &t_ctx)))
+            .unwrap_or_default()
+    }
+
+    /// Returns None if the language doesn't support imports
+    pub fn format_import(&self, import: &str, stem: &str) -> Option<String> {
+        let mut t_ctx = self.file_context(stem);
+        let relative;
+        let import = if self.relative_imports && !import.starts_with(&['.', '/'][..]) {
+            let path = normalize_separators(import, "/");
+            relative = relative_path(&path, module_dir(stem));
+            relative.as_str()
+        } else {
+            import
+        };
+        let import = match &self.import_separator {
+            Some(separator) => normalize_separators(import, separator),
+            None => import.to_owned(),
+        };
+        t_ctx.insert("$import", import);
         let im = self.import.clone()?;
         Some(template::replace_parameters(&im, &t_ctx))
     }
@@ -129,51 +620,1238 @@ impl LangOptions {
         self.comment
             .clone()
             .map(|c| format!("{}\n", template::replace_parameters(&c, &t_ctx)))
-            .unwrap_or_else(String::new)
+            .unwrap_or_default()
     }
 
-    pub fn format_intro(&self) -> String {
-        let t_ctx = HashMap::new();
-        self.intro
-            .clone()
-            .map(|c| format!("{}\n", template::replace_parameters(&c, &t_ctx)))
-            .unwrap_or_else(String::new)
+    /// Each line of the file description as a comment
+    pub fn format_file_doc(&self) -> String {
+        self.file_doc
+            .iter()
+            .flat_map(|doc| doc.lines())
+            .map(|line| self.format_comment(line))
+            .collect()
     }
 
-    pub fn format_outro(&self) -> String {
-        let t_ctx = HashMap::new();
-        self.outro
-            .clone()
-            .map(|c| format!("{}\n", template::replace_parameters(&c, &t_ctx)))
-            .unwrap_or_else(String::new)
+    /// Parameters for the intro, outro and import templates
+    fn file_context(&self, stem: &str) -> HashMap<&'static str, String> {
+        let mut t_ctx = HashMap::new();
+        t_ctx.insert("$stem", stem.to_owned());
+        t_ctx.insert("$ext", self.file_ext.clone());
+        t_ctx.insert("$module_dir", module_dir(stem).to_owned());
+        t_ctx
     }
 
+    /// Fails with the key of a `$stamp_KEY` parameter missing from `stamp`
+    pub fn format_intro(
+        &self, stem: &str, stamp: &BTreeMap<String, String>,
+    ) -> Result<String, String> {
+        self.format_file_template(&self.intro, stem, stamp)
+    }
+
+    /// Fails like `format_intro`
+    pub fn format_outro(
+        &self, stem: &str, stamp: &BTreeMap<String, String>,
+    ) -> Result<String, String> {
+        self.format_file_template(&self.outro, stem, stamp)
+    }
+
+    fn format_file_template(
+        &self, template: &Option<String>, stem: &str, stamp: &BTreeMap<String, String>,
+    ) -> Result<String, String> {
+        let template = match template {
+            Some(template) => template,
+            None => return Ok(String::new()),
+        };
+        let missing = template::parameters(template)
+            .filter_map(|name| name.strip_prefix("$stamp_"))
+            .find(|key| !stamp.contains_key(*key));
+        if let Some(key) = missing {
+            return Err(key.to_owned());
+        }
+        let stamp_params: Vec<_> =
+            stamp.iter().map(|(k, v)| (format!("$stamp_{}", k), v)).collect();
+        let mut t_ctx: HashMap<&str, String> = self.file_context(stem);
+        for (param, value) in &stamp_params {
+            t_ctx.insert(param, (*value).clone());
+        }
+        Ok(format!("{}\n", template::replace_parameters(template, &t_ctx)))
+    }
+
+    /// Imports required by all constants, without duplicates, ordered by `import_sort`
+    pub fn imports(&self, constants: &[Constant]) -> Vec<String> {
+        let mut imports: Vec<String> = Vec::new();
+        for import in constants.iter().flat_map(|c| self.constant_imports(c)) {
+            if !imports.contains(&import) {
+                imports.push(import);
+            }
+        }
+        if self.import_sort == ImportSort::Sorted {
+            imports.sort();
+        }
+        imports
+    }
+
+    /// Types of constants without an entry in `types`, sorted and without duplicates.
+    /// Always empty for languages that define no types, as they ignore them.
+    pub fn unknown_types<'a>(&self, constants: &'a [Constant]) -> Vec<&'a str> {
+        if self.types.is_empty() {
+            return Vec::new();
+        }
+        let mut unknown: Vec<&str> = constants
+            .iter()
+            .filter_map(|c| c.type_.as_deref())
+            .filter(|t| !self.types.contains_key(*t))
+            .collect();
+        unknown.sort_unstable();
+        unknown.dedup();
+        unknown
+    }
+
+    /// Imports of the type of `constant`, in the order the type lists them,
+    /// followed by those of the boolean format if the value contains a boolean
     pub fn constant_imports(&self, constant: &Constant) -> Vec<String> {
+        let mut imports = Vec::new();
         if let Some(type_) = constant.type_.clone() {
             if let Some(type_opts) = self.types.get(&type_) {
-                return type_opts.import.clone();
+                imports.extend(type_opts.import.iter().cloned());
+            }
+        }
+        if constant.value().contains_boolean() {
+            if let Some(boolean) = self.constant_format(constant).boolean {
+                for import in boolean.import {
+                    if !imports.contains(&import) {
+                        imports.push(import);
+                    }
+                }
+            }
+        }
+        imports
+    }
+}
+
+/// Replace the separators between path segments with `separator`. Segments are
+/// separated by `/` if the path contains one, and by `.` otherwise. A relative
+/// prefix, either `./` and `../` or Python-style leading dots, is converted
+/// to dots if `separator` is `.` and to `./` or `../` otherwise.
+/// Absolute paths are returned as is.
+fn normalize_separators(path: &str, separator: &str) -> String {
+    if path.starts_with('/') {
+        return path.to_owned();
+    }
+    let rest = path.trim_start_matches(&['.', '/'][..]);
+    let prefix = &path[..path.len() - rest.len()];
+    // Number of parent directories, None if not relative
+    let levels = if prefix.contains('/') {
+        Some(prefix.matches("..").count())
+    } else if !prefix.is_empty() {
+        Some(prefix.len() - 1)
+    } else {
+        None
+    };
+    let prefix = match (levels, separator) {
+        (None, _) => String::new(),
+        (Some(levels), ".") => ".".repeat(levels + 1),
+        (Some(0), _) => "./".to_owned(),
+        (Some(levels), _) => "../".repeat(levels),
+    };
+    let segments: Vec<&str> = rest.split(if path.contains('/') { '/' } else { '.' }).collect();
+    format!("{}{}", prefix, segments.join(separator))
+}
+
+/// Directory of the output file relative to the target directory, empty if none
+fn module_dir(stem: &str) -> &str {
+    stem.rfind('/').map_or("", |end| &stem[..end])
+}
+
+/// `path` relative to `dir`, both `/`-separated and relative to the same directory
+fn relative_path(path: &str, dir: &str) -> String {
+    fn segments(text: &str) -> Vec<&str> {
+        text.split('/').filter(|s| !s.is_empty() && *s != ".").collect()
+    }
+    let (path, dir) = (segments(path), segments(dir));
+    let common = path.iter().zip(&dir).take_while(|(a, b)| a == b).count();
+    let prefix = match dir.len() - common {
+        0 => "./".to_owned(),
+        levels => "../".repeat(levels),
+    };
+    format!("{}{}", prefix, path[common..].join("/"))
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// `special_values` as a table, as TOML keys are always strings
+mod special_values {
+    use std::collections::BTreeMap;
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::expr::parse_literal;
+    use crate::value::Primitive;
+
+    pub fn serialize<S: Serializer>(
+        values: &BTreeMap<i128, String>, serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(values.iter().map(|(k, v)| (k.to_string(), v)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<i128, String>, D::Error> {
+        let table = BTreeMap::<String, String>::deserialize(deserializer)?;
+        let mut values = BTreeMap::new();
+        for (key, literal) in table {
+            match parse_literal(&key) {
+                Ok(Primitive::Integer(value)) => {
+                    values.insert(value, literal);
+                },
+                _ => {
+                    return Err(D::Error::custom(format!(
+                        "Special value key {:?} is not an integer literal",
+                        key
+                    )));
+                },
             }
         }
-        Vec::new()
+        Ok(values)
+    }
+}
+
+/// `ident_pattern` as text, anchored to match whole names once compiled
+mod ident_pattern {
+    use regex::Regex;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    const PREFIX: &str = "^(?:";
+    const SUFFIX: &str = ")$";
+
+    pub fn serialize<S: Serializer>(
+        pattern: &Option<Regex>, serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match pattern {
+            Some(regex) => {
+                let text = regex.as_str();
+                serializer.serialize_str(&text[PREFIX.len()..text.len() - SUFFIX.len()])
+            },
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Regex>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        Regex::new(&format!("{}{}{}", PREFIX, text, SUFFIX))
+            .map(Some)
+            .map_err(|error| D::Error::custom(format!("Invalid ident_pattern: {}", error)))
     }
 }
 
 /// Additional formatting for a single type in some language
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 #[serde(default, deny_unknown_fields)]
 pub struct LangTypeOptions {
     /// Use a different name for the type
     pub name: Option<String>,
 
-    /// Prefix when using a value
+    /// Prefix when using a value. If this ends with `(` and `value_suffix` starts
+    /// with `)`, negative numbers are not parenthesized again by `negative_style`.
     pub value_prefix: String,
 
     /// Suffix when using a value
     pub value_suffix: String,
 
-    /// Override literal formatting
+    /// Override literal formatting, falling back to the language format
+    /// for the kinds of values not set here
     pub format: Format,
 
     /// Requires these dependencies imported to use
     pub import: Vec<String>,
+
+    /// Width of integer values in bits between 1 and 128, unlimited if None
+    #[serde(deserialize_with = "deserialize_bits", skip_serializing_if = "Option::is_none")]
+    pub bits: Option<u8>,
+
+    /// Whether integer values can be negative
+    pub signed: bool,
+
+    /// Handling of integer values outside the range given by `bits`
+    pub on_overflow: OverflowMode,
+
+    /// Command the formatted value is piped through, using its output without
+    /// a trailing line break instead. Only run with `--allow-commands`.
+    pub value_command: Option<Vec<String>>,
+}
+impl LangTypeOptions {
+    /// Whether `value_prefix` and `value_suffix` put the value in parentheses,
+    /// e.g. `Offset(` and `)`
+    fn wraps_value(&self) -> bool {
+        self.value_prefix.ends_with('(') && self.value_suffix.starts_with(')')
+    }
+
+    /// Apply the range of the type to integers, including those in lists
+    pub fn fit(&self, value: &Primitive) -> Result<Primitive, FormatError> {
+        let bits = match self.bits {
+            Some(bits) => bits as u32,
+            None => return Ok(value.clone()),
+        };
+        Ok(match value {
+            Primitive::Integer(v) => {
+                let (min, max) = match (self.signed, bits) {
+                    (true, 128) => (i128::MIN, i128::MAX),
+                    (true, _) => (-(1 << (bits - 1)), (1 << (bits - 1)) - 1),
+                    (false, 127..=128) => (0, i128::MAX),
+                    (false, _) => (0, (1 << bits) - 1),
+                };
+                Primitive::Integer(match self.on_overflow {
+                    _ if (min..=max).contains(v) => *v,
+                    OverflowMode::Error => {
+                        return Err(FormatError::OutOfRange {
+                            value: *v,
+                            bits: bits as u8,
+                            signed: self.signed,
+                        });
+                    },
+                    OverflowMode::Saturate => (*v).clamp(min, max),
+                    OverflowMode::Wrap => {
+                        // Truncate to the width, then sign- or zero-extend
+                        let shift = 128 - bits;
+                        if self.signed {
+                            (v << shift) >> shift
+                        } else {
+                            (((*v as u128) << shift) >> shift) as i128
+                        }
+                    },
+                })
+            },
+            Primitive::List(items) => {
+                Primitive::List(items.iter().map(|item| self.fit(item)).collect::<Result<_, _>>()?)
+            },
+            other => other.clone(),
+        })
+    }
+
+    /// Like `fit`, but if `format` uses the two's complement negative style, negative
+    /// integers are rendered as two's complement at the width of the type, such as
+    /// `-1` as `0xffff_ffff` for a 32-bit type. For unsigned types, this only applies
+    /// to values that would be out of range otherwise and fit the signed range.
+    pub fn fit_formatted(
+        &self, value: &Primitive, format: &Format,
+    ) -> Result<Primitive, FormatError> {
+        let bits = match self.bits {
+            Some(bits) if bits < 128 && format.is_twos_complement() => bits as u32,
+            _ => return self.fit(value),
+        };
+        Ok(match value {
+            Primitive::List(items) => Primitive::List(
+                items
+                    .iter()
+                    .map(|item| self.fit_formatted(item, format))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Primitive::Integer(v)
+                if !self.signed
+                    && matches!(self.on_overflow, OverflowMode::Error)
+                    && (-(1 << (bits - 1))..0).contains(v) =>
+            {
+                Primitive::Integer(v + (1 << bits))
+            },
+            other => match self.fit(other)? {
+                Primitive::Integer(v) if v < 0 => Primitive::Integer(v + (1 << bits)),
+                fitted => fitted,
+            },
+        })
+    }
+}
+
+/// Order of imports in the generated file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum ImportSort {
+    /// Alphabetical
+    #[serde(alias = "sorted")]
+    #[default]
+    Sorted,
+    /// In order of first use: by constant, then in the order the type lists them
+    #[serde(alias = "as_is")]
+    AsIs,
+}
+
+/// Case of output names of constants
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CaseStyle {
+    /// `max_buffer_size`
+    #[serde(alias = "snake")]
+    Snake,
+    /// `MAX_BUFFER_SIZE`
+    #[serde(alias = "screaming_snake")]
+    ScreamingSnake,
+    /// `maxBufferSize`
+    #[serde(alias = "camel")]
+    Camel,
+    /// `MaxBufferSize`
+    #[serde(alias = "pascal")]
+    Pascal,
+    /// `max-buffer-size`
+    #[serde(alias = "kebab")]
+    Kebab,
+}
+impl CaseStyle {
+    /// Words of `name` are separated by `_` and `-`, and at changes
+    /// from lowercase or digits to uppercase, as in `maxBufferSize`
+    pub fn convert(self, name: &str) -> String {
+        let words = split_words(name);
+        let capitalize = |word: &str| {
+            let mut chars = word.chars();
+            chars.next().map_or_else(String::new, |first| {
+                first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect()
+            })
+        };
+        match self {
+            Self::Snake => words.join("_").to_lowercase(),
+            Self::ScreamingSnake => words.join("_").to_uppercase(),
+            Self::Kebab => words.join("-").to_lowercase(),
+            Self::Pascal => words.iter().map(|w| capitalize(w)).collect(),
+            Self::Camel => words
+                .iter()
+                .enumerate()
+                .map(|(i, w)| if i == 0 { w.to_lowercase() } else { capitalize(w) })
+                .collect(),
+        }
+    }
+}
+
+/// Words of a constant name, see `CaseStyle::convert`
+fn split_words(name: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut previous: Option<char> = None;
+    for (i, c) in name.char_indices() {
+        if c == '_' || c == '-' {
+            if let Some(s) = start.take() {
+                words.push(&name[s..i]);
+            }
+        } else {
+            let boundary = c.is_uppercase()
+                && previous.is_some_and(|p| p.is_lowercase() || p.is_numeric());
+            match start {
+                Some(s) if boundary => {
+                    words.push(&name[s..i]);
+                    start = Some(i);
+                },
+                Some(_) => {},
+                None => start = Some(i),
+            }
+        }
+        previous = Some(c);
+    }
+    if let Some(s) = start {
+        words.push(&name[s..]);
+    }
+    words
+}
+
+/// Order of constants in generated files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum ConstantSort {
+    /// As in the constants files
+    #[serde(alias = "declaration")]
+    #[default]
+    Declaration,
+    /// Numbers in ascending order, followed by other values in declaration order
+    #[serde(alias = "value")]
+    Value,
+}
+
+/// Constants that `ConstantSort` orders relative to each other
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum SortScope {
+    /// All constants, with each enum placed at its first member
+    #[serde(alias = "global")]
+    #[default]
+    Global,
+    /// Members within each enum, with constants and enums in declaration order
+    #[serde(alias = "per_group")]
+    PerGroup,
+}
+
+/// Handling of integers that don't fit their type
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum OverflowMode {
+    #[serde(alias = "error")]
+    #[default]
+    Error,
+    /// Clamp to the minimum or maximum of the type
+    #[serde(alias = "saturate")]
+    Saturate,
+    /// Keep the lowest bits, as two's complement for signed types
+    #[serde(alias = "wrap")]
+    Wrap,
+}
+
+#[cfg(test)]
+mod test_options {
+    use super::{
+        module_dir, normalize_separators, relative_path, split_words, CaseStyle, ConstantError,
+        Context, EvalErrorMessage, FormatError, Options, Primitive,
+    };
+    use crate::{resolve, Constant, Constants};
+
+    fn constants(text: &str) -> Vec<Constant> {
+        let mut constants = toml::from_str::<Constants>(text).unwrap().constants;
+        resolve(&mut constants, &[]).unwrap();
+        constants
+    }
+
+    const LANGUAGES: &str = r#"
+        [lang.python]
+        file_ext = ".py"
+        template = "$name = $value"
+
+        [lang.nasm]
+        file_ext = ".asm"
+        template = "%define $name $value"
+    "#;
+
+    fn language_names(options: &Options) -> Vec<String> {
+        options
+            .languages()
+            .into_iter()
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_enabled_default_all() {
+        let options: Options = toml::from_str(LANGUAGES).unwrap();
+        assert_eq!(language_names(&options), vec!["nasm", "python"]);
+
+        let options: Options =
+            toml::from_str(&format!("[codegen]\ncomment_sections = true\n{}", LANGUAGES))
+                .unwrap();
+        assert_eq!(language_names(&options), vec!["nasm", "python"]);
+    }
+
+    #[test]
+    fn test_enabled_explicit() {
+        let options: Options =
+            toml::from_str(&format!("[codegen]\nenabled = [\"nasm\"]\n{}", LANGUAGES)).unwrap();
+        assert_eq!(language_names(&options), vec!["nasm"]);
+
+        let options: Options =
+            toml::from_str(&format!("[codegen]\nenabled = []\n{}", LANGUAGES)).unwrap();
+        assert!(language_names(&options).is_empty());
+    }
+
+    #[test]
+    fn test_languages_sorted() {
+        let mut text = String::new();
+        for name in &["zig", "c", "python", "asm", "rust", "nasm", "go"] {
+            text.push_str(&format!(
+                "[lang.{}]\nfile_ext = \".{}\"\ntemplate = \"$name\"\n",
+                name, name
+            ));
+        }
+        let options: Options = toml::from_str(&text).unwrap();
+        assert_eq!(language_names(&options), vec![
+            "asm", "c", "go", "nasm", "python", "rust", "zig"
+        ]);
+    }
+
+    #[test]
+    fn test_to_toml_round_trip() {
+        let options: Options = toml::from_str(&format!(
+            r##"
+            [codegen]
+            enabled = ["python", "rust"]
+            section_labels = {{ start_body = "Alku" }}
+            {}
+            [lang.rust]
+            file_ext = ".rs"
+            template = "const $name: $type = $value;"
+            comment = "// $comment"
+            format.boolean = {{ true = "1", false = "0" }}
+            format.list = {{ open = "&[", separator = ",", close = "]" }}
+
+            special_values = {{ "-1" = "ALL" }}
+            ident_pattern = "[A-Za-z_][A-Za-z0-9_]*"
+
+            [lang.rust.type.Addr]
+            bits = 16
+            on_overflow = "wrap"
+            format.integer = {{ radix = "hex", underscores = 4 }}
+            format.float = {{ fixed_point = {{ scale_bits = 8 }} }}
+            "##,
+            LANGUAGES
+        ))
+        .unwrap();
+
+        let text = options.to_toml();
+        assert!(text.contains("emit_imports = true"), "{}", text);
+        assert!(text.contains("end_body = \"End body block\""), "{}", text);
+        assert!(text.contains("radix = \"Hexadecimal\""), "{}", text);
+        assert!(text.contains("-1 = \"ALL\""), "{}", text);
+        assert!(text.contains("ident_pattern = \"[A-Za-z_][A-Za-z0-9_]*\""), "{}", text);
+
+        let reparsed: Options = toml::from_str(&text).unwrap();
+        assert_eq!(reparsed.to_toml(), text);
+        assert_eq!(language_names(&reparsed), vec!["python", "rust"]);
+    }
+
+    #[test]
+    fn test_normalize_separators() {
+        assert_eq!(normalize_separators("pkg/sub/mod", "."), "pkg.sub.mod");
+        assert_eq!(normalize_separators("pkg.sub.mod", "/"), "pkg/sub/mod");
+        assert_eq!(normalize_separators("./units/size", "."), ".units.size");
+        assert_eq!(normalize_separators("../../units", "."), "...units");
+        assert_eq!(normalize_separators(".units.size", "/"), "./units/size");
+        assert_eq!(normalize_separators("..units", "/"), "../units");
+        assert_eq!(normalize_separators("./units", "/"), "./units");
+        // Extensions and absolute paths are kept
+        assert_eq!(normalize_separators("./x.js", "/"), "./x.js");
+        assert_eq!(normalize_separators("../types/x.js", "/"), "../types/x.js");
+        assert_eq!(normalize_separators("/abs/x", "/"), "/abs/x");
+        assert_eq!(normalize_separators("/abs/x", "."), "/abs/x");
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(module_dir("constants"), "");
+        assert_eq!(module_dir("api/v1/constants"), "api/v1");
+        assert_eq!(relative_path("types/units", ""), "./types/units");
+        assert_eq!(relative_path("types/units", "api"), "../types/units");
+        assert_eq!(relative_path("api/units", "api/v1"), "../units");
+        assert_eq!(relative_path("api/v1/units", "api/v1"), "./units");
+    }
+
+    #[test]
+    fn test_conditions() {
+        let text = r##"
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+            condition = "(not EMBEDDED)"
+
+            [lang.c]
+            file_ext = ".h"
+            template = "#define $name $value"
+            condition = "EMBEDDED"
+
+            [lang.nasm]
+            file_ext = ".asm"
+            template = "%define $name $value"
+        "##;
+        let mut flags = Context::new();
+        flags.insert("EMBEDDED".to_owned(), Primitive::Boolean(false));
+        let mut options: Options = toml::from_str(text).unwrap();
+        options.apply_conditions(&flags).unwrap();
+        let names: Vec<_> = options.languages().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["nasm", "python"]);
+
+        flags.insert("EMBEDDED".to_owned(), Primitive::Integer(1));
+        let mut options: Options = toml::from_str(text).unwrap();
+        let (language, error) = options.apply_conditions(&flags).unwrap_err();
+        assert_eq!(language, "c");
+        assert!(matches!(error.message, EvalErrorMessage::InvalidArgument(_)));
+
+        let mut options: Options = toml::from_str(text).unwrap();
+        let (_, error) = options.apply_conditions(&Context::new()).unwrap_err();
+        assert!(matches!(error.message, EvalErrorMessage::UnknownSymbol(_)));
+    }
+
+    #[test]
+    fn test_language_presets() {
+        let names = ["rust", "c", "cpp", "python", "typescript", "go", "json"];
+        let names: Vec<String> = names.iter().map(|name| (*name).to_owned()).collect();
+        let options = Options::with_language_presets(&names, None).unwrap();
+        assert_eq!(options.languages().len(), names.len());
+
+        let user = toml::from_str("[lang.python]\nfile_ext = \".pyi\"").unwrap();
+        let options = Options::with_language_presets(&names[3..4], Some(user)).unwrap();
+        assert_eq!(options.lang["python"].file_ext, ".pyi");
+        assert!(Options::with_language_presets(&["cobol".to_owned()], None).is_err());
+    }
+
+    #[test]
+    fn test_type_presets() {
+        let options: Options = toml::from_str(
+            r#"
+            [type_preset.addresses]
+            PhysAddr = { name = "u64", format.integer = { radix = "hex" } }
+
+            [lang.c]
+            file_ext = ".h"
+            template = "const $type $name = $value;"
+            type_presets = ["c_stdint", "addresses"]
+
+            [lang.c.type.u8]
+            on_overflow = "wrap"
+
+            [lang.c.type.PhysAddr]
+            name = "uintptr_t"
+            "#,
+        )
+        .unwrap();
+        let (_, c) = options.languages()[0];
+        let constants = constants(
+            r#"
+            [[constant]]
+            name = "BYTE"
+            type = "u8"
+            value = "257"
+
+            [[constant]]
+            name = "WORD"
+            type = "i32"
+            value = "-1"
+
+            [[constant]]
+            name = "BASE"
+            type = "PhysAddr"
+            value = "0x1000"
+            "#,
+        );
+
+        assert_eq!(
+            c.format_constant("c", &constants[0]).unwrap(),
+            "const uint8_t BYTE = 1;"
+        );
+        assert_eq!(c.constant_imports(&constants[0]), vec!["<stdint.h>"]);
+        assert_eq!(
+            c.format_constant("c", &constants[1]).unwrap(),
+            "const int32_t WORD = -1;"
+        );
+        assert_eq!(
+            c.format_constant("c", &constants[2]).unwrap(),
+            "const uintptr_t BASE = 0x1000;"
+        );
+
+        let error = toml::from_str::<Options>(
+            "[lang.c]\nfile_ext = \".h\"\ntemplate = \"\"\ntype_presets = [\"nope\"]",
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("Unknown type preset \"nope\""), "{}", error);
+    }
+
+    #[test]
+    fn test_error_location() {
+        let text = "[lang.c]\nfile_ext = \".h\"\ntemplate = \"\"\ntype.u8.bits = \"no\"\n";
+        let error = Options::from_slice(text.as_bytes()).unwrap_err().to_string();
+        assert!(error.contains("expected u8"), "{}", error);
+        assert!(error.contains("at line 4"), "{}", error);
+
+        let text = "[lang.c]\nfile_ext = \".h\"\ntemplate = \"\"\ntype_presets = [\"nope\"]\n";
+        let error = Options::from_slice(text.as_bytes()).unwrap_err().to_string();
+        assert!(error.contains("in language \"c\""), "{}", error);
+    }
+
+    #[test]
+    fn test_format_constant_types() {
+        let options: Options = toml::from_str(
+            r#"
+            [lang.rust]
+            file_ext = ".rs"
+            template = "const $name: $type = $value;"
+
+            [lang.rust.type.PhysAddr]
+            value_prefix = "PhysAddr::new("
+            value_suffix = ")"
+            format.integer = { radix = "hex" }
+
+            [lang.rust.type.size_bytes]
+            name = "u64"
+
+            [lang.rust.type.Offset]
+            value_prefix = "Offset("
+            value_suffix = ")"
+            format.integer = { negative_style = "parenthesized" }
+            "#,
+        )
+        .unwrap();
+        let (_, rust) = options.languages()[0];
+        let constants = constants(
+            r#"
+            [[constant]]
+            name = "UNKNOWN"
+            type = "u16"
+            value = "16"
+
+            [[constant]]
+            name = "UNNAMED"
+            type = "PhysAddr"
+            value = "16"
+
+            [[constant]]
+            name = "NAMED"
+            type = "size_bytes"
+            value = "16"
+
+            [[constant]]
+            name = "UNTYPED"
+            value = "16"
+
+            [[constant]]
+            name = "OFFSET"
+            type = "Offset"
+            value = "-5"
+
+            [[constant]]
+            name = "OFFSETS"
+            type = "Offset"
+            value = "(list -5 5)"
+            "#,
+        );
+
+        assert_eq!(
+            rust.format_constant("rust", &constants[0]).unwrap(),
+            "const UNKNOWN: u16 = 16;"
+        );
+        assert_eq!(
+            rust.format_constant("rust", &constants[1]).unwrap(),
+            "const UNNAMED: PhysAddr = PhysAddr::new(0x10);"
+        );
+        assert_eq!(
+            rust.format_constant("rust", &constants[2]).unwrap(),
+            "const NAMED: u64 = 16;"
+        );
+        assert_eq!(
+            rust.format_constant("rust", &constants[3]),
+            Err(ConstantError::TypeRequired)
+        );
+        assert_eq!(
+            rust.format_constant("rust", &constants[4]).unwrap(),
+            "const OFFSET: Offset = Offset(-5);"
+        );
+        // List items are not in the parentheses of the type
+        assert_eq!(
+            rust.format_constant("rust", &constants[5]).unwrap(),
+            "const OFFSETS: Offset = Offset([(-5), 5]);"
+        );
+    }
+
+    #[test]
+    fn test_unknown_types() {
+        let options: Options = toml::from_str(&format!(
+            "{}\n[lang.rust]\nfile_ext = \".rs\"\ntemplate = \"$name\"\ntype.u32 = {{}}\n",
+            LANGUAGES
+        ))
+        .unwrap();
+        let constants = constants(
+            r#"
+            [[constant]]
+            name = "A"
+            type = "u32"
+            value = "1"
+
+            [[constant]]
+            name = "B"
+            type = "u23"
+            value = "2"
+
+            [[constant]]
+            name = "C"
+            type = "u23"
+            value = "3"
+
+            [[constant]]
+            name = "D"
+            value = "4"
+            "#,
+        );
+        let languages = options.languages();
+        let unknown: Vec<_> =
+            languages.iter().map(|(_, lang)| lang.unknown_types(&constants)).collect();
+        assert_eq!(language_names(&options), vec!["nasm", "python", "rust"]);
+        assert_eq!(unknown, vec![vec![], vec![], vec!["u23"]]);
+    }
+
+    #[test]
+    fn test_boolean_imports() {
+        let options: Options = toml::from_str(
+            r##"
+            [lang.c]
+            file_ext = ".h"
+            template = "#define $name $value"
+            format.boolean = { true = "true", false = "false", import = ["<stdbool.h>"] }
+            type.u8 = { name = "uint8_t", import = ["<stdint.h>"] }
+            type.flag = { format.boolean = ["1", "0"] }
+            "##,
+        )
+        .unwrap();
+        let (_, c) = options.languages()[0];
+        let constants = constants(
+            r#"
+            [[constant]]
+            name = "COUNT"
+            type = "u8"
+            value = "1"
+
+            [[constant]]
+            name = "ENABLED"
+            value = "true"
+
+            [[constant]]
+            name = "MASKS"
+            type = "u8"
+            value = "(list (list false))"
+
+            [[constant]]
+            name = "RAW"
+            type = "flag"
+            value = "false"
+            "#,
+        );
+        assert_eq!(c.constant_imports(&constants[0]), vec!["<stdint.h>"]);
+        assert_eq!(c.constant_imports(&constants[1]), vec!["<stdbool.h>"]);
+        assert_eq!(c.constant_imports(&constants[2]), vec!["<stdint.h>", "<stdbool.h>"]);
+        assert!(c.constant_imports(&constants[3]).is_empty());
+        assert_eq!(c.imports(&constants[..2]), vec!["<stdbool.h>", "<stdint.h>"]);
+    }
+
+    #[test]
+    fn test_special_values() {
+        let options: Options = toml::from_str(
+            r##"
+            [lang.c]
+            file_ext = ".h"
+            template = "#define $name $value"
+            special_values = { "0xffff_ffff" = "UINT32_MAX", "-128" = "INT8_MIN" }
+
+            [lang.c_typed]
+            file_ext = ".typed.h"
+            template = "#define $name $value // $type"
+            special_values = { "0xffff_ffff" = "UINT32_MAX" }
+            type.uint32_t = { value_prefix = "(uint32_t)" }
+
+            [lang.rust]
+            file_ext = ".rs"
+            template = "pub const $name: u32 = $value;"
+            format.integer = { radix = "hex", underscores = 4 }
+            "##,
+        )
+        .unwrap();
+        let languages = options.languages();
+        let (c, c_typed, rust) = (languages[0].1, languages[1].1, languages[2].1);
+        let constants = constants(
+            r#"
+            [[constant]]
+            name = "MAX"
+            value = "4294967295"
+
+            [[constant]]
+            name = "MAX_F"
+            value = "4294967295.0"
+
+            [[constant]]
+            name = "MAX_T"
+            type = "uint32_t"
+            value = "4294967295"
+
+            [[constant]]
+            name = "ONE_T"
+            type = "uint32_t"
+            value = "1"
+            "#,
+        );
+        assert_eq!(c.format_constant("c", &constants[0]).unwrap(), "#define MAX UINT32_MAX");
+        assert_eq!(c.format_constant("c", &constants[1]).unwrap(), "#define MAX_F 4294967295.0");
+        // Special values are verbatim, without the value prefix of the type
+        assert_eq!(
+            c_typed.format_constant("c_typed", &constants[2]).unwrap(),
+            "#define MAX_T UINT32_MAX // uint32_t"
+        );
+        assert_eq!(
+            c_typed.format_constant("c_typed", &constants[3]).unwrap(),
+            "#define ONE_T (uint32_t)1 // uint32_t"
+        );
+        assert_eq!(
+            rust.format_constant("rust", &constants[0]).unwrap(),
+            "pub const MAX: u32 = 0xffff_ffff;"
+        );
+
+        let error = toml::from_str::<Options>(
+            "[lang.c]\nfile_ext = \".h\"\ntemplate = \"\"\nspecial_values = { \"1.5\" = \"X\" }",
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("\"1.5\" is not an integer"), "{}", error);
+    }
+
+    #[test]
+    fn test_rename() {
+        let options: Options = toml::from_str(
+            r##"
+            [lang.python]
+            file_ext = ".py"
+            template = "$name = $value"
+            rename = { type = "type_" }
+
+            [lang.c]
+            file_ext = ".h"
+            template = "#define $name $value"
+            "##,
+        )
+        .unwrap();
+        let languages = options.languages();
+        let (c, python) = (languages[0].1, languages[1].1);
+        let constants = constants(
+            "[[constant]]\nname = \"type\"\nvalue = \"1\"\n\
+             [[constant]]\nname = \"kind\"\nvalue = \"2\"",
+        );
+        assert_eq!(python.format_constant("python", &constants[0]).unwrap(), "type_ = 1");
+        assert_eq!(python.format_constant("python", &constants[1]).unwrap(), "kind = 2");
+        assert_eq!(c.format_constant("c", &constants[0]).unwrap(), "#define type 1");
+    }
+
+    #[test]
+    fn test_name_case() {
+        let format_as = |case: &str| {
+            let options: Options = toml::from_str(&format!(
+                "[lang.x]\nfile_ext = \".x\"\ntemplate = \"$name\"\nname_case = \"{}\"\n\
+                 rename = {{ renamed = \"as_is\" }}",
+                case
+            ))
+            .unwrap();
+            let constants = constants(
+                "[[constant]]\nname = \"max_buffer_size\"\nvalue = \"1\"\n\
+                 [[constant]]\nname = \"renamed\"\nvalue = \"2\"",
+            );
+            let lang = options.languages()[0].1;
+            let name = lang.format_constant("x", &constants[0]).unwrap();
+            assert_eq!(lang.format_constant("x", &constants[1]).unwrap(), "as_is");
+            name
+        };
+        assert_eq!(format_as("snake"), "max_buffer_size");
+        assert_eq!(format_as("screaming_snake"), "MAX_BUFFER_SIZE");
+        assert_eq!(format_as("camel"), "maxBufferSize");
+        assert_eq!(format_as("pascal"), "MaxBufferSize");
+        assert_eq!(format_as("kebab"), "max-buffer-size");
+        assert_eq!(format_as("ScreamingSnake"), "MAX_BUFFER_SIZE");
+
+        assert_eq!(split_words("MAX_BUFFER_SIZE"), vec!["MAX", "BUFFER", "SIZE"]);
+        assert_eq!(split_words("maxBufferSize"), vec!["max", "Buffer", "Size"]);
+        assert_eq!(split_words("__page2Kib--x"), vec!["page2", "Kib", "x"]);
+        assert_eq!(CaseStyle::Camel.convert("MAX_BUFFER_SIZE"), "maxBufferSize");
+        assert_eq!(CaseStyle::Snake.convert("PageSize4K"), "page_size4_k");
+    }
+
+    #[test]
+    fn test_name_prefix() {
+        let options: Options = toml::from_str(
+            r##"
+            [lang.c]
+            file_ext = ".h"
+            template = "#define $name $value"
+            name_prefix = "MYLIB_"
+            name_suffix = "_V1"
+            name_case = "screaming_snake"
+            rename = { size = "len" }
+            "##,
+        )
+        .unwrap();
+        let c = options.languages()[0].1;
+        let mut constants = toml::from_str::<Constants>(
+            "[[constant]]\nname = \"max_foo\"\nvalue = \"4\"\n\
+             [[constant]]\nname = \"size\"\nvalue = \"(add max_foo 1)\"",
+        )
+        .unwrap()
+        .constants;
+        let context = resolve(&mut constants, &[]).unwrap();
+        assert_eq!(context["max_foo"], Primitive::Integer(4));
+        assert!(!context.contains_key("MYLIB_MAX_FOO_V1"));
+        assert_eq!(
+            c.format_constant("c", &constants[0]).unwrap(),
+            "#define MYLIB_MAX_FOO_V1 4"
+        );
+        assert_eq!(c.format_constant("c", &constants[1]).unwrap(), "#define MYLIB_len_V1 5");
+    }
+
+    #[test]
+    fn test_identifier_validation() {
+        let options: Options = toml::from_str(
+            r#"
+            [lang.rust]
+            file_ext = ".rs"
+            template = "pub const $name: u32 = $value;"
+            ident_pattern = "[A-Za-z_][A-Za-z0-9_]*"
+            reserved_words = ["type", "match"]
+            "#,
+        )
+        .unwrap();
+        let rust = options.languages()[0].1;
+        let constants = constants(
+            "[[constant]]\nname = \"type\"\nvalue = \"1\"\n\
+             [[constant]]\nname = \"2fast\"\nvalue = \"2\"\n\
+             [[constant]]\nname = \"FAST_2\"\nvalue = \"3\"\n\
+             [[constant]]\nname = \"fast-2\"\nvalue = \"4\"",
+        );
+        assert_eq!(
+            rust.format_constant("rust", &constants[0]),
+            Err(ConstantError::InvalidIdentifier("type".to_owned()))
+        );
+        assert_eq!(
+            rust.format_constant("rust", &constants[1]),
+            Err(ConstantError::InvalidIdentifier("2fast".to_owned()))
+        );
+        assert_eq!(
+            rust.format_constant("rust", &constants[2]).unwrap(),
+            "pub const FAST_2: u32 = 3;"
+        );
+        // Pattern must match the whole name
+        assert_eq!(
+            rust.format_constant("rust", &constants[3]),
+            Err(ConstantError::InvalidIdentifier("fast-2".to_owned()))
+        );
+
+        let error = toml::from_str::<Options>(
+            "[lang.c]\nfile_ext = \".h\"\ntemplate = \"\"\nident_pattern = \"[a-\"",
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("Invalid ident_pattern"), "{}", error);
+    }
+
+    #[test]
+    fn test_type_overflow() {
+        let options: Options = toml::from_str(
+            r#"
+            [lang.c]
+            file_ext = ".h"
+            template = "$name = $value"
+
+            [lang.c.type.u8]
+            bits = 8
+
+            [lang.c.type.u8_saturate]
+            bits = 8
+            on_overflow = "saturate"
+
+            [lang.c.type.u8_wrap]
+            bits = 8
+            on_overflow = "wrap"
+
+            [lang.c.type.i8_saturate]
+            bits = 8
+            signed = true
+            on_overflow = "saturate"
+
+            [lang.c.type.i8_wrap]
+            bits = 8
+            signed = true
+            on_overflow = "wrap"
+            "#,
+        )
+        .unwrap();
+        let (_, c) = options.languages()[0];
+        let format = |type_: &str, value: &str| {
+            let constant = constants(&format!(
+                "[[constant]]\nname = \"A\"\ntype = \"{}\"\nvalue = \"{}\"",
+                type_, value
+            ));
+            c.format_constant("c", &constant[0])
+        };
+
+        assert_eq!(format("u8", "255"), Ok("A = 255".to_owned()));
+        assert_eq!(
+            format("u8", "300"),
+            Err(ConstantError::Format(FormatError::OutOfRange {
+                value: 300,
+                bits: 8,
+                signed: false
+            }))
+        );
+        assert_eq!(format("u8_saturate", "300"), Ok("A = 255".to_owned()));
+        assert_eq!(format("u8_saturate", "-1"), Ok("A = 0".to_owned()));
+        assert_eq!(format("u8_wrap", "300"), Ok("A = 44".to_owned()));
+        assert_eq!(format("u8_wrap", "-1"), Ok("A = 255".to_owned()));
+        assert_eq!(format("i8_saturate", "300"), Ok("A = 127".to_owned()));
+        assert_eq!(format("i8_saturate", "-300"), Ok("A = -128".to_owned()));
+        assert_eq!(format("i8_wrap", "200"), Ok("A = -56".to_owned()));
+        assert_eq!(format("u8_wrap", "(list 256 257)"), Ok("A = [0, 1]".to_owned()));
+        assert_eq!(format("u8_wrap", "1.5"), Ok("A = 1.5".to_owned()));
+
+        for &(bits, valid) in &[(0, false), (1, true), (128, true), (129, false), (200, false)] {
+            let options =
+                format!("[lang.c]\nfile_ext = \".h\"\ntemplate = \"\"\ntype.t.bits = {}", bits);
+            assert_eq!(toml::from_str::<Options>(&options).is_ok(), valid, "{}", bits);
+        }
+    }
+
+    #[test]
+    fn test_type_twos_complement() {
+        let options: Options = toml::from_str(
+            r#"
+            [lang.c]
+            file_ext = ".h"
+            template = "$name = $value"
+            format.integer = { radix = "hex", underscores = 4, negative_style = "twos_complement" }
+
+            [lang.c.type.u32]
+            bits = 32
+
+            [lang.c.type.i8]
+            bits = 8
+            signed = true
+
+            [lang.c.type.u8_saturate]
+            bits = 8
+            on_overflow = "saturate"
+
+            [lang.c.type.dec_u8]
+            bits = 8
+            format.integer = { radix = "dec" }
+
+            [lang.c.type.i32]
+            bits = 32
+            signed = true
+
+            [lang.c.type.i32_sign]
+            bits = 32
+            signed = true
+            format.integer = { radix = "hex", negative_style = "sign_magnitude" }
+
+            [lang.c.type.i32_paren]
+            bits = 32
+            signed = true
+            format.integer = { radix = "hex", negative_style = "parenthesized" }
+
+            [lang.c.type.word]
+            format.integer = { radix = "hex", negative_style = "twos_complement", bits = 16 }
+
+            [lang.c.type.u32_word]
+            bits = 32
+            format.integer = { radix = "hex", negative_style = "twos_complement", bits = 16 }
+            "#,
+        )
+        .unwrap();
+        let (_, c) = options.languages()[0];
+        let format = |type_: &str, value: &str| {
+            let constant = constants(&format!(
+                "[[constant]]\nname = \"A\"\ntype = \"{}\"\nvalue = \"{}\"",
+                type_, value
+            ));
+            c.format_constant("c", &constant[0])
+        };
+
+        assert_eq!(format("u32", "-1"), Ok("A = 0xffff_ffff".to_owned()));
+        assert_eq!(format("u32", "(list -2 2)"), Ok("A = [0xffff_fffe, 0x2]".to_owned()));
+        assert_eq!(format("u32", "0xffff_ffff"), Ok("A = 0xffff_ffff".to_owned()));
+        assert_eq!(format("i8", "-128"), Ok("A = 0x80".to_owned()));
+        assert_eq!(format("u8_saturate", "-1"), Ok("A = 0x0".to_owned()));
+        assert!(matches!(
+            format("u32", "(mul -1 0x8000_0001)"),
+            Err(ConstantError::Format(FormatError::OutOfRange { .. }))
+        ));
+        assert!(matches!(
+            format("dec_u8", "-1"),
+            Err(ConstantError::Format(FormatError::OutOfRange { .. }))
+        ));
+
+        assert_eq!(format("i32", "-5"), Ok("A = 0xffff_fffb".to_owned()));
+        assert_eq!(format("i32_sign", "-5"), Ok("A = -0x5".to_owned()));
+        assert_eq!(format("i32_paren", "-5"), Ok("A = (-0x5)".to_owned()));
+        // Width of the format is used only if the type has none
+        assert_eq!(format("word", "-5"), Ok("A = 0xfffb".to_owned()));
+        assert_eq!(format("u32_word", "-5"), Ok("A = 0xfffffffb".to_owned()));
+    }
 }
@@ -0,0 +1,160 @@
+//! Built-in option bundles, and expanding them into user options
+
+use toml::value::{Table, Value};
+
+/// Built-in type presets, keyed by the type names used in constants files
+const TYPE_PRESETS: &[(&str, &str)] = &[
+    ("rust_integers", r#"
+        u8 = { bits = 8 }
+        u16 = { bits = 16 }
+        u32 = { bits = 32 }
+        u64 = { bits = 64 }
+        u128 = { bits = 128 }
+        i8 = { bits = 8, signed = true }
+        i16 = { bits = 16, signed = true }
+        i32 = { bits = 32, signed = true }
+        i64 = { bits = 64, signed = true }
+        i128 = { bits = 128, signed = true }
+    "#),
+    ("c_stdint", r#"
+        u8 = { name = "uint8_t", bits = 8, import = ["<stdint.h>"] }
+        u16 = { name = "uint16_t", bits = 16, import = ["<stdint.h>"] }
+        u32 = { name = "uint32_t", bits = 32, import = ["<stdint.h>"] }
+        u64 = { name = "uint64_t", bits = 64, import = ["<stdint.h>"] }
+        i8 = { name = "int8_t", bits = 8, signed = true, import = ["<stdint.h>"] }
+        i16 = { name = "int16_t", bits = 16, signed = true, import = ["<stdint.h>"] }
+        i32 = { name = "int32_t", bits = 32, signed = true, import = ["<stdint.h>"] }
+        i64 = { name = "int64_t", bits = 64, signed = true, import = ["<stdint.h>"] }
+    "#),
+];
+
+/// Built-in language options, for use without an options file
+const LANGUAGE_PRESETS: &[(&str, &str)] = &[
+    ("rust", r#"
+        file_ext = ".rs"
+        template = "pub const $name: $type = $value;"
+        comment = "// $comment"
+        type_presets = ["rust_integers"]
+        format.list = { open = "&[", separator = ", ", close = "]" }
+    "#),
+    ("c", r##"
+        file_ext = ".h"
+        template = "#define $name $value"
+        comment = "// $comment"
+        intro = "#pragma once"
+        format.boolean = { true = "1", false = "0" }
+        format.list = { open = "{", separator = ", ", close = "}" }
+    "##),
+    ("cpp", r##"
+        file_ext = ".hpp"
+        template = "constexpr auto $name = $value;"
+        comment = "// $comment"
+        intro = "#pragma once"
+        format.list = { open = "{", separator = ", ", close = "}" }
+    "##),
+    ("python", r##"
+        file_ext = ".py"
+        template = "$name = $value"
+        comment = "# $comment"
+        format.boolean = { true = "True", false = "False" }
+    "##),
+    ("typescript", r#"
+        file_ext = ".ts"
+        template = "export const $name = $value;"
+        comment = "// $comment"
+    "#),
+    ("go", r#"
+        file_ext = ".go"
+        template = "const $name = $value"
+        comment = "// $comment"
+        intro = "package $stem"
+    "#),
+    ("json", r#"
+        file_ext = ".json"
+        template = '  "$name": $value'
+        constant_separator = ","
+        intro = "{"
+        outro = "}"
+        format.list = { open = "[", separator = ", ", close = "]" }
+    "#),
+];
+
+/// Options table with the named language presets as languages
+pub fn language_presets(names: &[String]) -> Result<Value, String> {
+    let mut languages = Table::new();
+    for name in names {
+        let preset = LANGUAGE_PRESETS
+            .iter()
+            .find(|(preset, _)| preset == name)
+            .map(|(_, text)| toml::from_str(text).expect("Invalid built-in preset"))
+            .ok_or_else(|| {
+                let known: Vec<&str> = LANGUAGE_PRESETS.iter().map(|(name, _)| *name).collect();
+                format!("Unknown language preset {:?}, expected one of {:?}", name, known)
+            })?;
+        languages.insert(name.clone(), Value::Table(preset));
+    }
+    let mut options = Table::new();
+    options.insert("lang".to_owned(), Value::Table(languages));
+    Ok(Value::Table(options))
+}
+
+/// Type options of a preset, from `user` presets or the built-in ones
+fn type_preset(name: &str, user: Option<&Table>) -> Result<Table, String> {
+    if let Some(Value::Table(preset)) = user.and_then(|presets| presets.get(name)) {
+        return Ok(preset.clone());
+    }
+    TYPE_PRESETS
+        .iter()
+        .find(|(preset, _)| *preset == name)
+        .map(|(_, text)| toml::from_str(text).expect("Invalid built-in preset"))
+        .ok_or_else(|| format!("Unknown type preset {:?}", name))
+}
+
+/// Merge `type_presets` of each language into its `type` table.
+/// Options set in the language itself override those from presets,
+/// and later presets override earlier ones.
+pub fn expand_type_presets(options: &mut Value) -> Result<(), String> {
+    let user = options.get("type_preset").and_then(Value::as_table).cloned();
+    let languages = match options.get_mut("lang").and_then(Value::as_table_mut) {
+        Some(languages) => languages,
+        None => return Ok(()),
+    };
+    for (lang_name, lang) in languages.iter_mut() {
+        let names: Vec<String> = match lang.get("type_presets").and_then(Value::as_array) {
+            Some(names) => names
+                .iter()
+                .map(|name| name.as_str().map(str::to_owned))
+                .collect::<Option<_>>()
+                .ok_or_else(|| {
+                    format!("Type preset names must be strings in language {:?}", lang_name)
+                })?,
+            None => continue,
+        };
+
+        let mut types = Table::new();
+        for name in &names {
+            let preset = type_preset(name, user.as_ref())
+                .map_err(|error| format!("{} in language {:?}", error, lang_name))?;
+            merge(&mut types, preset);
+        }
+        if let Some(Value::Table(local)) = lang.get("type") {
+            merge(&mut types, local.clone());
+        }
+        if let Some(lang) = lang.as_table_mut() {
+            lang.insert("type".to_owned(), Value::Table(types));
+        }
+    }
+    Ok(())
+}
+
+/// Recursively merge tables, with values in `overrides` taking precedence
+pub fn merge(base: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(value)) => merge(base, value),
+            (_, value) => {
+                base.insert(key, value);
+            },
+        }
+    }
+}
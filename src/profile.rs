@@ -0,0 +1,59 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::Error;
+
+/// Default command line arguments, read from a `constcodegen.toml`
+/// in the current directory or any of its ancestors.
+/// Relative paths are relative to the directory of the profile file.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Target directory for generated files
+    pub target_dir: Option<PathBuf>,
+
+    /// Target filename stem
+    pub stem: Option<String>,
+
+    /// File specifying generation options
+    pub options: Option<PathBuf>,
+
+    /// Files specifying constants
+    pub constants: Vec<PathBuf>,
+
+    /// Path of the profile file itself
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+impl Profile {
+    pub const FILE_NAME: &'static str = "constcodegen.toml";
+
+    /// Find and load the nearest profile, starting from `start`
+    pub fn discover(start: &Path) -> Result<Option<Self>, Error> {
+        for dir in start.ancestors() {
+            let path = dir.join(Self::FILE_NAME);
+            if path.is_file() {
+                log::info!("Using profile {:?}", path);
+                return Ok(Some(Self::load(&path)?));
+            }
+        }
+        Ok(None)
+    }
+
+    fn load(path: &Path) -> Result<Self, Error> {
+        let c = fs::read(path)?;
+        let mut profile: Self = toml::from_slice(&c).map_err(|error| Error::Parse {
+            path: path.to_owned(),
+            error,
+        })?;
+
+        let dir = path.parent().expect("Profile path has a parent");
+        profile.target_dir = profile.target_dir.map(|p| dir.join(p));
+        profile.options = profile.options.map(|p| dir.join(p));
+        profile.constants = profile.constants.iter().map(|p| dir.join(p)).collect();
+        profile.path = path.to_owned();
+        Ok(profile)
+    }
+}
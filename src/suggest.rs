@@ -0,0 +1,61 @@
+//! "Did you mean" suggestions for mistyped configuration keys
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref RE_UNKNOWN_FIELD: Regex =
+        Regex::new(r"unknown field `([^`]*)`, expected (?:one of )?((?:`[^`]*`(?:, )?)+)").unwrap();
+    static ref RE_FIELD: Regex = Regex::new(r"`([^`]*)`").unwrap();
+}
+
+/// Closest valid field name for an "unknown field" deserialization error message
+pub fn unknown_field(message: &str) -> Option<String> {
+    let cap = RE_UNKNOWN_FIELD.captures(message)?;
+    let unknown = &cap[1];
+    RE_FIELD
+        .captures_iter(&cap[2])
+        .map(|field| field[1].to_owned())
+        .map(|field| (levenshtein(unknown, &field), field))
+        .filter(|(distance, field)| *distance <= (field.chars().count() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, field)| field)
+}
+
+/// Edit distance in characters
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + if ca == *cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod test_suggest {
+    use super::{levenshtein, unknown_field};
+
+    #[test]
+    fn test_levenshtein() {
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("comment_section", "comment_sections"), 1);
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let message = "unknown field `comment_section`, expected one of `enabled`, \
+                       `comment_sections`, `section_labels` for key `codegen` at line 2";
+        assert_eq!(unknown_field(message), Some("comment_sections".to_owned()));
+        assert_eq!(unknown_field("unknown field `nme`, expected `name`"), Some("name".to_owned()));
+        assert_eq!(unknown_field("unknown field `xyz`, expected `name`"), None);
+        assert_eq!(unknown_field("invalid type"), None);
+    }
+}
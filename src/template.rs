@@ -8,27 +8,60 @@ lazy_static! {
 }
 
 pub fn contains_parameter(text: &str, parameter: &str) -> bool {
-    for cap in RE_PARAM.find_iter(text) {
-        let name = cap.as_str();
-        if name != "$$" && name == parameter {
-            return true;
-        }
-    }
-    false
+    parameters(text).any(|name| name == parameter)
+}
+
+/// Names of the parameters in `text`, including the `$`, in order of appearance
+pub fn parameters(text: &str) -> impl Iterator<Item = &str> {
+    RE_PARAM.find_iter(text).map(|cap| cap.as_str()).filter(|name| *name != "$$")
 }
 
 pub fn replace_parameters(text: &str, context: &HashMap<&str, String>) -> String {
-    let mut result = text.to_owned();
+    let mut result = String::with_capacity(text.len());
+    let mut last_end = 0;
     for cap in RE_PARAM.find_iter(text) {
+        result.push_str(&text[last_end..cap.start()]);
+        last_end = cap.end();
+
         let name = cap.as_str();
         if name == "$$" {
-            continue;
-        }
-        if let Some(value) = context.get(name) {
-            result = result.replace(name, value);
+            result.push('$');
+        } else if let Some(value) = context.get(name) {
+            result.push_str(value);
         } else {
             panic!("Unknown template parameter {:?}", name);
         }
     }
-    result.replace("$$", "$")
+    result.push_str(&text[last_end..]);
+    result
+}
+
+#[cfg(test)]
+mod test_template {
+    use std::collections::HashMap;
+
+    use super::replace_parameters;
+
+    #[test]
+    fn test_replace_escape() {
+        let ctx = HashMap::new();
+        assert_eq!(replace_parameters("$$x costs $$$$5", &ctx), "$x costs $$5");
+    }
+
+    #[test]
+    fn test_replace_value_is_literal() {
+        let mut ctx = HashMap::new();
+        ctx.insert("$name", "A".to_owned());
+        ctx.insert("$value", "\"$name$$\"".to_owned());
+        assert_eq!(replace_parameters("$name = $value", &ctx), "A = \"$name$$\"");
+    }
+
+    #[test]
+    fn test_replace_overlapping_names() {
+        let mut ctx = HashMap::new();
+        ctx.insert("$x", "1".to_owned());
+        ctx.insert("$xy", "2".to_owned());
+        assert_eq!(replace_parameters("$xy $x $xy", &ctx), "2 1 2");
+        assert_eq!(replace_parameters("$x $xy $x", &ctx), "1 2 1");
+    }
 }
@@ -0,0 +1,43 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Durations of processing phases, in the order they were recorded
+#[derive(Debug, Default)]
+pub struct Timings(Vec<(String, Duration)>);
+impl Timings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `f`, recording how long it took under `phase`
+    pub fn record<T>(&mut self, phase: &str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.0.push((phase.to_owned(), start.elapsed()));
+        result
+    }
+
+    /// Append the phases of `other` after those recorded so far
+    pub fn extend(&mut self, other: Timings) {
+        self.0.extend(other.0);
+    }
+
+    pub fn phases(&self) -> impl Iterator<Item = (&str, Duration)> {
+        self.0.iter().map(|(phase, duration)| (phase.as_str(), *duration))
+    }
+}
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let width = self.0.iter().map(|(phase, _)| phase.len()).max().unwrap_or(0);
+        for (phase, duration) in &self.0 {
+            writeln!(
+                f,
+                "{:width$}  {:>10.3} ms",
+                phase,
+                duration.as_secs_f64() * 1000.0,
+                width = width
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -1,13 +1,15 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
 
 use serde::Serialize;
 
-use crate::expr::EvalErrorMessage;
+use crate::expr::{parse_literal, EvalError, EvalErrorMessage};
+use crate::format_value::float_literal;
 
 fn int_float_eq(i: i128, f: f64) -> bool {
     if f.trunc() == f {
-        if std::i128::MIN as f64 <= f && f <= std::i128::MAX as f64 {
+        if i128::MIN as f64 <= f && f <= i128::MAX as f64 {
             (f as i128) == i
         } else {
             false
@@ -22,8 +24,77 @@ pub enum Primitive {
     Boolean(bool),
     Integer(i128),
     Float(f64),
+    String(String),
+    List(Vec<Primitive>),
 }
 impl Primitive {
+    /// Single literal in expression syntax, such as `0xff`, `1.5e3`, `true` or `"text"`.
+    /// Expressions and symbols are rejected.
+    pub fn parse(text: &str) -> Result<Primitive, EvalError> {
+        parse_literal(text)
+    }
+
+    /// Name of the primitive kind, as returned by `typeof`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Boolean(_) => "boolean",
+            Self::Integer(_) => "integer",
+            Self::Float(_) => "float",
+            Self::String(_) => "string",
+            Self::List(_) => "list",
+        }
+    }
+
+    /// Whether this is a boolean or a list containing one at any depth
+    pub fn contains_boolean(&self) -> bool {
+        match self {
+            Self::Boolean(_) => true,
+            Self::List(items) => items.iter().any(Self::contains_boolean),
+            _ => false,
+        }
+    }
+
+    /// Language-neutral literal that does not depend on any `Format`:
+    /// JSON syntax for finite values, `NaN`, `inf` and `-inf` for other floats
+    pub fn to_canonical(&self) -> String {
+        match self {
+            Self::Boolean(v) => v.to_string(),
+            Self::Integer(v) => v.to_string(),
+            Self::Float(v) => float_literal(*v),
+            Self::String(v) => {
+                let mut result = String::with_capacity(v.len() + 2);
+                result.push('"');
+                for c in v.chars() {
+                    match c {
+                        '"' => result.push_str("\\\""),
+                        '\\' => result.push_str("\\\\"),
+                        '\n' => result.push_str("\\n"),
+                        '\r' => result.push_str("\\r"),
+                        '\t' => result.push_str("\\t"),
+                        c if c.is_control() => {
+                            result.push_str(&format!("\\u{:04x}", c as u32))
+                        },
+                        c => result.push(c),
+                    }
+                }
+                result.push('"');
+                result
+            },
+            Self::List(v) => {
+                let items: Vec<_> = v.iter().map(Self::to_canonical).collect();
+                format!("[{}]", items.join(", "))
+            },
+        }
+    }
+
+    /// Number of elements, one for scalars
+    pub fn count(&self) -> usize {
+        match self {
+            Self::List(items) => items.len(),
+            _ => 1,
+        }
+    }
+
     /// Normal equals for other types, but approx for floats
     pub fn approx_eq(&self, other: &Self, epsilon: f64) -> bool {
         if let Self::Float(f1) = self {
@@ -94,6 +165,20 @@ impl Primitive {
         })
     }
 
+    /// Bitwise or of integer flags
+    pub fn bit_or(&self, other: &Self) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        Ok(match (self, other) {
+            (Integer(a), Integer(b)) => Integer(a | b),
+            (a, b) => {
+                return Err(EvalErrorMessage::InvalidArgument(format!(
+                    "Cannot (bits {:?} {:?})",
+                    a, b
+                )));
+            },
+        })
+    }
+
     /// Multiply
     pub fn mul(&self, other: &Self) -> Result<Primitive, EvalErrorMessage> {
         use Primitive::*;
@@ -111,6 +196,406 @@ impl Primitive {
             },
         })
     }
+
+    /// Integer division, truncating toward zero like C and Rust `/`
+    pub fn div(&self, other: &Self) -> Result<Primitive, EvalErrorMessage> {
+        self.division("div", other, i128::checked_div)
+    }
+
+    /// Integer remainder with the sign of the dividend, like C and Rust `%`
+    pub fn rem(&self, other: &Self) -> Result<Primitive, EvalErrorMessage> {
+        self.division("mod", other, i128::checked_rem)
+    }
+
+    /// Integer division rounding toward negative infinity, like Python `//`.
+    /// Differs from `div` when the operands have different signs.
+    pub fn floor_div(&self, other: &Self) -> Result<Primitive, EvalErrorMessage> {
+        self.division("floor_div", other, |a, b| {
+            let q = a.checked_div(b)?;
+            if a % b != 0 && (a < 0) != (b < 0) {
+                q.checked_sub(1)
+            } else {
+                Some(q)
+            }
+        })
+    }
+
+    /// Euclidean remainder, which is never negative.
+    /// Differs from `mod` when the dividend is negative.
+    pub fn euclid_mod(&self, other: &Self) -> Result<Primitive, EvalErrorMessage> {
+        self.division("euclid_mod", other, i128::checked_rem_euclid)
+    }
+
+    fn division(
+        &self, name: &str, other: &Self, op: fn(i128, i128) -> Option<i128>,
+    ) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        match (self, other) {
+            (Integer(_), Integer(0)) => Err(EvalErrorMessage::DivisionByZero),
+            (Integer(a), Integer(b)) => Ok(Integer(op(*a, *b).ok_or(EvalErrorMessage::Overflow)?)),
+            (a, b) => Err(EvalErrorMessage::InvalidArgument(format!(
+                "Cannot ({} {:?} {:?})",
+                name, a, b
+            ))),
+        }
+    }
+
+    /// Add modulo `2^width`
+    pub fn wrapping_add(&self, other: &Self, width: &Self) -> Result<Primitive, EvalErrorMessage> {
+        self.wrapping("wadd", other, width, u128::wrapping_add)
+    }
+
+    /// Subtract modulo `2^width`
+    pub fn wrapping_sub(&self, other: &Self, width: &Self) -> Result<Primitive, EvalErrorMessage> {
+        self.wrapping("wsub", other, width, u128::wrapping_sub)
+    }
+
+    /// Multiply modulo `2^width`
+    pub fn wrapping_mul(&self, other: &Self, width: &Self) -> Result<Primitive, EvalErrorMessage> {
+        self.wrapping("wmul", other, width, u128::wrapping_mul)
+    }
+
+    /// Integer operation in two's complement, masked to `width` bits.
+    /// The result is unsigned, except for width 128, which wraps to the range of i128.
+    fn wrapping(
+        &self, name: &str, other: &Self, width: &Self, op: fn(u128, u128) -> u128,
+    ) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        Ok(match (self, other, width) {
+            (Integer(a), Integer(b), Integer(w)) if (1..=128).contains(w) => {
+                let result = op(*a as u128, *b as u128);
+                if *w == 128 {
+                    Integer(result as i128)
+                } else {
+                    Integer((result & ((1 << w) - 1)) as i128)
+                }
+            },
+            (a, b, w) => {
+                return Err(EvalErrorMessage::InvalidArgument(format!(
+                    "Cannot ({} {:?} {:?} {:?})",
+                    name, a, b, w
+                )));
+            },
+        })
+    }
+
+    /// Round up to the next power of two
+    pub fn next_pow2(&self) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        Ok(match self {
+            Integer(a) if *a > 0 => Integer(
+                (*a as u128)
+                    .checked_next_power_of_two()
+                    .filter(|v| *v <= i128::MAX as u128)
+                    .ok_or(EvalErrorMessage::Overflow)? as i128,
+            ),
+            a => {
+                return Err(EvalErrorMessage::InvalidArgument(format!(
+                    "Cannot (next_pow2 {:?})",
+                    a
+                )));
+            },
+        })
+    }
+
+    /// Is power of two
+    pub fn is_pow2(&self) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        Ok(match self {
+            Integer(a) => Boolean(*a > 0 && (*a & (*a - 1)) == 0),
+            a => {
+                return Err(EvalErrorMessage::InvalidArgument(format!(
+                    "Cannot (is_pow2 {:?})",
+                    a
+                )));
+            },
+        })
+    }
+
+    /// Number of trailing zero bits in the 128-bit two's complement pattern,
+    /// which is 128 for zero
+    pub fn trailing_zeros(&self) -> Result<Primitive, EvalErrorMessage> {
+        self.count_bits("trailing_zeros", i128::trailing_zeros)
+    }
+
+    /// Number of leading zero bits in the 128-bit two's complement pattern,
+    /// which is 128 for zero and 0 for negative numbers
+    pub fn leading_zeros(&self) -> Result<Primitive, EvalErrorMessage> {
+        self.count_bits("leading_zeros", i128::leading_zeros)
+    }
+
+    fn count_bits(&self, name: &str, op: fn(i128) -> u32) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        Ok(match self {
+            Integer(a) => Integer(op(*a).into()),
+            a => {
+                return Err(EvalErrorMessage::InvalidArgument(format!(
+                    "Cannot ({} {:?})",
+                    name, a
+                )));
+            },
+        })
+    }
+
+    /// Round up to a multiple of alignment
+    pub fn align_up(&self, alignment: &Self) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        Ok(match (self, alignment) {
+            (Integer(a), Integer(b)) if *b > 0 => {
+                let rem = a.rem_euclid(*b);
+                if rem == 0 {
+                    Integer(*a)
+                } else {
+                    Integer(a.checked_add(b - rem).ok_or(EvalErrorMessage::Overflow)?)
+                }
+            },
+            (a, b) => {
+                return Err(EvalErrorMessage::InvalidArgument(format!(
+                    "Cannot (align_up {:?} {:?})",
+                    a, b
+                )));
+            },
+        })
+    }
+
+    /// Round down to a multiple of alignment
+    pub fn align_down(&self, alignment: &Self) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        Ok(match (self, alignment) {
+            (Integer(a), Integer(b)) if *b > 0 => Integer(a - a.rem_euclid(*b)),
+            (a, b) => {
+                return Err(EvalErrorMessage::InvalidArgument(format!(
+                    "Cannot (align_down {:?} {:?})",
+                    a, b
+                )));
+            },
+        })
+    }
+
+    /// Repeat a byte pattern the given number of times
+    pub fn repeat(&self, times: &Self) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        Ok(match (self, times) {
+            (Integer(a), Integer(b)) if (0..=0xff).contains(a) && *b >= 0 => {
+                // Any nonzero pattern overflows after 16 repetitions
+                let mut acc: i128 = 0;
+                for _ in 0..(*b).min(17) {
+                    acc = acc
+                        .checked_mul(0x100)
+                        .and_then(|v| v.checked_add(*a))
+                        .ok_or(EvalErrorMessage::Overflow)?;
+                }
+                Integer(acc)
+            },
+            (a, b) => {
+                return Err(EvalErrorMessage::InvalidArgument(format!(
+                    "Cannot (repeat {:?} {:?})",
+                    a, b
+                )));
+            },
+        })
+    }
+
+    /// 64-bit FNV-1a hash of a string
+    pub fn hash(&self) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        Ok(match self {
+            String(a) => {
+                let mut acc: u64 = 0xcbf2_9ce4_8422_2325;
+                for byte in a.bytes() {
+                    acc ^= byte as u64;
+                    acc = acc.wrapping_mul(0x0100_0000_01b3);
+                }
+                Integer(acc as i128)
+            },
+            a => {
+                return Err(EvalErrorMessage::InvalidArgument(format!(
+                    "Cannot (hash {:?})",
+                    a
+                )));
+            },
+        })
+    }
+
+    /// Byte count of a size string such as "4 KiB", with binary (KiB, MiB, GiB, TiB)
+    /// or decimal (KB, MB, GB, TB) units. A missing unit or "B" means bytes.
+    pub fn size(&self) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        let invalid = || EvalErrorMessage::InvalidArgument(format!("Cannot (size {:?})", self));
+        let text = match self {
+            String(a) => a.trim(),
+            _ => return Err(invalid()),
+        };
+        let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+        let (number, unit) = text.split_at(split);
+        let number: i128 = number.parse().map_err(|_| invalid())?;
+        let multiplier: i128 = match unit.trim_start() {
+            "" | "B" => 1,
+            "KiB" => 1 << 10,
+            "MiB" => 1 << 20,
+            "GiB" => 1 << 30,
+            "TiB" => 1 << 40,
+            "KB" => 1_000,
+            "MB" => 1_000_000,
+            "GB" => 1_000_000_000,
+            "TB" => 1_000_000_000_000,
+            _ => return Err(invalid()),
+        };
+        number
+            .checked_mul(multiplier)
+            .map(Integer)
+            .ok_or(EvalErrorMessage::Overflow)
+    }
+
+    /// Sum of list elements, zero for an empty list
+    pub fn sum(&self) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        match self {
+            List(items) => items.iter().try_fold(Integer(0), |acc, item| acc.add(item)),
+            a => Err(EvalErrorMessage::InvalidArgument(format!("Cannot (sum {:?})", a))),
+        }
+    }
+
+    /// Smallest list element, empty lists are rejected
+    pub fn min(&self) -> Result<Primitive, EvalErrorMessage> {
+        self.extremum("min", Ordering::Less)
+    }
+
+    /// Largest list element, empty lists are rejected
+    pub fn max(&self) -> Result<Primitive, EvalErrorMessage> {
+        self.extremum("max", Ordering::Greater)
+    }
+
+    /// List element furthest in the `wanted` direction, the first one on ties
+    fn extremum(&self, name: &str, wanted: Ordering) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        let invalid = || EvalErrorMessage::InvalidArgument(format!("Cannot ({} {:?})", name, self));
+        let items = match self {
+            List(items) if !items.is_empty() => items,
+            _ => return Err(invalid()),
+        };
+        let mut best = &items[0];
+        for item in items {
+            let ordering = match (item, best) {
+                (Integer(a), Integer(b)) => Some(a.cmp(b)),
+                (Integer(a), Float(b)) => (*a as f64).partial_cmp(b),
+                (Float(a), Integer(b)) => a.partial_cmp(&(*b as f64)),
+                (Float(a), Float(b)) => a.partial_cmp(b),
+                _ => None,
+            };
+            match ordering {
+                Some(o) if o == wanted => best = item,
+                Some(_) => {},
+                None => return Err(invalid()),
+            }
+        }
+        Ok(best.clone())
+    }
+
+    /// Total order for sorting constants by value: numbers in ascending order, then
+    /// all other values, which are equal to each other so that stable sorts keep their order
+    pub fn sort_order(&self, other: &Self) -> Ordering {
+        // Integers too large for a float are ordered exactly among those of the same float
+        fn key(value: &Primitive) -> Option<(f64, i128)> {
+            match value {
+                Primitive::Integer(v) => Some((*v as f64, *v)),
+                Primitive::Float(v) if !v.is_nan() => Some((*v, *v as i128)),
+                _ => None,
+            }
+        }
+        match (key(self), key(other)) {
+            (Some((a_float, a_int)), Some((b_float, b_int))) => {
+                a_float.total_cmp(&b_float).then(a_int.cmp(&b_int))
+            },
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+
+    /// Smallest value of an integer type named like `i32`
+    pub fn type_min(&self) -> Result<Primitive, EvalErrorMessage> {
+        self.type_limit("type_min", false)
+    }
+
+    /// Largest value of an integer type named like `u32`.
+    /// `u128` is rejected, as its maximum doesn't fit in the value range.
+    pub fn type_max(&self) -> Result<Primitive, EvalErrorMessage> {
+        self.type_limit("type_max", true)
+    }
+
+    fn type_limit(&self, name: &str, max: bool) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        let limit = match self {
+            String(type_name) => {
+                let (signed, bits) = match type_name.get(..1) {
+                    Some("i") => ("i", type_name[1..].parse::<u32>()),
+                    Some("u") => ("u", type_name[1..].parse::<u32>()),
+                    _ => ("", Ok(0)),
+                };
+                match (signed, bits) {
+                    ("i", Ok(bits @ (8 | 16 | 32 | 64 | 128))) if max => {
+                        Some(i128::MAX >> (128 - bits))
+                    },
+                    ("i", Ok(bits @ (8 | 16 | 32 | 64 | 128))) => Some(i128::MIN >> (128 - bits)),
+                    ("u", Ok(8 | 16 | 32 | 64 | 128)) if !max => Some(0),
+                    ("u", Ok(bits @ (8 | 16 | 32 | 64))) => Some((1 << bits) - 1),
+                    _ => None,
+                }
+            },
+            _ => None,
+        };
+        limit.map(Integer).ok_or_else(|| {
+            EvalErrorMessage::InvalidArgument(format!("Cannot ({} {:?})", name, self))
+        })
+    }
+
+    /// Truthiness: nonzero numbers are true, NaN is rejected
+    pub fn to_bool(&self) -> Result<Primitive, EvalErrorMessage> {
+        use Primitive::*;
+        Ok(match self {
+            Boolean(a) => Boolean(*a),
+            Integer(a) => Boolean(*a != 0),
+            Float(a) if !a.is_nan() => Boolean(*a != 0.0),
+            a => {
+                return Err(EvalErrorMessage::InvalidArgument(format!(
+                    "Cannot (bool {:?})",
+                    a
+                )));
+            },
+        })
+    }
+
+    /// Integer literal in a string, with the same syntax as in expressions
+    pub fn parse_int(&self) -> Result<Primitive, EvalErrorMessage> {
+        self.parse_number("parse_int", |value| match value {
+            Primitive::Integer(a) => Some(Primitive::Integer(a)),
+            _ => None,
+        })
+    }
+
+    /// Float or integer literal in a string, with the same syntax as in expressions
+    pub fn parse_float(&self) -> Result<Primitive, EvalErrorMessage> {
+        self.parse_number("parse_float", |value| match value {
+            Primitive::Integer(a) => Some(Primitive::Float(a as f64)),
+            Primitive::Float(a) => Some(Primitive::Float(a)),
+            _ => None,
+        })
+    }
+
+    fn parse_number<F>(&self, name: &str, convert: F) -> Result<Primitive, EvalErrorMessage>
+    where F: Fn(Primitive) -> Option<Primitive> {
+        let invalid = || EvalErrorMessage::InvalidArgument(format!("Cannot ({} {:?})", name, self));
+        let text = match self {
+            Primitive::String(text) => text,
+            _ => return Err(invalid()),
+        };
+        match Self::parse(text) {
+            Ok(value) => convert(value).ok_or_else(invalid),
+            Err(error) if error.message == EvalErrorMessage::Overflow => Err(error.message),
+            Err(_) => Err(invalid()),
+        }
+    }
 }
 impl PartialEq for Primitive {
     fn eq(&self, other: &Self) -> bool {
@@ -129,6 +614,14 @@ impl PartialEq for Primitive {
                 Self::Float(o) => s == o,
                 _ => false,
             },
+            Self::String(s) => match other {
+                Self::String(o) => s == o,
+                _ => false,
+            },
+            Self::List(s) => match other {
+                Self::List(o) => s == o,
+                _ => false,
+            },
         }
     }
 }
@@ -137,9 +630,95 @@ impl fmt::Display for Primitive {
         write!(f, "{}", match self {
             Self::Boolean(v) => v.to_string(),
             Self::Integer(v) => v.to_string(),
-            Self::Float(v) => v.to_string(),
+            Self::Float(v) => float_literal(*v),
+            Self::String(v) => format!("{:?}", v),
+            Self::List(v) => format!(
+                "[{}]",
+                v.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ")
+            ),
         })
     }
 }
 
 pub type Context = HashMap<String, Primitive>;
+
+#[cfg(test)]
+mod test_value {
+    use super::Primitive::{self, *};
+    use crate::expr::EvalErrorMessage;
+    use crate::format_value::Format;
+
+    #[test]
+    fn test_canonical() {
+        assert_eq!(Boolean(true).to_canonical(), "true");
+        assert_eq!(Integer(-42).to_canonical(), "-42");
+        assert_eq!(Integer(i128::MAX).to_canonical(), i128::MAX.to_string());
+        assert_eq!(Integer(i128::MIN).to_canonical(), i128::MIN.to_string());
+        assert_eq!(Float(-1.5).to_canonical(), "-1.5");
+        assert_eq!(Float(1e20).to_canonical(), "1e20");
+        assert_eq!(Float(f64::NAN).to_canonical(), "NaN");
+        assert_eq!(
+            String("a\"b\\c\n\u{1}\u{e9}".to_owned()).to_canonical(),
+            "\"a\\\"b\\\\c\\n\\u0001\u{e9}\""
+        );
+        assert_eq!(
+            List(vec![Integer(1), List(vec![]), String("x".to_owned())]).to_canonical(),
+            r#"[1, [], "x"]"#
+        );
+    }
+
+    #[test]
+    fn test_sort_order() {
+        let mut values = [
+            String("a".to_owned()),
+            Float(2.5),
+            Integer(i128::MAX),
+            Float(f64::NAN),
+            Integer(i128::MAX - 1),
+            Integer(-3),
+            Boolean(true),
+            Float(-0.5),
+        ];
+        values.sort_by(Primitive::sort_order);
+        assert_eq!(&values[..5], &[
+            Integer(-3),
+            Float(-0.5),
+            Float(2.5),
+            Integer(i128::MAX - 1),
+            Integer(i128::MAX)
+        ]);
+        assert_eq!(values[5], String("a".to_owned()));
+        assert!(matches!(values[6], Float(v) if v.is_nan()));
+        assert_eq!(values[7], Boolean(true));
+    }
+
+    #[test]
+    fn test_parse_round_trip() {
+        let format = Format::default();
+        let values = [
+            Integer(0),
+            Integer(-42),
+            Integer(i128::MAX),
+            Integer(i128::MIN),
+            Float(0.0),
+            Float(-1.5),
+            Float(1e20),
+            Float(-2.5e-7),
+            Float(f64::MAX),
+            Float(f64::MIN_POSITIVE),
+            Boolean(true),
+            Boolean(false),
+        ];
+        for value in &values {
+            let text = format.format(value).unwrap();
+            assert_eq!(Primitive::parse(&text).as_ref(), Ok(value), "{}", text);
+        }
+
+        assert_eq!(Primitive::parse("0xff"), Ok(Integer(255)));
+        assert_eq!(Primitive::parse("1e3"), Ok(Float(1000.0)));
+        for text in &["A", "(add 1 2)", "1 2"] {
+            let error = Primitive::parse(text).unwrap_err();
+            assert_eq!(error.message, EvalErrorMessage::UnexpectedToken, "{}", text);
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn test_constants_from_stdin() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = "[lang.python]\nfile_ext = \".py\"\ntemplate = \"$name = $value\"\n";
+    fs::write(dir.path().join("options.toml"), options).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_constcodegen"))
+        .current_dir(dir.path())
+        .args(["--options", "options.toml", "-t", ".", "--constants-format", "json", "-"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(br#"{"constant": [{"name": "PAGE", "value": "0x1000"}]}"#)
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+    assert_eq!(fs::read_to_string(dir.path().join("constants.py")).unwrap(), "PAGE = 4096\n");
+}
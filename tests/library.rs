@@ -0,0 +1,316 @@
+use constcodegen::{
+    explain, generate, resolve, resolve_with, Constants, Error, EvalErrorMessage, Options,
+    Primitive, ResolveOptions,
+};
+
+#[test]
+fn test_library_api() {
+    let options: Options = toml::from_str(
+        r#"
+        [lang.python]
+        file_ext = ".py"
+        template = "$name = $value"
+
+        [lang.nasm]
+        file_ext = ".asm"
+        template = "%define $name $value"
+        format.boolean = ["1", "0"]
+        "#,
+    )
+    .unwrap();
+    let constants = toml::from_str::<Constants>(
+        r#"
+        [[constant]]
+        name = "PAGE_SIZE"
+        value = "0x1000"
+
+        [[macro]]
+        name = "double"
+        params = ["x"]
+        expr = "(mul x 2)"
+
+        [[constant]]
+        name = "TWO_PAGES"
+        value = "(double PAGE_SIZE)"
+
+        [[constant]]
+        name = "ENABLED"
+        value = "true"
+        "#,
+    )
+    .unwrap();
+    let macros = constants.macros;
+    let mut constants = constants.constants;
+
+    let context = resolve(&mut constants, &macros).unwrap();
+    assert_eq!(context["TWO_PAGES"], Primitive::Integer(0x2000));
+
+    let outputs = generate(&options, "constants", &constants).unwrap();
+    assert_eq!(outputs.len(), 2);
+    assert_eq!(
+        outputs["python"],
+        "PAGE_SIZE = 4096\nTWO_PAGES = 8192\nENABLED = true\n"
+    );
+    assert_eq!(
+        outputs["nasm"],
+        "%define PAGE_SIZE 4096\n%define TWO_PAGES 8192\n%define ENABLED 1\n"
+    );
+}
+
+#[test]
+fn test_evaluation_error_display() {
+    let mut constants = toml::from_str::<Constants>(
+        r#"
+        [[constant]]
+        name = "SIZE"
+        value = """
+(mul
+    0x1000
+    (add 1 true))"""
+        "#,
+    )
+    .unwrap()
+    .constants;
+
+    let error = resolve(&mut constants, &[]).unwrap_err();
+    assert!(matches!(error, Error::Evaluation(..)));
+    assert_eq!(
+        error.to_string(),
+        concat!(
+            "In constant \"SIZE\":\n",
+            "Argument invalid: Cannot (add Integer(1) Boolean(true)) (line 3, column 12)\n",
+            "\n",
+            "      (add 1 true))\n",
+            "             ^^^^",
+        )
+    );
+
+    let json = error.to_json();
+    assert_eq!(json["kind"], "evaluation");
+    assert_eq!(json["constant"], "SIZE");
+    assert_eq!(json["line"], 3);
+    assert_eq!(json["column"], 12);
+    assert_eq!(json["message"], error.to_string());
+    assert!(json.get("file").is_none());
+
+    for (value, location) in &[("", ""), ("   ", " (line 1, column 1)\n\n     \n  ^^^")] {
+        let text = format!("[[constant]]\nname = \"EMPTY\"\nvalue = \"{}\"", value);
+        let mut constants = toml::from_str::<Constants>(&text).unwrap().constants;
+        assert_eq!(
+            resolve(&mut constants, &[]).unwrap_err().to_string(),
+            format!(
+                "In constant \"EMPTY\":\nValue is empty; use '\"\"' for an empty string{}",
+                location
+            )
+        );
+    }
+}
+
+#[test]
+fn test_unknown_symbol_chain() {
+    let resolve_error = |text: &str| {
+        let mut constants = toml::from_str::<Constants>(text).unwrap().constants;
+        resolve(&mut constants, &[]).unwrap_err()
+    };
+
+    let error = resolve_error(
+        r#"
+        [[constant]]
+        name = "A"
+        value = "(add B 1)"
+
+        [[constant]]
+        name = "B"
+        value = "(mul 2 C)"
+
+        [[constant]]
+        name = "C"
+        format = "v${D}"
+        "#,
+    );
+    match &error {
+        Error::UnknownSymbol { chain, undefined, .. } => {
+            assert_eq!(chain, &["A", "B", "C", "D"]);
+            assert!(undefined);
+        },
+        other => panic!("Unexpected error {:?}", other),
+    }
+    assert!(
+        error.to_string().starts_with(
+            "Constant \"A\" references \"B\", which references \"C\", \
+             which references \"D\", which is not defined\nUnknown symbol name \"B\""
+        ),
+        "{}",
+        error
+    );
+
+    let error = resolve_error(
+        r#"
+        [[constant]]
+        name = "X"
+        value = "Y"
+
+        [[constant]]
+        name = "Y"
+        value = "1"
+        "#,
+    );
+    assert!(
+        error.to_string().starts_with("Constant \"X\" references \"Y\", which is defined after it"),
+        "{}",
+        error
+    );
+
+    let error = resolve_error("[[constant]]\nname = \"OLD\"\nalias_of = \"NEW\"");
+    assert!(
+        error.to_string().starts_with("Constant \"OLD\" references \"NEW\", which is not defined"),
+        "{}",
+        error
+    );
+
+    let error = resolve_error("[[constant]]\nname = \"X\"\nvalue = \"MISSING\"");
+    assert!(
+        matches!(&error, Error::UnknownSymbol { chain, undefined: true, .. } if chain.len() == 2),
+        "{:?}",
+        error
+    );
+}
+
+#[test]
+fn test_env_function() {
+    std::env::set_var("CONSTCODEGEN_TEST_HASH", "abc123");
+    let text = r#"
+        [[constant]]
+        name = "HASH"
+        value = '(env "CONSTCODEGEN_TEST_HASH")'
+
+        [[constant]]
+        name = "BUILD"
+        value = '(env "CONSTCODEGEN_TEST_UNSET" 0)'
+        "#;
+    let options = ResolveOptions {
+        allow_env: true,
+        ..ResolveOptions::default()
+    };
+    let mut constants = toml::from_str::<Constants>(text).unwrap().constants;
+    let context = resolve_with(&mut constants, &[], &options).unwrap();
+    assert_eq!(context["HASH"], Primitive::String("abc123".to_owned()));
+    assert_eq!(context["BUILD"], Primitive::Integer(0));
+
+    let mut constants = toml::from_str::<Constants>(
+        "[[constant]]\nname = \"A\"\nvalue = '(env \"CONSTCODEGEN_TEST_UNSET\")'",
+    )
+    .unwrap()
+    .constants;
+    match resolve_with(&mut constants, &[], &options).unwrap_err() {
+        Error::Evaluation(_, error) => {
+            assert!(matches!(error.message, EvalErrorMessage::InvalidArgument(_)))
+        },
+        other => panic!("Unexpected error {:?}", other),
+    }
+
+    let mut constants = toml::from_str::<Constants>(text).unwrap().constants;
+    match resolve(&mut constants, &[]).unwrap_err() {
+        Error::Evaluation(_, error) => {
+            assert_eq!(error.message, EvalErrorMessage::UnknownFunction("env".to_owned()))
+        },
+        other => panic!("Unexpected error {:?}", other),
+    }
+}
+
+#[test]
+fn test_explain() {
+    let constants = toml::from_str::<Constants>(
+        r#"
+        [[constant]]
+        name = "PAGE"
+        value = "0x1000"
+
+        [[macro]]
+        name = "double"
+        params = ["x"]
+        expr = "(mul x 2)"
+
+        [[constant]]
+        name = "SIZE"
+        value = "(add (double PAGE) (mul 2 3) 1)"
+
+        [[constant]]
+        name = "LABEL"
+        format = "${SIZE} bytes"
+        "#,
+    )
+    .unwrap();
+    let macros = constants.macros;
+    let mut constants = constants.constants;
+    let options = ResolveOptions::default();
+
+    assert_eq!(
+        explain(&mut constants, &macros, &options, "SIZE").unwrap(),
+        vec![
+            "Parsed: (add (double PAGE) (mul 2 3) 1)",
+            "Substituted: (add (double 4096) (mul 2 3) 1)",
+            "Called: (double 4096) = 8192",
+            "Called: (mul 2 3) = 6",
+            "Called: (add 8192 6 1) = 8199",
+            "Result: 8199",
+        ]
+    );
+    assert_eq!(
+        explain(&mut constants, &macros, &options, "LABEL").unwrap(),
+        vec!["Result: \"8199 bytes\""]
+    );
+    assert!(matches!(
+        explain(&mut constants, &macros, &options, "MISSING"),
+        Err(Error::UnknownConstant(_))
+    ));
+}
+
+#[test]
+fn test_limits() {
+    let text = r#"
+        [[constant]]
+        name = "A"
+        value = "(add 1 (add 2 (add 3 4)))"
+
+        [[constant]]
+        name = "B"
+        value = "A"
+        "#;
+    let resolve_limited = |options: ResolveOptions| {
+        let mut constants = toml::from_str::<Constants>(text).unwrap().constants;
+        resolve_with(&mut constants, &[], &options)
+    };
+    let limited = |f: fn(&mut ResolveOptions)| {
+        let mut options = ResolveOptions::default();
+        f(&mut options);
+        resolve_limited(options)
+    };
+
+    assert!(limited(|o| o.max_expression_depth = Some(3)).is_ok());
+    let error = limited(|o| o.max_expression_depth = Some(2)).unwrap_err();
+    assert!(matches!(error, Error::LimitExceeded(_)), "{:?}", error);
+    assert!(error.to_string().contains("\"A\" is nested 3 deep"), "{}", error);
+
+    assert!(limited(|o| o.max_constants = Some(2)).is_ok());
+    assert!(matches!(limited(|o| o.max_constants = Some(1)), Err(Error::LimitExceeded(_))));
+    assert!(matches!(
+        limited(|o| o.max_expression_length = Some(10)),
+        Err(Error::LimitExceeded(_))
+    ));
+
+    // Deep nesting is rejected before parsing it
+    let deep = format!("{}1{}", "(add 1 ".repeat(100_000), ")".repeat(100_000));
+    let mut constants =
+        toml::from_str::<Constants>(&format!("[[constant]]\nname = \"X\"\nvalue = \"{}\"", deep))
+            .unwrap()
+            .constants;
+    let options = ResolveOptions {
+        max_expression_depth: Some(64),
+        ..ResolveOptions::default()
+    };
+    assert!(matches!(
+        resolve_with(&mut constants, &[], &options),
+        Err(Error::LimitExceeded(_))
+    ));
+}